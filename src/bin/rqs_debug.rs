@@ -2,7 +2,7 @@
 
 use rqs::channel::{ChannelMessage, Message, TransferAction};
 use rqs::hdl::TransferState;
-use rqs::RQS;
+use rqs::{OutboundPayload, SendInfo, RQS};
 use tokio::signal;
 use tokio::sync::broadcast::Sender;
 
@@ -10,6 +10,29 @@ use tokio::sync::broadcast::Sender;
 async fn main() -> Result<(), anyhow::Error> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
 
+    // `rqs_debug history [query]` and `rqs_debug clear-history` only look at the stored history,
+    // `rqs_debug send <host:port> <file>...` sends without discovering the peer
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("history") => {
+            let rqs = RQS::default();
+            let entries = match args.get(1) {
+                Some(query) => rqs.search_history(query)?,
+                None => rqs.history()?,
+            };
+            for entry in entries {
+                println!("{}", serde_json::to_string(&entry)?);
+            }
+            return Ok(());
+        }
+        Some("clear-history") => {
+            RQS::default().clear_history()?;
+            println!("Transfer history cleared");
+            return Ok(());
+        }
+        _ => {}
+    }
+
     println!("Starting RQS debug server (AUTO-ACCEPT MODE)...");
 
     let mut rqs = RQS::default();
@@ -19,7 +42,14 @@ async fn main() -> Result<(), anyhow::Error> {
     let sender = rqs.message_sender.clone();
 
     // Start the service
-    let (_sender_file, _ble_receiver) = rqs.run().await?;
+    let (sender_file, _ble_receiver) = rqs.run().await?;
+
+    if let [cmd, addr, files @ ..] = args.as_slice()
+        && cmd == "send"
+    {
+        let info = SendInfo::to_address(addr.clone(), None, OutboundPayload::Files(files.to_vec()));
+        sender_file.send(info).await?;
+    }
 
     println!("RQS server running. Press Ctrl+C to stop.");
     println!("All incoming transfers will be AUTO-ACCEPTED.\n");
@@ -47,6 +77,13 @@ async fn handle_messages(
             Ok(msg) => {
                 println!(">>> ChannelMessage: {msg:?}");
 
+                if let Message::Client(ref client) = msg.msg
+                    && client.state == Some(TransferState::Rejected)
+                    && let Some(reason) = client.metadata.as_ref().and_then(|m| m.rejection.as_ref())
+                {
+                    println!("==> Transfer {} rejected: {reason}", msg.id);
+                }
+
                 // Auto-accept when we reach WaitingForUserConsent
                 if let Message::Client(ref client) = msg.msg
                     && client.state == Some(TransferState::WaitingForUserConsent)
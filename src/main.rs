@@ -4,9 +4,10 @@ use std::sync::mpsc;
 use std::thread;
 
 use eframe::egui;
-use rqs::channel::{ChannelMessage, Message, TransferAction};
+use rqs::channel::{ChannelMessage, Message, TransferAction, TransferKind};
+use rqs::hdl::info::{FileOutcomeKind, RejectionReason, TransferPayload};
 use rqs::hdl::{EndpointInfo, TransferState};
-use rqs::{OutboundPayload, SendInfo, RQS};
+use rqs::{HistoryEntry, OutboundPayload, SendInfo, RQS};
 use tokio::sync::broadcast;
 
 /// Message types for the GUI channel
@@ -62,6 +63,26 @@ struct ReceivedFile {
     size: u64,
 }
 
+/// Files received in previous sessions, excluding the quarantined ones
+fn received_from_history(entries: Vec<HistoryEntry>) -> Vec<ReceivedFile> {
+    entries
+        .into_iter()
+        .filter(|e| e.direction == TransferKind::Inbound)
+        .flat_map(|e| {
+            let sender = e.peer_name.unwrap_or_else(|| "Unknown".to_string());
+            let quarantined: Vec<i64> = e.file_outcomes.iter().map(|o| o.payload_id).collect();
+            e.files
+                .into_iter()
+                .filter(move |f| f.completed && !quarantined.contains(&f.payload_id))
+                .map(move |f| ReceivedFile {
+                    name: f.name,
+                    sender: sender.clone(),
+                    size: f.size,
+                })
+        })
+        .collect()
+}
+
 /// State of an outbound transfer (for the overlay)
 struct OutboundTransfer {
     id: String,
@@ -70,6 +91,7 @@ struct OutboundTransfer {
     state: TransferState,
     total_bytes: u64,
     ack_bytes: u64,
+    rejection: Option<RejectionReason>,
 }
 
 /// State of an inbound transfer (for auto-accept and status)
@@ -102,6 +124,7 @@ impl KvakkApp {
             broadcast::Sender<ChannelMessage>,
             tokio::sync::mpsc::Sender<SendInfo>,
             String,
+            Vec<ReceivedFile>,
         )>();
 
         let ctx = cc.egui_ctx.clone();
@@ -111,12 +134,19 @@ impl KvakkApp {
             rt.block_on(async move {
                 let mut rqs = RQS::default();
                 let device_name = rqs.get_device_name();
+                let received_files = rqs.history().map_or_else(
+                    |e| {
+                        log::error!("Failed to load the transfer history: {e}");
+                        Vec::new()
+                    },
+                    received_from_history,
+                );
                 let message_sender = rqs.message_sender.clone();
                 let mut receiver = rqs.message_sender.subscribe();
 
                 match rqs.run().await {
                     Ok((sender_file, _ble_receiver)) => {
-                        drop(init_tx.send((message_sender, sender_file, device_name)));
+                        drop(init_tx.send((message_sender, sender_file, device_name, received_files)));
 
                         // Start device discovery
                         let (endpoint_tx, mut endpoint_rx) = broadcast::channel::<EndpointInfo>(50);
@@ -161,10 +191,10 @@ impl KvakkApp {
             });
         });
 
-        let (cmd_tx, send_tx, device_name) = init_rx
+        let (cmd_tx, send_tx, device_name, received_files) = init_rx
             .recv()
-            .map(|(cmd, send, name)| (Some(cmd), Some(send), name))
-            .unwrap_or((None, None, "Unknown".to_string()));
+            .map(|(cmd, send, name, files)| (Some(cmd), Some(send), name, files))
+            .unwrap_or((None, None, "Unknown".to_string(), Vec::new()));
 
         Self {
             device_name,
@@ -173,7 +203,7 @@ impl KvakkApp {
             cmd_tx,
             send_tx,
             endpoints: Vec::new(),
-            received_files: Vec::new(),
+            received_files,
             outbound: None,
             inbound: None,
         }
@@ -213,6 +243,7 @@ impl KvakkApp {
                 if let Some(meta) = &client.metadata {
                     outbound.total_bytes = meta.total_bytes;
                     outbound.ack_bytes = meta.ack_bytes;
+                    outbound.rejection.clone_from(&meta.rejection);
                     if outbound.pin_code.is_none() {
                         outbound.pin_code = meta.pin_code.clone();
                     }
@@ -227,6 +258,7 @@ impl KvakkApp {
                 state: state.clone(),
                 total_bytes: meta.total_bytes,
                 ack_bytes: meta.ack_bytes,
+                rejection: meta.rejection.clone(),
             });
         }
     }
@@ -247,7 +279,18 @@ impl KvakkApp {
 
                 // Transfer finished - add to received files
                 if *state == TransferState::Finished {
-                    for name in &inbound.file_names {
+                    let outcomes = client.metadata.as_ref().map_or(&[][..], |m| m.file_outcomes.as_slice());
+                    let quarantined: Vec<&str> = outcomes.iter()
+                        .filter(|o| matches!(o.kind, FileOutcomeKind::Quarantined { .. }))
+                        .map(|o| o.name.as_str())
+                        .collect();
+                    if !quarantined.is_empty() {
+                        log::warn!("Files quarantined by the scanner: {quarantined:?}");
+                    }
+
+                    // Quarantined, cancelled and failed files didn't make it
+                    let skipped: Vec<&str> = outcomes.iter().map(|o| o.name.as_str()).collect();
+                    for name in inbound.file_names.iter().filter(|n| !skipped.contains(&n.as_str())) {
                         self.received_files.push(ReceivedFile {
                             name: name.clone(),
                             sender: inbound.sender.clone(),
@@ -255,20 +298,13 @@ impl KvakkApp {
                         });
                     }
                     self.inbound = None;
-                } else if matches!(state, TransferState::Cancelled | TransferState::Rejected | TransferState::Disconnected) {
+                } else if matches!(state, TransferState::Cancelled | TransferState::Rejected | TransferState::TimedOut | TransferState::Disconnected) {
                     self.inbound = None;
                 }
             }
         } else if let Some(meta) = &client.metadata {
             // New inbound transfer
-            let file_names = meta.payload.as_ref().map_or_else(Vec::new, |p| {
-                match p {
-                    rqs::hdl::info::TransferPayload::Files(files) => files.clone(),
-                    rqs::hdl::info::TransferPayload::Text(t) => vec![format!("Text: {}", t.chars().take(50).collect::<String>())],
-                    rqs::hdl::info::TransferPayload::Url(u) => vec![format!("URL: {u}")],
-                    rqs::hdl::info::TransferPayload::Wifi { ssid, .. } => vec![format!("WiFi: {ssid}")],
-                }
-            });
+            let file_names = meta.payload.as_ref().map_or_else(Vec::new, payload_labels);
 
             self.inbound = Some(InboundTransfer {
                 id: id.to_string(),
@@ -302,11 +338,12 @@ impl KvakkApp {
     }
 
     fn send_files_to(&self, endpoint: &EndpointInfo, files: Vec<String>) {
-        if let (Some(send_tx), Some(ip), Some(port)) = (&self.send_tx, &endpoint.ip, &endpoint.port) {
+        if let (Some(send_tx), Some(addr)) = (&self.send_tx, endpoint.socket_addr()) {
             let info = SendInfo {
                 id: endpoint.id.clone(),
                 name: endpoint.name.clone().unwrap_or_else(|| "Unknown".to_string()),
-                addr: format!("{ip}:{port}"),
+                addr,
+                fallback_addrs: endpoint.addresses.clone(),
                 ob: OutboundPayload::Files(files),
             };
             let tx = send_tx.clone();
@@ -539,6 +576,9 @@ impl KvakkApp {
             0.0
         };
         let state = outbound.state.clone();
+        // What the user can act on (free some space, send something else, ...)
+        let rejection = outbound.rejection.as_ref()
+            .map_or_else(|| "Rejected by receiver".to_string(), ToString::to_string);
 
         let mut close_clicked = false;
 
@@ -571,9 +611,10 @@ impl KvakkApp {
             let (status_text, status_color) = match &state {
                 TransferState::Finished => ("Transfer complete!", theme::GREEN),
                 TransferState::Cancelled => ("Cancelled", theme::OVERLAY0),
-                TransferState::Rejected => ("Rejected by receiver", theme::RED),
+                TransferState::Rejected => (rejection.as_str(), theme::RED),
                 TransferState::Disconnected => ("Disconnected", theme::RED),
                 TransferState::SendingFiles => ("Sending...", theme::BLUE),
+                TransferState::Reconnecting => ("Reconnecting...", theme::OVERLAY0),
                 _ => ("Connecting...", theme::OVERLAY0),
             };
             ui.label(egui::RichText::new(status_text).size(14.0).color(status_color));
@@ -607,6 +648,14 @@ impl KvakkApp {
                     .color(theme::BLUE));
                 return;
             }
+            if let Some(inbound) = &self.inbound
+                && matches!(inbound.state, TransferState::Reconnecting)
+            {
+                ui.label(egui::RichText::new("Waiting for the sender to reconnect...")
+                    .size(13.0)
+                    .color(theme::OVERLAY0));
+                return;
+            }
 
             // Received files count
             let count = self.received_files.len();
@@ -642,6 +691,16 @@ impl KvakkApp {
     }
 }
 
+fn payload_labels(payload: &TransferPayload) -> Vec<String> {
+    match payload {
+        TransferPayload::Files(files) => files.clone(),
+        TransferPayload::Text(t) => vec![format!("Text: {}", t.chars().take(50).collect::<String>())],
+        TransferPayload::Url(u) => vec![format!("URL: {u}")],
+        TransferPayload::Wifi { ssid, .. } => vec![format!("WiFi: {ssid}")],
+        TransferPayload::Mixed(items) => items.iter().flat_map(payload_labels).collect(),
+    }
+}
+
 fn format_size(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = KB * 1024;
@@ -0,0 +1,535 @@
+//! Paired key certificates, to recognize the devices we already exchanged with.
+//!
+//! This device holds a P-256 key pair, a secret id and an authenticity key (Nearby Share's
+//! certificate secret key). During the paired key exchange it signs the UKEY2 auth token
+//! (`signed_data`) and sends an HKDF of that token salted with the authenticity key
+//! (`secret_id_hash`), so the peer can find the matching public certificate without the
+//! secret id going over the wire. Public
+//! certificates are exchanged with `CertificateInfoFrame`s, the one the peer's paired key
+//! encryption was made with is kept once a transfer between both devices finished.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hkdf::Hkdf;
+use p256::ecdsa::signature::{Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p256::elliptic_curve::rand_core::OsRng;
+use p256::pkcs8::{DecodePublicKey, EncodePublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::hdl::{InnerState, TransferState};
+use crate::sharing_nearby::{
+    CertificateInfoFrame, PairedKeyEncryptionFrame, PublicCertificate, paired_key_result_frame,
+};
+use crate::utils::{DeviceType, gen_random};
+
+/// Length of `secret_id_hash`, as sent by Android
+const SECRET_ID_HASH_LEN: usize = 6;
+/// Our certificate is replaced once expired
+const CERTIFICATE_VALIDITY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Loaded on first use
+static STORE: LazyLock<Mutex<Option<CertificateStore>>> = LazyLock::new(|| Mutex::new(None));
+
+/// This device's certificate, private part included.
+#[derive(Clone, Serialize, Deserialize)]
+struct LocalCertificate {
+    #[serde(with = "b64")]
+    secret_id: Vec<u8>,
+    #[serde(with = "b64")]
+    authenticity_key: Vec<u8>,
+    #[serde(with = "b64")]
+    private_key: Vec<u8>,
+    /// Unix timestamps, in milliseconds
+    start_time: i64,
+    end_time: i64,
+}
+
+/// A peer whose public certificate we kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KnownDevice {
+    /// Secret id of the certificate, base64 (URL-safe)
+    pub id: String,
+    pub name: Option<String>,
+    pub device_type: Option<DeviceType>,
+    #[serde(with = "b64")]
+    pub authenticity_key: Vec<u8>,
+    /// DER-encoded SubjectPublicKeyInfo
+    #[serde(with = "b64")]
+    pub public_key: Vec<u8>,
+    /// Unix timestamps, in milliseconds
+    pub start_time: i64,
+    pub end_time: i64,
+    /// Unix timestamp, in seconds
+    pub added_at: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CertificateStore {
+    local: LocalCertificate,
+    #[serde(default)]
+    known_devices: Vec<KnownDevice>,
+}
+
+impl LocalCertificate {
+    fn generate() -> Self {
+        let start_time = unix_millis(SystemTime::now());
+        let validity = i64::try_from(CERTIFICATE_VALIDITY.as_millis()).unwrap_or(i64::MAX);
+
+        Self {
+            secret_id: gen_random(32),
+            authenticity_key: gen_random(32),
+            private_key: SigningKey::random(&mut OsRng).to_bytes().to_vec(),
+            start_time,
+            end_time: start_time.saturating_add(validity),
+        }
+    }
+
+    fn signing_key(&self) -> Result<SigningKey, anyhow::Error> {
+        Ok(SigningKey::from_slice(&self.private_key)?)
+    }
+
+    fn public_certificate(&self) -> Result<PublicCertificate, anyhow::Error> {
+        let public_key = self.signing_key()?.verifying_key().to_public_key_der()?;
+
+        Ok(PublicCertificate {
+            secret_id: Some(self.secret_id.clone()),
+            authenticity_key: Some(self.authenticity_key.clone()),
+            public_key: Some(public_key.into_vec()),
+            start_time: Some(self.start_time),
+            end_time: Some(self.end_time),
+            ..Default::default()
+        })
+    }
+
+    /// Prove to the peer that we own this certificate, for this connection only.
+    fn paired_key_encryption(
+        &self,
+        auth_token: &[u8],
+    ) -> Result<PairedKeyEncryptionFrame, anyhow::Error> {
+        let signature: Signature = self.signing_key()?.sign(auth_token);
+
+        Ok(PairedKeyEncryptionFrame {
+            secret_id_hash: Some(secret_id_hash(&self.authenticity_key, auth_token)?),
+            signed_data: Some(signature.to_der().as_bytes().to_vec()),
+            ..Default::default()
+        })
+    }
+}
+
+impl KnownDevice {
+    fn from_certificate(
+        certificate: &PublicCertificate,
+        name: Option<String>,
+        device_type: Option<DeviceType>,
+    ) -> Option<Self> {
+        let public_key = certificate.public_key.clone()?;
+        // Only keep certificates we'll be able to verify with
+        VerifyingKey::from_public_key_der(&public_key).ok()?;
+
+        Some(Self {
+            id: URL_SAFE_NO_PAD.encode(certificate.secret_id.as_ref()?),
+            name,
+            device_type,
+            authenticity_key: certificate.authenticity_key.clone()?,
+            public_key,
+            start_time: certificate.start_time(),
+            end_time: certificate.end_time(),
+            added_at: u64::try_from(unix_millis(SystemTime::now()) / 1000).unwrap_or_default(),
+        })
+    }
+
+    fn is_valid(&self, now: i64) -> bool {
+        (self.start_time..=self.end_time).contains(&now)
+    }
+}
+
+/// HKDF-SHA256 of the auth token salted with the certificate's secret key, without info,
+/// as Nearby Share's `ComputeAuthenticationTokenHash`.
+fn secret_id_hash(authenticity_key: &[u8], auth_token: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut hash = vec![0u8; SECRET_ID_HASH_LEN];
+    Hkdf::<Sha256>::new(Some(authenticity_key), auth_token)
+        .expand(&[], &mut hash)
+        .map_err(|e| anyhow!("secret_id_hash: {e}"))?;
+
+    Ok(hash)
+}
+
+/// Whether `frame` is meant for `device` (its authenticity key hashes to `secret_id_hash`).
+fn hash_matches(
+    device: &KnownDevice,
+    frame: &PairedKeyEncryptionFrame,
+    auth_token: &[u8],
+) -> bool {
+    secret_id_hash(&device.authenticity_key, auth_token)
+        .is_ok_and(|hash| hash.as_slice() == frame.secret_id_hash())
+}
+
+/// Whether `frame` was signed with the key of `device`.
+fn signature_matches(
+    device: &KnownDevice,
+    frame: &PairedKeyEncryptionFrame,
+    auth_token: &[u8],
+) -> bool {
+    VerifyingKey::from_public_key_der(&device.public_key)
+        .ok()
+        .zip(Signature::from_der(frame.signed_data()).ok())
+        .is_some_and(|(key, signature)| key.verify(auth_token, &signature).is_ok())
+}
+
+/// Success if `frame` was signed by one of `known_devices`, for this connection.
+fn verify_with(
+    known_devices: &[KnownDevice],
+    frame: &PairedKeyEncryptionFrame,
+    auth_token: &[u8],
+) -> paired_key_result_frame::Status {
+    let now = unix_millis(SystemTime::now());
+    let Some(device) = known_devices
+        .iter()
+        .find(|d| d.is_valid(now) && hash_matches(d, frame, auth_token))
+    else {
+        return paired_key_result_frame::Status::Unable;
+    };
+
+    if signature_matches(device, frame, auth_token) {
+        debug!("Paired key verified for {:?} ({})", device.name, device.id);
+        paired_key_result_frame::Status::Success
+    } else {
+        warn!("Paired key signature mismatch for {:?} ({})", device.name, device.id);
+        paired_key_result_frame::Status::Fail
+    }
+}
+
+/// Add `device`, or refresh the entry with the same certificate.
+/// An entry is never replaced by another certificate claiming its id, returns whether it was kept.
+fn keep(known_devices: &mut Vec<KnownDevice>, device: KnownDevice) -> bool {
+    let Some(existing) = known_devices.iter_mut().find(|d| d.id == device.id) else {
+        known_devices.push(device);
+        return true;
+    };
+
+    if existing.public_key != device.public_key
+        || existing.authenticity_key != device.authenticity_key
+    {
+        return false;
+    }
+    *existing = KnownDevice {
+        added_at: existing.added_at,
+        ..device
+    };
+    true
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX))
+        .unwrap_or_default()
+}
+
+pub fn certificates_path() -> PathBuf {
+    directories::ProjectDirs::from("", "", "kvakk")
+        .map(|d| d.data_dir().join("certificates.json"))
+        .unwrap_or_else(|| std::env::temp_dir().join("kvakk-certificates.json"))
+}
+
+fn save(store: &CertificateStore) -> Result<(), anyhow::Error> {
+    let path = certificates_path();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // The private key is in there
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    file.write_all(&serde_json::to_vec_pretty(store)?)?;
+
+    Ok(())
+}
+
+fn load() -> Result<CertificateStore, anyhow::Error> {
+    let mut store = match std::fs::read(certificates_path()) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => CertificateStore {
+            local: LocalCertificate::generate(),
+            known_devices: Vec::new(),
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    if store.local.end_time <= unix_millis(SystemTime::now()) {
+        info!("Local certificate expired, generating a new one");
+        store.local = LocalCertificate::generate();
+    }
+    save(&store)?;
+
+    Ok(store)
+}
+
+/// Run `f` on the store, loading it (or creating our certificate) on first use.
+fn with_store<T>(
+    f: impl FnOnce(&mut CertificateStore) -> Result<T, anyhow::Error>,
+) -> Result<T, anyhow::Error> {
+    let mut guard = STORE.lock().map_err(|_| anyhow::anyhow!("certificate store lock poisoned"))?;
+    if guard.is_none() {
+        *guard = Some(load()?);
+    }
+
+    match guard.as_mut() {
+        Some(store) => f(store),
+        None => Err(anyhow::anyhow!("certificate store unavailable")),
+    }
+}
+
+/// Our `PairedKeyEncryptionFrame` for the connection with this UKEY2 auth token.
+/// Falls back to random data (the peer then sees an unknown device) if our certificate is unusable.
+pub fn paired_key_encryption(auth_token: &[u8]) -> PairedKeyEncryptionFrame {
+    with_store(|s| s.local.paired_key_encryption(auth_token)).unwrap_or_else(|e| {
+        error!("Failed to sign the paired key encryption: {e}");
+        PairedKeyEncryptionFrame {
+            secret_id_hash: Some(gen_random(SECRET_ID_HASH_LEN)),
+            signed_data: Some(gen_random(72)),
+            ..Default::default()
+        }
+    })
+}
+
+/// Our public certificate, for the peer to recognize us next time.
+pub fn certificate_info() -> Option<CertificateInfoFrame> {
+    match with_store(|s| s.local.public_certificate()) {
+        Ok(certificate) => Some(CertificateInfoFrame {
+            public_certificate: vec![certificate],
+        }),
+        Err(e) => {
+            error!("Failed to build our public certificate: {e}");
+            None
+        }
+    }
+}
+
+/// Check the peer's paired key encryption against the known devices.
+/// Unable when the peer isn't known (or its certificate expired), Fail when the signature doesn't match.
+pub fn verify(frame: &PairedKeyEncryptionFrame, auth_token: &[u8]) -> paired_key_result_frame::Status {
+    with_store(|s| Ok(verify_with(&s.known_devices, frame, auth_token))).unwrap_or_else(|e| {
+        error!("Failed to verify the paired key encryption: {e}");
+        paired_key_result_frame::Status::Unable
+    })
+}
+
+/// Among the certificates the peer sent, the one its paired key encryption was made with:
+/// the peer proved owning it on this connection.
+pub fn bound_certificate(
+    certificates: &[PublicCertificate],
+    frame: &PairedKeyEncryptionFrame,
+    auth_token: &[u8],
+) -> Option<PublicCertificate> {
+    certificates
+        .iter()
+        .find(|c| proves(c, frame, auth_token))
+        .cloned()
+}
+
+/// Whether the peer's paired key encryption for this connection was made with `certificate`.
+pub fn proves(
+    certificate: &PublicCertificate,
+    frame: &PairedKeyEncryptionFrame,
+    auth_token: &[u8],
+) -> bool {
+    KnownDevice::from_certificate(certificate, None, None).is_some_and(|d| {
+        hash_matches(&d, frame, auth_token) && signature_matches(&d, frame, auth_token)
+    })
+}
+
+/// Keep the certificate the peer proved owning, once a transfer with it finished.
+///
+/// The first certificate of a device is trusted without further checks: it was bound to
+/// a connection the user agreed to (accepting the transfer, or the peer accepting ours),
+/// and it only recognizes that same device later (`peer_verified`), nothing gets accepted
+/// on its strength. A known id never gets another certificate though.
+pub fn remember(state: &InnerState) {
+    if state.state != TransferState::Finished {
+        return;
+    }
+
+    // Outbound transfers only know the peer from the metadata
+    let peer = state.remote_device_info.as_ref().or_else(|| {
+        state.transfer_metadata.as_ref().and_then(|m| m.source.as_ref())
+    });
+    let Some(device) = state.peer_certificate.as_ref().and_then(|c| {
+        KnownDevice::from_certificate(
+            c,
+            peer.map(|p| p.name.clone()),
+            peer.map(|p| p.device_type.clone()),
+        )
+    }) else {
+        return;
+    };
+
+    let result = with_store(|s| {
+        let (name, id) = (device.name.clone(), device.id.clone());
+        if !keep(&mut s.known_devices, device) {
+            warn!("{name:?} sent another certificate for the known id {id}, keeping ours");
+            return Ok(());
+        }
+        info!("Remembering {name:?} ({id})");
+        save(s)
+    });
+    if let Err(e) = result {
+        error!("Failed to store the certificate of transfer {}: {e}", state.id);
+    }
+}
+
+/// Every device we kept the certificate of, oldest first.
+pub fn known_devices() -> Result<Vec<KnownDevice>, anyhow::Error> {
+    with_store(|s| Ok(s.known_devices.clone()))
+}
+
+/// Stop recognizing a device, returns whether it was known.
+pub fn forget_device(id: &str) -> Result<bool, anyhow::Error> {
+    with_store(|s| {
+        let count = s.known_devices.len();
+        s.known_devices.retain(|d| d.id != id);
+        if s.known_devices.len() == count {
+            return Ok(false);
+        }
+        save(s)?;
+        Ok(true)
+    })
+}
+
+mod b64 {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn known(local: &LocalCertificate) -> KnownDevice {
+        let certificate = local.public_certificate().unwrap();
+        KnownDevice::from_certificate(&certificate, Some("Pixel 8".into()), None).unwrap()
+    }
+
+    #[test]
+    fn test_secret_id_hash_vector() {
+        // RFC 5869 test case 3: an all-zero salt of the hash length is the same as no salt
+        let hash = secret_id_hash(&[0u8; 32], &[0x0b; 22]).unwrap();
+        assert_eq!(hash, [0x8d, 0xa4, 0xe7, 0x75, 0xa5, 0x63]);
+
+        // RFC 5869 test case 1 inputs, without its info
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let hash = secret_id_hash(&salt, &[0x0b; 22]).unwrap();
+        assert_eq!(hash, [0xb2, 0xa3, 0xd4, 0x51, 0x26, 0xd3]);
+    }
+
+    #[test]
+    fn test_verify_paired_key_encryption() {
+        let local = LocalCertificate::generate();
+        let auth_token = gen_random(32);
+        let frame = local.paired_key_encryption(&auth_token).unwrap();
+
+        assert_eq!(
+            verify_with(&[known(&local)], &frame, &auth_token),
+            paired_key_result_frame::Status::Success
+        );
+        // Unknown peer
+        assert_eq!(
+            verify_with(&[known(&LocalCertificate::generate())], &frame, &auth_token),
+            paired_key_result_frame::Status::Unable
+        );
+        // Replayed from another connection
+        assert_eq!(
+            verify_with(&[known(&local)], &frame, &gen_random(32)),
+            paired_key_result_frame::Status::Unable
+        );
+    }
+
+    #[test]
+    fn test_verify_forged_signature() {
+        let local = LocalCertificate::generate();
+        let auth_token = gen_random(32);
+        let mut frame = local.paired_key_encryption(&auth_token).unwrap();
+        let forged: Signature = SigningKey::random(&mut OsRng).sign(&auth_token);
+        frame.signed_data = Some(forged.to_der().as_bytes().to_vec());
+
+        assert_eq!(
+            verify_with(&[known(&local)], &frame, &auth_token),
+            paired_key_result_frame::Status::Fail
+        );
+    }
+
+    #[test]
+    fn test_bound_certificate() {
+        let local = LocalCertificate::generate();
+        let other = LocalCertificate::generate();
+        let auth_token = gen_random(32);
+        let frame = local.paired_key_encryption(&auth_token).unwrap();
+        let certificates = [
+            other.public_certificate().unwrap(),
+            local.public_certificate().unwrap(),
+        ];
+
+        let bound = bound_certificate(&certificates, &frame, &auth_token).unwrap();
+        assert_eq!(bound.secret_id, Some(local.secret_id.clone()));
+        // Only the other one
+        assert!(bound_certificate(&certificates[..1], &frame, &auth_token).is_none());
+        // Proven on another connection
+        assert!(bound_certificate(&certificates, &frame, &gen_random(32)).is_none());
+    }
+
+    #[test]
+    fn test_keep_never_replaces_certificate() {
+        let local = LocalCertificate::generate();
+        let mut known_devices = vec![known(&local)];
+
+        let mut renamed = known(&local);
+        renamed.name = Some("Pixel 9".into());
+        assert!(keep(&mut known_devices, renamed));
+        assert_eq!(known_devices.len(), 1);
+        assert_eq!(known_devices[0].name.as_deref(), Some("Pixel 9"));
+
+        // Same secret id, another key
+        let mut impostor = LocalCertificate::generate();
+        impostor.secret_id.clone_from(&local.secret_id);
+        assert!(!keep(&mut known_devices, known(&impostor)));
+        assert_eq!(known_devices[0].public_key, known(&local).public_key);
+    }
+
+    #[test]
+    fn test_expired_certificate() {
+        let local = LocalCertificate::generate();
+        let auth_token = gen_random(32);
+        let frame = local.paired_key_encryption(&auth_token).unwrap();
+        let mut device = known(&local);
+        device.end_time = device.start_time - 1;
+
+        assert_eq!(
+            verify_with(&[device], &frame, &auth_token),
+            paired_key_result_frame::Status::Unable
+        );
+    }
+}
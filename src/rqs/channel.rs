@@ -7,6 +7,9 @@ pub enum TransferAction {
     ConsentAccept,
     ConsentDecline,
     TransferCancel,
+    /// Stop transferring a single file (by payload id, see `TransferMetadata::files`),
+    /// the rest of the transfer carries on
+    CancelPayload(i64),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -25,13 +28,13 @@ pub struct MessageClient {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     Lib { action: TransferAction },
-    Client(MessageClient),
+    Client(Box<MessageClient>),
 }
 
 impl Message {
     pub fn as_client(&self) -> Option<&MessageClient> {
         match self {
-            Message::Client(message_client) => Some(message_client),
+            Message::Client(message_client) => Some(message_client.as_ref()),
             _ => None,
         }
     }
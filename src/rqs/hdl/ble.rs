@@ -1,34 +1,182 @@
+use std::future::Future;
 use std::time::{Duration, SystemTime};
 
 use anyhow::anyhow;
-use btleplug::api::{Central, CentralEvent, Manager as _, ScanFilter};
+use btleplug::api::{Central, CentralEvent, CentralState, Manager as _, ScanFilter};
 use btleplug::platform::{Adapter, Manager};
 use futures::stream::StreamExt;
 use tokio::sync::broadcast::Sender;
 use tokio_util::sync::CancellationToken;
 use uuid::{uuid, Uuid};
 
+use crate::BLE_CONFIG;
+
 const SERVICE_UUID_SHARING: Uuid = uuid!("0000fe2c-0000-1000-8000-00805f9b34fb");
 
 const INNER_NAME: &str = "BleListener";
 
+/// How long to wait before trying again when BLE couldn't start (e.g. the adapter is off)
+const BLE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// There's no Bluetooth adapter (matching the config), unlike one being off.
+#[derive(Debug)]
+pub struct NoAdapter(pub Option<String>);
+
+impl std::fmt::Display for NoAdapter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            None => write!(f, "no bluetooth adapter"),
+            Some(selector) => write!(f, "no bluetooth adapter matching {selector}"),
+        }
+    }
+}
+
+impl std::error::Error for NoAdapter {}
+
+/// The Bluetooth adapter is off, and `BleConfig::power_on` isn't set.
+#[derive(Debug)]
+pub struct AdapterOff(pub String);
+
+impl std::fmt::Display for AdapterOff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bluetooth adapter {} is off", self.0)
+    }
+}
+
+impl std::error::Error for AdapterOff {}
+
+/// Which Bluetooth adapter to use and what for. Can be changed while running.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BleConfig {
+    /// Adapter name (`hci0`) or address, the first one otherwise
+    pub adapter: Option<String>,
+    /// Listen for devices sharing nearby, to announce ourselves again over mDNS
+    pub listen: bool,
+    /// Advertise while discovering, to wake up nearby receivers
+    pub advertise: bool,
+    /// Turn the adapter on when it's off. Otherwise BLE waits for it to be turned on
+    pub power_on: bool,
+}
+
+impl Default for BleConfig {
+    fn default() -> Self {
+        Self {
+            adapter: None,
+            listen: true,
+            advertise: true,
+            power_on: false,
+        }
+    }
+}
+
+/// Name of the adapter described by btleplug's `adapter_info`: "<name> (<modalias>)".
+pub fn adapter_info_name(adapter_info: &str) -> &str {
+    adapter_info.split(' ').next().unwrap_or(adapter_info)
+}
+
+pub fn get_ble_config() -> BleConfig {
+    BLE_CONFIG.borrow().clone()
+}
+
+/// Run `task` while `enabled` holds for the `BleConfig`, restarting it when the config changes
+/// and retrying every `BLE_RETRY_INTERVAL` when it fails. A missing or powered off adapter is
+/// only reported once, in case one gets plugged in or turned on later.
+pub async fn supervise<F, Fut>(name: &str, enabled: fn(&BleConfig) -> bool, ctk: CancellationToken, mut task: F)
+where
+    F: FnMut(BleConfig, CancellationToken) -> Fut,
+    Fut: Future<Output = Result<(), anyhow::Error>>,
+{
+    let mut config_rx = BLE_CONFIG.subscribe();
+    // What we're waiting for (no adapter, adapter off), once reported
+    let mut waiting_reported: Option<String> = None;
+
+    loop {
+        let config = config_rx.borrow_and_update().clone();
+        let is_enabled = enabled(&config);
+
+        if is_enabled {
+            let task_ctk = ctk.child_token();
+            let run = task(config, task_ctk.clone());
+            tokio::pin!(run);
+
+            tokio::select! {
+                r = &mut run => {
+                    match r {
+                        Err(e) if e.is::<NoAdapter>() || e.is::<AdapterOff>() => {
+                            let waiting = e.to_string();
+                            if waiting_reported.as_ref() == Some(&waiting) {
+                                debug!("{name}: {e}");
+                            } else {
+                                info!("{name}: {e}, waiting");
+                                waiting_reported = Some(waiting);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("{name}: {e}");
+                            waiting_reported = None;
+                        }
+                        Ok(()) => waiting_reported = None,
+                    }
+                }
+                _ = config_rx.changed() => {
+                    info!("{name}: Bluetooth config changed, restarting");
+                    waiting_reported = None;
+                    task_ctk.cancel();
+                    drop(run.await);
+                    continue;
+                }
+            }
+        } else {
+            info!("{name}: disabled");
+        }
+
+        tokio::select! {
+            _ = ctk.cancelled() => break,
+            _ = config_rx.changed() => waiting_reported = None,
+            () = tokio::time::sleep(BLE_RETRY_INTERVAL), if is_enabled => {}
+        }
+    }
+}
+
 pub struct BleListener {
     adapter: Adapter,
     sender: Sender<()>,
 }
 
 impl BleListener {
-    pub async fn new(sender: Sender<()>) -> Result<Self, anyhow::Error> {
+    pub async fn new(sender: Sender<()>, config: &BleConfig) -> Result<Self, anyhow::Error> {
         let manager = Manager::new().await?;
         let adapters = manager.adapters().await?;
-        if adapters.is_empty() {
-            return Err(anyhow!("no bluetooth adapter"));
+
+        let adapter = match &config.adapter {
+            Some(selector) => {
+                // Resolve an address to the name first
+                #[cfg(target_os = "linux")]
+                let selector = &super::ble_adapter_name(selector).await?;
+                let mut found = None;
+                for adapter in &adapters {
+                    let info = adapter.adapter_info().await?;
+                    if adapter_info_name(&info) == selector {
+                        found = Some(adapter.clone());
+                        break;
+                    }
+                }
+                found.ok_or_else(|| anyhow!(NoAdapter(Some(selector.clone()))))?
+            }
+            None => adapters.first().cloned().ok_or_else(|| anyhow!(NoAdapter(None)))?,
+        };
+
+        if adapter.adapter_state().await? != CentralState::PoweredOn {
+            #[cfg(target_os = "linux")]
+            if config.power_on {
+                super::power_on_adapter(&adapter.adapter_info().await?).await?;
+                return Ok(Self { adapter, sender });
+            }
+            let info = adapter.adapter_info().await?;
+            return Err(anyhow!(AdapterOff(adapter_info_name(&info).to_owned())));
         }
 
-        Ok(Self {
-            adapter: adapters[0].clone(),
-            sender,
-        })
+        Ok(Self { adapter, sender })
     }
 
     pub async fn run(self, ctk: CancellationToken) -> Result<(), anyhow::Error> {
@@ -86,6 +234,20 @@ impl BleListener {
             }
         }
 
+        drop(self.adapter.stop_scan().await);
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adapter_info_name() {
+        assert_eq!(adapter_info_name("hci10 (usb:v1D6Bp0246d0537)"), "hci10");
+        assert_ne!(adapter_info_name("hci10 (usb:v1D6Bp0246d0537)"), "hci1");
+        assert_eq!(adapter_info_name("hci0"), "hci0");
+    }
+}
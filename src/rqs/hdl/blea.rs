@@ -1,11 +1,14 @@
 use std::sync::Arc;
 
+use anyhow::anyhow;
 use bluer::UuidExt;
 use bluer::adv::Advertisement;
 use bytes::Bytes;
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
+use super::{AdapterOff, BleConfig, NoAdapter};
+
 const SERVICE_DATA: Bytes = Bytes::from_static(&[
     252, 18, 142, 1, 66, 0, 0, 0, 0, 0, 0, 0, 0, 0, 191, 45, 91, 160, 225, 216, 117, 36, 202, 0,
 ]);
@@ -17,11 +20,51 @@ pub struct BleAdvertiser {
     adapter: Arc<bluer::Adapter>,
 }
 
+/// The adapter with this name or address.
+async fn find_adapter(session: &bluer::Session, selector: &str) -> Result<bluer::Adapter, anyhow::Error> {
+    for name in session.adapter_names().await? {
+        let adapter = session.adapter(&name)?;
+        if name == selector || adapter.address().await?.to_string().eq_ignore_ascii_case(selector) {
+            return Ok(adapter);
+        }
+    }
+
+    Err(anyhow!(NoAdapter(Some(selector.to_owned()))))
+}
+
+/// Name of the adapter with this name or address.
+pub async fn ble_adapter_name(selector: &str) -> Result<String, anyhow::Error> {
+    let session = bluer::Session::new().await?;
+    Ok(find_adapter(&session, selector).await?.name().to_string())
+}
+
+/// Power on the adapter described by `adapter_info` (starting with its name).
+pub async fn power_on_adapter(adapter_info: &str) -> Result<(), anyhow::Error> {
+    let name = super::adapter_info_name(adapter_info);
+    info!("{INNER_NAME}: powering on Bluetooth adapter {name}");
+    bluer::Session::new().await?.adapter(name)?.set_powered(true).await?;
+
+    Ok(())
+}
+
 impl BleAdvertiser {
-    pub async fn new() -> Result<Self, anyhow::Error> {
+    pub async fn new(config: &BleConfig) -> Result<Self, anyhow::Error> {
         let session = bluer::Session::new().await?;
-        let adapter = session.default_adapter().await?;
-        adapter.set_powered(true).await?;
+        let adapter = match &config.adapter {
+            Some(selector) => find_adapter(&session, selector).await?,
+            None => session.default_adapter().await.map_err(|e| match e.kind {
+                bluer::ErrorKind::NotFound => anyhow!(NoAdapter(None)),
+                _ => e.into(),
+            })?,
+        };
+
+        if !adapter.is_powered().await? {
+            if !config.power_on {
+                return Err(anyhow!(AdapterOff(adapter.name().to_owned())));
+            }
+            info!("{INNER_NAME}: powering on Bluetooth adapter {}", adapter.name());
+            adapter.set_powered(true).await?;
+        }
 
         Ok(Self {
             adapter: Arc::new(adapter),
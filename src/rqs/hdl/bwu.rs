@@ -0,0 +1,419 @@
+//! Bandwidth upgrade (BWU) of a connection to a fresh Wi-Fi LAN socket.
+//!
+//! As in Nearby Connections, the side that accepted the connection (inbound) offers the upgrade:
+//! 1. initiator: `UPGRADE_PATH_AVAILABLE` with the address of a new listener, on the prior socket
+//! 2. responder: connects and sends `CLIENT_INTRODUCTION` (plaintext, it identifies the endpoint)
+//! 3. initiator: `CLIENT_INTRODUCTION_ACK` (plaintext)
+//! 4. both: `LAST_WRITE_TO_PRIOR_CHANNEL` on the prior socket, everything after goes to the new one
+//! 5. both: once the peer's `LAST_WRITE_TO_PRIOR_CHANNEL` is read, `SAFE_TO_CLOSE_PRIOR_CHANNEL`
+//!    is sent on the prior socket, which is closed once the peer's one is read
+//!
+//! The encryption keys and sequence numbers carry over to the new socket. Frames are still
+//! processed strictly in sequence: the new socket isn't read before the prior one is drained
+//! up to the peer's `LAST_WRITE_TO_PRIOR_CHANNEL`, and the frame of the new socket that
+//! overtakes its `SAFE_TO_CLOSE_PRIOR_CHANNEL` is held until that one is read.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+use crate::BANDWIDTH_UPGRADE;
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::upgrade_path_info::{
+    Medium, WifiLanSocket,
+};
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::{
+    ClientIntroduction, ClientIntroductionAck, EventType, UpgradePathInfo,
+};
+use crate::location_nearby_connections::{
+    BandwidthUpgradeNegotiationFrame, OfflineFrame, V1Frame, offline_frame, v1_frame,
+};
+use crate::securemessage::SecureMessage;
+use crate::utils::{LengthReader, stream_read_exact};
+
+/// How long the responder has to connect and introduce itself (and the initiator to ack)
+pub const BWU_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SANE_FRAME_LENGTH: usize = 5 * 1024 * 1024;
+
+/// Where a connection is in its bandwidth upgrade.
+#[derive(Debug, Default)]
+pub enum Upgrade {
+    #[default]
+    None,
+    /// Initiator: waiting for the responder to connect, until the deadline
+    Offered { listener: TcpListener, deadline: Instant },
+    /// Writes go to the new socket, the prior one is read until the peer is done with it
+    Migrating {
+        prior: TcpStream,
+        /// Length of the next frame on the prior socket, as far as it was read
+        prior_length: LengthReader,
+        /// The peer's `LAST_WRITE_TO_PRIOR_CHANNEL` was read, the new socket is read too
+        drained: bool,
+        /// Nothing more comes on the prior socket (`SAFE_TO_CLOSE_PRIOR_CHANNEL` read, or closed)
+        done: bool,
+        /// A frame of the new socket ahead of the one still on the prior socket, with its sequence number
+        held: Option<(i32, SecureMessage)>,
+    },
+}
+
+/// Something happening on the sockets of an upgrade.
+#[derive(Debug)]
+pub enum UpgradeEvent {
+    /// The responder connected to our listener
+    Connected(TcpStream),
+    /// Length of the next frame waiting on the prior socket
+    PriorFrame([u8; 4]),
+}
+
+impl Upgrade {
+    /// `prior_length` carries over what was read of a frame length on the prior socket.
+    pub fn migrating(prior: TcpStream, prior_length: LengthReader) -> Self {
+        Self::Migrating {
+            prior,
+            prior_length,
+            drained: false,
+            done: false,
+            held: None,
+        }
+    }
+
+    pub fn is_migrating(&self) -> bool {
+        matches!(self, Self::Migrating { .. })
+    }
+
+    /// Whether frames can be read from the current socket: not before the prior one is drained,
+    /// nor while a frame waits for the one still on the prior socket.
+    pub fn reads_current(&self) -> bool {
+        match self {
+            Self::Migrating { drained, held, .. } => *drained && held.is_none(),
+            _ => true,
+        }
+    }
+
+    /// The peer's `LAST_WRITE_TO_PRIOR_CHANNEL` was read.
+    pub fn prior_drained(&mut self) {
+        if let Self::Migrating { drained, .. } = self {
+            *drained = true;
+        }
+    }
+
+    /// Nothing more will be read from the prior socket.
+    pub fn prior_done(&mut self) {
+        if let Self::Migrating { done, .. } = self {
+            *done = true;
+        }
+    }
+
+    /// Keep a frame that came out of sequence for later, if it may have overtaken
+    /// the last one of the prior socket. Only one frame is ever held.
+    pub fn hold(&mut self, sequence_number: i32, smsg: &SecureMessage) -> bool {
+        match self {
+            Self::Migrating {
+                drained: true,
+                done: false,
+                held: held @ None,
+                ..
+            } => {
+                *held = Some((sequence_number, smsg.clone()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The held frame, once it's the next one or once the prior socket is done with,
+    /// which also ends the migration.
+    pub fn take_held(&mut self, next_sequence_number: i32) -> Option<SecureMessage> {
+        let Self::Migrating { held, done, .. } = self else {
+            return None;
+        };
+
+        let ready = *done || held.as_ref().is_some_and(|(seq, _)| *seq == next_sequence_number);
+        let smsg = if ready { held.take().map(|(_, smsg)| smsg) } else { None };
+        if *done {
+            *self = Self::None;
+        }
+        smsg
+    }
+
+    /// Never resolves when there's no upgrade going on.
+    pub async fn next_event(&mut self) -> Result<UpgradeEvent, anyhow::Error> {
+        match self {
+            Self::Offered { listener, deadline } => {
+                let (socket, _) = tokio::time::timeout_at(*deadline, listener.accept())
+                    .await
+                    .map_err(|_| anyhow!("nobody connected in time"))??;
+                Ok(UpgradeEvent::Connected(socket))
+            }
+            Self::Migrating { prior, prior_length, done: false, .. } => {
+                Ok(UpgradeEvent::PriorFrame(prior_length.read(prior).await?))
+            }
+            Self::Migrating { .. } | Self::None => std::future::pending().await,
+        }
+    }
+}
+
+pub fn upgrade_enabled() -> bool {
+    BANDWIDTH_UPGRADE.read().map(|g| *g).unwrap_or(false)
+}
+
+pub fn bwu_frame(frame: BandwidthUpgradeNegotiationFrame) -> OfflineFrame {
+    OfflineFrame {
+        version: Some(offline_frame::Version::V1.into()),
+        v1: Some(V1Frame {
+            r#type: Some(v1_frame::FrameType::BandwidthUpgradeNegotiation.into()),
+            bandwidth_upgrade_negotiation: Some(frame),
+            ..Default::default()
+        }),
+    }
+}
+
+/// A frame with only the event set (`LAST_WRITE_TO_PRIOR_CHANNEL`, `SAFE_TO_CLOSE_PRIOR_CHANNEL`).
+pub fn bwu_event_frame(event: EventType) -> OfflineFrame {
+    bwu_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(event.into()),
+        ..Default::default()
+    })
+}
+
+pub fn upgrade_path_available_frame(addr: SocketAddr) -> OfflineFrame {
+    let ip_address = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    bwu_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(EventType::UpgradePathAvailable.into()),
+        upgrade_path_info: Some(UpgradePathInfo {
+            medium: Some(Medium::WifiLan.into()),
+            wifi_lan_socket: Some(WifiLanSocket {
+                ip_address: Some(ip_address),
+                wifi_port: Some(i32::from(addr.port())),
+            }),
+            supports_disabling_encryption: Some(false),
+            supports_client_introduction_ack: Some(true),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+pub fn upgrade_failure_frame(medium: Medium) -> OfflineFrame {
+    bwu_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(EventType::UpgradeFailure.into()),
+        upgrade_path_info: Some(UpgradePathInfo {
+            medium: Some(medium.into()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+pub fn client_introduction_frame(endpoint_id: &str) -> OfflineFrame {
+    bwu_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(EventType::ClientIntroduction.into()),
+        client_introduction: Some(ClientIntroduction {
+            endpoint_id: Some(endpoint_id.to_owned()),
+            supports_disabling_encryption: Some(false),
+        }),
+        ..Default::default()
+    })
+}
+
+pub fn client_introduction_ack_frame() -> OfflineFrame {
+    bwu_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(EventType::ClientIntroductionAck.into()),
+        client_introduction_ack: Some(ClientIntroductionAck {}),
+        ..Default::default()
+    })
+}
+
+/// Address of the socket offered in an `UPGRADE_PATH_AVAILABLE`.
+pub fn wifi_lan_addr(info: &UpgradePathInfo) -> Result<SocketAddr, anyhow::Error> {
+    if info.medium() != Medium::WifiLan {
+        return Err(anyhow!("unsupported upgrade medium: {:?}", info.medium()));
+    }
+
+    let socket = info
+        .wifi_lan_socket
+        .as_ref()
+        .ok_or_else(|| anyhow!("missing wifi_lan_socket"))?;
+    let ip = match socket.ip_address() {
+        &[a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        bytes => {
+            let octets: [u8; 16] = bytes
+                .try_into()
+                .map_err(|_| anyhow!("invalid ip_address length: {}", bytes.len()))?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    };
+    let port = u16::try_from(socket.wifi_port()).map_err(|_| anyhow!("invalid wifi_port"))?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// The BWU event of a frame, if it is one.
+pub fn bwu_event(frame: &OfflineFrame) -> Option<EventType> {
+    frame
+        .v1
+        .as_ref()
+        .filter(|v1| v1.r#type() == v1_frame::FrameType::BandwidthUpgradeNegotiation)
+        .and_then(|v1| v1.bandwidth_upgrade_negotiation.as_ref())
+        .map(BandwidthUpgradeNegotiationFrame::event_type)
+}
+
+/// Write a length-prefixed frame.
+pub async fn write_frame(socket: &mut TcpStream, data: &[u8]) -> Result<(), anyhow::Error> {
+    let length: u32 = data.len().try_into().map_err(|_| anyhow!("Frame too large"))?;
+
+    let mut prefixed_length = Vec::with_capacity(data.len() + 4);
+    prefixed_length.extend_from_slice(&length.to_be_bytes());
+    prefixed_length.extend_from_slice(data);
+
+    socket.write_all(&prefixed_length).await?;
+    socket.flush().await?;
+
+    Ok(())
+}
+
+/// Read the body of a frame whose length was already read.
+pub async fn read_frame_body(socket: &mut TcpStream, length_buf: [u8; 4]) -> Result<Vec<u8>, anyhow::Error> {
+    let msg_length = u32::from_be_bytes(length_buf) as usize;
+    if msg_length > SANE_FRAME_LENGTH {
+        return Err(anyhow!("Message length too big"));
+    }
+
+    let mut frame_data = vec![0u8; msg_length];
+    stream_read_exact(socket, &mut frame_data).await?;
+
+    Ok(frame_data)
+}
+
+/// Read a whole length-prefixed frame.
+pub async fn read_frame(socket: &mut TcpStream) -> Result<Vec<u8>, anyhow::Error> {
+    let mut length_buf = [0u8; 4];
+    stream_read_exact(socket, &mut length_buf).await?;
+
+    read_frame_body(socket, length_buf).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use prost::Message;
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::hdl::connected_pair;
+
+    #[tokio::test]
+    async fn test_wifi_lan_handshake_on_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let offer = upgrade_path_available_frame(listener.local_addr().unwrap());
+
+        // Responder side: parse the offer, connect and introduce itself
+        let info = offer.v1.as_ref().unwrap().bandwidth_upgrade_negotiation.as_ref().unwrap();
+        let addr = wifi_lan_addr(info.upgrade_path_info.as_ref().unwrap()).unwrap();
+        assert_eq!(addr, listener.local_addr().unwrap());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut client, &client_introduction_frame("ABCD").encode_to_vec())
+            .await
+            .unwrap();
+
+        // Initiator side: read the introduction and ack it
+        let (mut server, _) = listener.accept().await.unwrap();
+        let intro = OfflineFrame::decode(&*read_frame(&mut server).await.unwrap()).unwrap();
+        assert_eq!(bwu_event(&intro), Some(EventType::ClientIntroduction));
+        let endpoint_id = intro.v1.unwrap().bandwidth_upgrade_negotiation.unwrap().client_introduction.unwrap();
+        assert_eq!(endpoint_id.endpoint_id(), "ABCD");
+        write_frame(&mut server, &client_introduction_ack_frame().encode_to_vec())
+            .await
+            .unwrap();
+
+        let ack = OfflineFrame::decode(&*read_frame(&mut client).await.unwrap()).unwrap();
+        assert_eq!(bwu_event(&ack), Some(EventType::ClientIntroductionAck));
+    }
+
+    #[tokio::test]
+    async fn test_holds_one_frame_until_prior_is_done() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let prior = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let smsg = SecureMessage::default();
+
+        let mut upgrade = Upgrade::migrating(prior, LengthReader::default());
+        // Not drained yet, frames must come in sequence
+        assert!(!upgrade.reads_current());
+        assert!(!upgrade.hold(12, &smsg));
+
+        upgrade.prior_drained();
+        assert!(upgrade.reads_current());
+        assert!(upgrade.hold(12, &smsg));
+        assert!(!upgrade.hold(13, &smsg));
+        assert!(!upgrade.reads_current());
+
+        // Frame 11 still expected from the prior socket
+        assert!(upgrade.take_held(11).is_none());
+        assert!(upgrade.take_held(12).is_some());
+        assert!(upgrade.reads_current());
+
+        assert!(upgrade.hold(14, &smsg));
+        upgrade.prior_done();
+        assert!(upgrade.take_held(13).is_some());
+        assert!(!upgrade.is_migrating());
+    }
+
+    #[tokio::test]
+    async fn test_handlers_upgrade_and_continue_on_new_socket() {
+        if let Ok(mut guard) = BANDWIDTH_UPGRADE.write() {
+            *guard = true;
+        }
+        let (sender, _) = broadcast::channel(50);
+        let (mut inbound, mut outbound) = connected_pair(sender.clone()).await.unwrap();
+
+        inbound.offer_upgrade().await.unwrap();
+        let Upgrade::Offered { listener, .. } = &inbound.upgrade else {
+            panic!("no upgrade offered");
+        };
+        let upgrade_addr = listener.local_addr().unwrap();
+
+        // Both sides until they're done with the prior socket
+        tokio::join!(
+            async {
+                while !inbound.state.upgraded || inbound.upgrade.is_migrating() {
+                    inbound.handle().await.unwrap();
+                }
+            },
+            async {
+                while !outbound.state.upgraded || outbound.upgrade.is_migrating() {
+                    outbound.handle().await.unwrap();
+                }
+            },
+        );
+        assert_eq!(inbound.socket.local_addr().unwrap(), upgrade_addr);
+        assert_eq!(outbound.socket.peer_addr().unwrap(), upgrade_addr);
+
+        // A keep-alive and its ack, over the new socket and still in sequence
+        outbound.state.keep_alive.enabled = true;
+        outbound.state.keep_alive.last_sent -= outbound.state.keep_alive.interval;
+        let (sent, answered) = tokio::join!(outbound.handle(), inbound.handle());
+        sent.unwrap();
+        answered.unwrap();
+
+        assert_eq!(inbound.state.client_seq, outbound.state.server_seq);
+        assert_eq!(outbound.state.client_seq, inbound.state.server_seq);
+    }
+
+    #[test]
+    fn test_wifi_lan_addr_rejects_other_mediums() {
+        let info = UpgradePathInfo {
+            medium: Some(Medium::WifiHotspot.into()),
+            ..Default::default()
+        };
+        assert!(wifi_lan_addr(&info).is_err());
+    }
+}
@@ -1,6 +1,6 @@
-use std::fs::File;
-use std::os::unix::fs::FileExt;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, anyhow};
@@ -13,17 +13,39 @@ use prost::Message;
 use rand::Rng;
 use sha2::{Digest, Sha256, Sha512};
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::Instant;
 
-use super::{InnerState, TransferState};
+use super::bwu::{
+    BWU_HANDSHAKE_TIMEOUT, Upgrade, UpgradeEvent, bwu_event, bwu_event_frame,
+    client_introduction_ack_frame, read_frame, read_frame_body, upgrade_enabled,
+    upgrade_failure_frame, upgrade_path_available_frame, write_frame,
+};
+use super::resume::{self, Registration, reconnect_frame, resume_frame};
+use super::{
+    DEFAULT_CONSENT_TIMEOUT, InnerState, KeepAlive, TransferState, control_frame, log_skipped_frame,
+};
+use crate::CONSENT_TIMEOUT;
+use crate::certs;
 use crate::channel::{self, ChannelMessage, MessageClient, TransferAction, TransferKind};
 use crate::hdl::TextPayloadInfo;
-use crate::hdl::info::{InternalFileInfo, TransferMetadata, TransferPayload, TransferPayloadKind};
+use crate::history::HistoryFile;
+use crate::hdl::info::{
+    FileOutcome, FileOutcomeKind, InternalFileInfo, TransferFile, TransferMetadata, TransferPayload,
+    TransferPayloadKind,
+};
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::{EventType, UpgradePathInfo};
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::upgrade_path_info::Medium;
+use crate::location_nearby_connections::connection_request_frame;
 use crate::location_nearby_connections::payload_transfer_frame::{
-    ControlMessage, PacketType, PayloadChunk, PayloadHeader, control_message, payload_header,
+    PacketType, PayloadChunk, PayloadHeader, control_message, payload_header,
+};
+use crate::location_nearby_connections::{
+    AutoReconnectFrame, AutoResumeFrame, KeepAliveFrame, OfflineFrame, PayloadTransferFrame,
+    auto_reconnect_frame, auto_resume_frame,
 };
-use crate::location_nearby_connections::{KeepAliveFrame, OfflineFrame, PayloadTransferFrame};
+use crate::scanner::{ScanVerdict, get_scan_config, quarantine_file, scan_file};
 use crate::securegcm::ukey2_alert::AlertType;
 use crate::securegcm::{
     DeviceToDeviceMessage, GcmMetadata, Type, Ukey2Alert, Ukey2ClientFinished, Ukey2ClientInit,
@@ -35,8 +57,10 @@ use crate::securemessage::{
 };
 use crate::sharing_nearby::wifi_credentials_metadata::SecurityType;
 use crate::sharing_nearby::{paired_key_result_frame, text_metadata};
+use crate::sink::{FsSink, IncomingFile, get_custom_sink};
+use crate::text_actions::handle_text_payload;
 use crate::utils::{
-    DeviceType, RemoteDeviceInfo, encode_point, gen_ecdsa_keypair, gen_random, get_download_dir,
+    LengthReader, RemoteDeviceInfo, encode_point, gen_ecdsa_keypair, gen_random, get_download_dir,
     hkdf_extract_expand, stream_read_exact, to_four_digit_string,
 };
 use crate::{location_nearby_connections, sharing_nearby};
@@ -46,12 +70,20 @@ type HmacSha256 = Hmac<Sha256>;
 const SANE_FRAME_LENGTH: i32 = 5 * 1024 * 1024;
 const SANITY_DURATION: Duration = Duration::from_micros(10);
 
-/// Read timeout for inbound connections (how long to wait for next frame from sender)
-const READ_TIMEOUT: Duration = Duration::from_secs(60);
+fn consent_timeout() -> Option<Duration> {
+    CONSENT_TIMEOUT.read().map(|g| *g).unwrap_or(Some(DEFAULT_CONSENT_TIMEOUT))
+}
 
 #[derive(Debug)]
 pub struct InboundRequest {
-    socket: TcpStream,
+    pub(super) socket: TcpStream,
+    pub(super) upgrade: Upgrade,
+    /// Length of the next frame on `socket`, as far as it was read
+    length_reader: LengthReader,
+    /// Set while receiving files, for a reconnection of the sender to take over
+    registration: Option<Registration>,
+    /// When the transfer gets declined as TIMED_OUT, while waiting for the user
+    consent_deadline: Option<Instant>,
     pub state: InnerState,
     sender: Sender<ChannelMessage>,
     receiver: Receiver<ChannelMessage>,
@@ -63,6 +95,10 @@ impl InboundRequest {
 
         Self {
             socket,
+            upgrade: Upgrade::None,
+            length_reader: LengthReader::default(),
+            registration: None,
+            consent_deadline: None,
             state: InnerState::new(id, None),
             sender,
             receiver,
@@ -70,10 +106,15 @@ impl InboundRequest {
     }
 
     pub async fn handle(&mut self) -> Result<(), anyhow::Error> {
-        // Buffer for the 4-byte length
-        let mut length_buf = [0u8; 4];
+        if self.state.keep_alive.is_due() {
+            trace!("Sending keepalive");
+            self.send_keepalive(false).await?;
+        }
 
         tokio::select! {
+            // A frame that is already there shouldn't lose against the keep-alive timer
+            biased;
+
             i = self.receiver.recv() => {
                 match i {
                     Ok(channel_msg) => {
@@ -110,6 +151,9 @@ impl InboundRequest {
                                     self.disconnection().await?;
                                     return Err(anyhow!(crate::errors::AppError::NotAnError));
                                 },
+                                TransferAction::CancelPayload(payload_id) => {
+                                    self.cancel_payload(*payload_id).await?;
+                                },
                             }
                         }
 
@@ -119,16 +163,31 @@ impl InboundRequest {
                     }
                 }
             },
-            result = tokio::time::timeout(READ_TIMEOUT, stream_read_exact(&mut self.socket, &mut length_buf)) => {
-                match result {
-                    Ok(h) => {
-                        h?;
-                        self._handle(length_buf).await?;
-                    }
-                    Err(_) => {
-                        warn!("Inbound read timeout after {}s in state {:?}", READ_TIMEOUT.as_secs(), self.state.state);
-                        return Err(anyhow!("Read timeout"));
-                    }
+            // While migrating, the prior socket is drained first to keep the frames in order
+            h = self.length_reader.read(&mut self.socket), if self.upgrade.reads_current() => {
+                self.state.keep_alive.last_received = Instant::now();
+                self._handle(h?).await?;
+            }
+            event = self.upgrade.next_event() => {
+                self.process_upgrade_event(event).await?;
+            }
+            () = resume::superseded(self.registration.as_ref()) => {
+                return Err(anyhow!("Superseded by a reconnection of the sender"));
+            }
+            () = tokio::time::sleep_until(self.consent_deadline.unwrap_or_else(Instant::now)),
+                if self.consent_deadline.is_some() && self.state.state == TransferState::WaitingForUserConsent =>
+            {
+                self.consent_timed_out().await?;
+            }
+            // Only reached when nothing is waiting on the socket
+            () = tokio::time::sleep_until(self.state.keep_alive.next_deadline()) => {
+                if self.state.keep_alive.is_expired() {
+                    warn!(
+                        "No frame from the sender for {}s in state {:?}",
+                        self.state.keep_alive.timeout.as_secs(),
+                        self.state.state
+                    );
+                    return Err(anyhow!("Keep-alive timeout"));
                 }
             }
         }
@@ -153,19 +212,7 @@ impl InboundRequest {
         match current_state.state {
             TransferState::Initial => {
                 debug!("Handling State::Initial frame");
-                let frame = location_nearby_connections::OfflineFrame::decode(&*frame_data)?;
-                let rdi = self.process_connection_request(&frame)?;
-                info!("RemoteDeviceInfo: {:?}", &rdi);
-
-                // Advance current state
-                self.update_state(
-                    |e: &mut InnerState| {
-                        e.state = TransferState::ReceivedConnectionRequest;
-                        e.remote_device_info = Some(rdi);
-                    },
-                    false,
-                )
-                .await;
+                self.handle_connection_request(&frame_data).await?;
             }
             TransferState::ReceivedConnectionRequest => {
                 debug!("Handling State::ReceivedConnectionRequest frame");
@@ -196,16 +243,7 @@ impl InboundRequest {
             }
             TransferState::ReceivedUkeyClientFinish => {
                 debug!("Handling State::ReceivedUkeyClientFinish frame");
-                let frame = location_nearby_connections::OfflineFrame::decode(&*frame_data)?;
-                self.process_connection_response(&frame).await?;
-
-                self.update_state(
-                    |e: &mut InnerState| {
-                        e.state = TransferState::SentConnectionResponse;
-                    },
-                    false,
-                )
-                .await;
+                self.handle_connection_response(&frame_data).await?;
             }
             // Only process encrypted messages after key exchange is complete
             TransferState::SentConnectionResponse
@@ -230,6 +268,65 @@ impl InboundRequest {
         Ok(())
     }
 
+    /// First frame of the sender: who it is and what the connection will look like.
+    async fn handle_connection_request(&mut self, frame_data: &[u8]) -> Result<(), anyhow::Error> {
+        let frame = location_nearby_connections::OfflineFrame::decode(frame_data)?;
+        let rdi = self.process_connection_request(&frame)?;
+        info!("RemoteDeviceInfo: {:?}", &rdi);
+        let keep_alive = frame
+            .v1
+            .as_ref()
+            .and_then(|v1| v1.connection_request.as_ref())
+            .map(|cr| {
+                KeepAlive::negotiated(cr.keep_alive_interval_millis(), cr.keep_alive_timeout_millis())
+            })
+            .unwrap_or_default();
+        debug!("Keep-alive: every {:?}, timeout {:?}", keep_alive.interval, keep_alive.timeout);
+        let connection_request = frame.v1.as_ref().and_then(|v1| v1.connection_request.as_ref());
+        let remote_endpoint_id = connection_request.map(|cr| cr.endpoint_id().to_owned());
+        let peer_supports_wifi_lan = connection_request
+            .is_some_and(|cr| cr.mediums().any(|m| m == connection_request_frame::Medium::WifiLan));
+
+        // Advance current state
+        self.update_state(
+            |e: &mut InnerState| {
+                e.state = TransferState::ReceivedConnectionRequest;
+                e.remote_device_info = Some(rdi);
+                e.keep_alive = keep_alive;
+                e.remote_endpoint_id = remote_endpoint_id;
+                e.peer_supports_wifi_lan = peer_supports_wifi_lan;
+            },
+            false,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    /// Last plaintext frame, everything after it is encrypted.
+    async fn handle_connection_response(&mut self, frame_data: &[u8]) -> Result<(), anyhow::Error> {
+        let frame = location_nearby_connections::OfflineFrame::decode(frame_data)?;
+        self.process_connection_response(&frame).await?;
+
+        self.update_state(
+            |e: &mut InnerState| {
+                e.state = TransferState::SentConnectionResponse;
+                e.keep_alive.enabled = true;
+            },
+            false,
+        )
+        .await;
+
+        if upgrade_enabled()
+            && self.state.peer_supports_wifi_lan
+            && let Err(e) = self.offer_upgrade().await
+        {
+            warn!("Failed to offer a bandwidth upgrade: {e}");
+        }
+
+        Ok(())
+    }
+
     fn process_connection_request(
         &self,
         frame: &location_nearby_connections::OfflineFrame,
@@ -257,30 +354,7 @@ impl InboundRequest {
             .as_ref()
             .ok_or_else(|| anyhow!("Missing endpoint info"))?;
 
-        // Check if endpoint info length is greater than 17
-        if endpoint_info.len() <= 17 {
-            return Err(anyhow!("Endpoint info too short"));
-        }
-
-        let device_name_length = endpoint_info[17] as usize;
-        // Validate length including device name
-        if endpoint_info.len() < device_name_length + 18 {
-            return Err(anyhow!(
-                "Endpoint info too short to contain the device name"
-            ));
-        }
-
-        // Extract and validate device name based on length
-        let device_name = std::str::from_utf8(&endpoint_info[18..(18 + device_name_length)])
-            .map_err(|_| anyhow!("Device name is not valid UTF-8"))?;
-
-        // Parsing the device type
-        let raw_device_type = (endpoint_info[0] & 7) >> 1_usize;
-
-        Ok(RemoteDeviceInfo {
-            name: device_name.to_string(),
-            device_type: DeviceType::from_raw_value(raw_device_type),
-        })
+        RemoteDeviceInfo::deserialize(endpoint_info)
     }
 
     async fn process_ukey2_client_init(&mut self, msg: &Ukey2Message) -> Result<(), anyhow::Error> {
@@ -467,11 +541,9 @@ impl InboundRequest {
             version: Some(sharing_nearby::frame::Version::V1.into()),
             v1: Some(sharing_nearby::V1Frame {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::PairedKeyEncryption.into()),
-                paired_key_encryption: Some(sharing_nearby::PairedKeyEncryptionFrame {
-                    secret_id_hash: Some(gen_random(6)),
-                    signed_data: Some(gen_random(72)),
-                    ..Default::default()
-                }),
+                paired_key_encryption: Some(certs::paired_key_encryption(
+                    self.state.auth_token.as_deref().unwrap_or_default(),
+                )),
                 ..Default::default()
             }),
         };
@@ -499,37 +571,19 @@ impl InboundRequest {
         Ok(String::from_utf8(payload_buffer.to_vec())?)
     }
 
-    /// Complete a text payload transfer (URL, text, or WiFi credentials).
-    async fn finish_text_transfer(&mut self, buffer: &mut [u8]) -> Result<(), anyhow::Error> {
-        info!("Transfer finished");
+    /// Complete a text payload (URL, text, or WiFi credentials).
+    async fn finish_text_payload(
+        &mut self,
+        text_payload: TextPayloadInfo,
+        buffer: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        info!("Text payload {} finished", text_payload.get_i64_value());
 
-        let text_payload = self.state.text_payload.clone()
-            .ok_or_else(|| anyhow!("Missing text_payload"))?;
-        match text_payload {
-            TextPayloadInfo::Url(_) => {
-                let payload = std::str::from_utf8(buffer)?.to_owned();
-                self.update_state(
-                    |e| {
-                        if let Some(tmd) = e.transfer_metadata.as_mut() {
-                            tmd.payload = Some(TransferPayload::Url(payload));
-                        }
-                    },
-                    false,
-                ).await;
-            }
-            TextPayloadInfo::Text(_) => {
-                let payload = std::str::from_utf8(buffer)?.to_owned();
-                self.update_state(
-                    |e| {
-                        if let Some(tmd) = e.transfer_metadata.as_mut() {
-                            tmd.payload = Some(TransferPayload::Text(payload));
-                        }
-                    },
-                    false,
-                ).await;
-            }
+        let payload = match text_payload {
+            TextPayloadInfo::Url(_) => TransferPayload::Url(std::str::from_utf8(buffer)?.to_owned()),
+            TextPayloadInfo::Text(_) => TransferPayload::Text(std::str::from_utf8(buffer)?.to_owned()),
             TextPayloadInfo::Wifi((_, ssid, security_type)) => {
-                let payload = match security_type {
+                let password = match security_type {
                     kind @ SecurityType::UnknownSecurityType => kind.as_str_name().into(),
                     SecurityType::Open => String::new(),
                     SecurityType::WpaPsk | SecurityType::Wep => {
@@ -539,24 +593,51 @@ impl InboundRequest {
                     }
                 };
 
-                self.update_state(
-                    |e| {
-                        if let Some(tmd) = e.transfer_metadata.as_mut() {
-                            tmd.payload = Some(TransferPayload::Wifi {
-                                ssid,
-                                password: payload,
-                                security_type,
-                            });
-                        }
-                    },
-                    false,
-                ).await;
+                TransferPayload::Wifi {
+                    ssid,
+                    password,
+                    security_type,
+                }
             }
+        };
+
+        handle_text_payload(&payload);
+
+        self.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    match tmd.payload.as_mut() {
+                        Some(TransferPayload::Mixed(items)) => items.push(payload),
+                        _ => tmd.payload = Some(payload),
+                    }
+                }
+            },
+            false,
+        ).await;
+
+        self.check_transfer_complete().await
+    }
+
+    /// Finish the transfer once every file and text payload has been received.
+    async fn check_transfer_complete(&mut self) -> Result<(), anyhow::Error> {
+        if !self.state.transferred_files.is_empty() || !self.state.text_payloads.is_empty() {
+            return Ok(());
         }
 
+        let has_files = self.state.transfer_metadata.as_ref()
+            .and_then(|m| m.payload.as_ref())
+            .is_some_and(TransferPayload::has_files);
+
+        info!("All payloads received, transfer finished");
         self.update_state(|e| { e.state = TransferState::Finished; }, true).await;
-        self.disconnection().await?;
-        Err(anyhow!(crate::errors::AppError::NotAnError))
+
+        if has_files {
+            // Receiver must initiate disconnect - Android waits for this
+            self.request_disconnection().await
+        } else {
+            self.disconnection().await?;
+            Err(anyhow!(crate::errors::AppError::NotAnError))
+        }
     }
 
     /// Process a bytes payload chunk.
@@ -568,6 +649,11 @@ impl InboundRequest {
         info!("Processing PayloadType::Bytes");
         let payload_id = header.id();
 
+        if self.state.cancelled_payloads.contains(&payload_id) {
+            trace!("Skipping chunk of cancelled payload {payload_id}");
+            return Ok(());
+        }
+
         if header.total_size() > i64::from(SANE_FRAME_LENGTH) {
             self.state.payload_buffers.remove(&payload_id);
             return Err(anyhow!("Payload too large: {} bytes", header.total_size()));
@@ -605,15 +691,12 @@ impl InboundRequest {
         if (chunk.flags() & 1) == 1 {
             debug!("End of bytes payload");
 
-            let is_text_payload = self.state.text_payload.as_ref()
-                .is_some_and(|tp| tp.get_i64_value() == payload_id);
-
             // Take the buffer out to release the borrow
-            let mut buffer = self.state.payload_buffers.remove(&payload_id)
+            let buffer = self.state.payload_buffers.remove(&payload_id)
                 .ok_or_else(|| anyhow!("Missing payload buffer"))?;
 
-            if is_text_payload {
-                return self.finish_text_transfer(&mut buffer).await;
+            if let Some(text_payload) = self.state.text_payloads.remove(&payload_id) {
+                return self.finish_text_payload(text_payload, &buffer).await;
             }
 
             let inner_frame = sharing_nearby::Frame::decode(buffer.as_slice())?;
@@ -632,6 +715,12 @@ impl InboundRequest {
         info!("Processing PayloadType::File");
         let payload_id = header.id();
 
+        // The sender may not have seen our cancellation yet
+        if self.state.cancelled_payloads.contains(&payload_id) {
+            trace!("Skipping chunk of cancelled payload {payload_id}");
+            return Ok(());
+        }
+
         let file_internal = self
             .state
             .transferred_files
@@ -658,10 +747,11 @@ impl InboundRequest {
         }
 
         if !chunk.body().is_empty() {
-            let file = file_internal.file.as_ref()
-                .ok_or_else(|| anyhow!("File handle not available"))?;
-            file.write_all_at(chunk.body(), u64::try_from(current_offset).unwrap_or_default())?;
+            let sink = file_internal.sink.as_mut()
+                .ok_or_else(|| anyhow!("File sink not available"))?;
+            sink.write_at(u64::try_from(current_offset).unwrap_or_default(), chunk.body())?;
             file_internal.bytes_transferred += chunk_size_i64;
+            file_internal.chunks_transferred += 1;
 
             self.update_state(
                 |e| {
@@ -675,18 +765,77 @@ impl InboundRequest {
             // Final chunk marker - send ACK to sender before removing from tracking
             self.send_payload_received_ack(payload_id).await?;
 
-            self.state.transferred_files.remove(&payload_id);
-            if self.state.transferred_files.is_empty() {
-                info!("All files received, transfer finished");
-                self.update_state(|e| { e.state = TransferState::Finished; }, true).await;
-                // Receiver must initiate disconnect - Android waits for this
-                self.request_disconnection().await?;
+            if let Some(mut file_info) = self.state.transferred_files.remove(&payload_id) {
+                if let Some(sink) = file_info.sink.take() {
+                    sink.finish()?;
+                }
+                let path = self.finalize_file(file_info).await?;
+                if let Some(hf) = self.state.history_files.iter_mut().find(|f| f.payload_id == payload_id) {
+                    hf.path = path;
+                    hf.completed = true;
+                }
             }
+            self.check_transfer_complete().await?;
         }
 
         Ok(())
     }
 
+    /// Move a completely received file out of its staging location,
+    /// scanning it first. Infected files end up in the quarantine dir.
+    ///
+    /// Returns where the file ended up, None if it was handed to a custom sink.
+    async fn finalize_file(&mut self, mut file_info: InternalFileInfo) -> Result<Option<PathBuf>, anyhow::Error> {
+        let Some(part_url) = file_info.part_url.take() else {
+            return Ok(self.state.payload_sink.is_none().then_some(file_info.file_url));
+        };
+
+        let Some(config) = get_scan_config() else {
+            std::fs::rename(&part_url, &file_info.file_url)?;
+            return Ok(Some(file_info.file_url));
+        };
+
+        let reason = match scan_file(&config, &part_url).await {
+            Ok(ScanVerdict::Clean) => {
+                // Something may have taken the name while we were receiving
+                let dest = match (file_info.file_url.parent(), file_info.file_url.file_name()) {
+                    (Some(dir), Some(name)) if file_info.file_url.exists() => {
+                        Self::resolve_filename_conflict(dir, &name.to_string_lossy())
+                    }
+                    _ => file_info.file_url.clone(),
+                };
+                info!("Scan clean, moving to: {dest:?}");
+                std::fs::rename(&part_url, &dest)?;
+                return Ok(Some(dest));
+            }
+            Ok(ScanVerdict::Infected(signature)) => signature,
+            // Fail closed, a file we couldn't scan isn't trusted either
+            Err(e) => {
+                error!("Failed to scan {part_url:?}: {e}");
+                format!("scan failed: {e}")
+            }
+        };
+
+        let path = quarantine_file(&part_url, &file_info.name, &config.quarantine_dir)?;
+        warn!("Quarantined {} ({reason}) to {path:?}", file_info.name);
+
+        let outcome = FileOutcome {
+            payload_id: file_info.payload_id,
+            name: file_info.name.clone(),
+            kind: FileOutcomeKind::Quarantined { path: path.clone(), reason },
+        };
+        self.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.file_outcomes.push(outcome);
+                }
+            },
+            true,
+        ).await;
+
+        Ok(Some(path))
+    }
+
     /// Process a control message (error, cancel, ack).
     async fn process_control_message(
         &mut self,
@@ -699,15 +848,19 @@ impl InboundRequest {
         match control.event() {
             EventType::PayloadError => {
                 warn!("Received PAYLOAD_ERROR for payload {payload_id}");
-                // Clean up the failed transfer
-                self.state.transferred_files.remove(&payload_id);
-                self.state.payload_buffers.remove(&payload_id);
+                self.drop_payload(payload_id, FileOutcomeKind::Failed {
+                    reason: "the sender reported an error".into(),
+                }).await;
+                if self.state.state == TransferState::ReceivingFiles {
+                    self.check_transfer_complete().await?;
+                }
             }
             EventType::PayloadCanceled => {
                 info!("Received PAYLOAD_CANCELED for payload {payload_id}");
-                // Clean up the canceled transfer
-                self.state.transferred_files.remove(&payload_id);
-                self.state.payload_buffers.remove(&payload_id);
+                self.drop_payload(payload_id, FileOutcomeKind::Cancelled).await;
+                if self.state.state == TransferState::ReceivingFiles {
+                    self.check_transfer_complete().await?;
+                }
             }
             EventType::PayloadReceivedAck => {
                 debug!("Received PAYLOAD_RECEIVED_ACK for payload {payload_id} at offset {}", control.offset());
@@ -719,6 +872,56 @@ impl InboundRequest {
         Ok(())
     }
 
+    /// Stop receiving one file at the user's request, the rest of the transfer carries on.
+    async fn cancel_payload(&mut self, payload_id: i64) -> Result<(), anyhow::Error> {
+        if self.state.state != TransferState::ReceivingFiles {
+            warn!("Can't cancel payload {payload_id} in state {:?}", self.state.state);
+            return Ok(());
+        }
+        if !self.state.transferred_files.contains_key(&payload_id) {
+            warn!("Can't cancel payload {payload_id}: not a file still being received");
+            return Ok(());
+        }
+
+        info!("Cancelling payload {payload_id}");
+        self.encrypt_and_send(&control_frame(payload_id, control_message::EventType::PayloadCanceled))
+            .await?;
+        self.drop_payload(payload_id, FileOutcomeKind::Cancelled).await;
+
+        self.check_transfer_complete().await
+    }
+
+    /// Forget a payload that won't be completed, deleting what was received of it.
+    async fn drop_payload(&mut self, payload_id: i64, kind: FileOutcomeKind) {
+        self.state.cancelled_payloads.insert(payload_id);
+        self.state.payload_buffers.remove(&payload_id);
+        self.state.text_payloads.remove(&payload_id);
+
+        let Some(mut file_info) = self.state.transferred_files.remove(&payload_id) else {
+            return;
+        };
+        if let Some(sink) = file_info.sink.take() {
+            sink.abort();
+        }
+
+        // What's left of it won't come, the progress is on the files still to be received
+        let remaining = u64::try_from(file_info.total_size - file_info.bytes_transferred).unwrap_or_default();
+        let outcome = FileOutcome {
+            payload_id,
+            name: file_info.name,
+            kind,
+        };
+        self.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.total_bytes = tmd.total_bytes.saturating_sub(remaining);
+                    tmd.file_outcomes.push(outcome);
+                }
+            },
+            true,
+        ).await;
+    }
+
     /// Process a payload transfer frame.
     async fn process_payload_transfer(
         &mut self,
@@ -793,6 +996,13 @@ impl InboundRequest {
 
         let d2d_msg = DeviceToDeviceMessage::decode(&*decrypted)?;
 
+        // The peer's SAFE_TO_CLOSE_PRIOR_CHANNEL may still be on the prior socket of an upgrade
+        let next = self.state.client_seq + 1;
+        if d2d_msg.sequence_number() != next && self.upgrade.hold(d2d_msg.sequence_number(), smsg) {
+            debug!("Holding frame {} until {next} is read from the prior socket", d2d_msg.sequence_number());
+            return Ok(());
+        }
+
         let seq = self.get_client_seq_inc().await;
         if d2d_msg.sequence_number() != seq {
             return Err(anyhow!(
@@ -813,8 +1023,11 @@ impl InboundRequest {
                 self.process_payload_transfer(v1_frame).await?;
             }
             location_nearby_connections::v1_frame::FrameType::KeepAlive => {
-                trace!("Sending keepalive");
-                self.send_keepalive(true).await?;
+                // Only answer keep-alives, not the acks to ours
+                if !v1_frame.keep_alive.as_ref().is_some_and(KeepAliveFrame::ack) {
+                    trace!("Sending keepalive ack");
+                    self.send_keepalive(true).await?;
+                }
             }
             location_nearby_connections::v1_frame::FrameType::Disconnection => {
                 debug!("Received Disconnection frame");
@@ -832,21 +1045,33 @@ impl InboundRequest {
                     }
                 }
             }
-            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeRetry => {
-                debug!("Received BANDWIDTH_UPGRADE_RETRY frame (ignoring)");
+            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation => {
+                if let Some(bwu) = &v1_frame.bandwidth_upgrade_negotiation {
+                    self.process_bandwidth_upgrade(bwu).await?;
+                }
             }
-            location_nearby_connections::v1_frame::FrameType::AutoResume => {
-                debug!("Received AUTO_RESUME frame (ignoring)");
+            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeRetry => {
+                if v1_frame.bandwidth_upgrade_retry.as_ref().is_some_and(location_nearby_connections::BandwidthUpgradeRetryFrame::is_request) {
+                    self.send_bandwidth_upgrade_retry_reply().await?;
+                }
             }
             location_nearby_connections::v1_frame::FrameType::AutoReconnect => {
-                debug!("Received AUTO_RECONNECT frame (ignoring)");
+                if let Some(auto_reconnect) = &v1_frame.auto_reconnect {
+                    self.process_auto_reconnect(auto_reconnect).await?;
+                }
             }
-            location_nearby_connections::v1_frame::FrameType::AuthenticationMessage
-            | location_nearby_connections::v1_frame::FrameType::AuthenticationResult => {
-                debug!("Received authentication frame (ignoring)");
+            location_nearby_connections::v1_frame::FrameType::AutoResume => {
+                if let Some(auto_resume) = &v1_frame.auto_resume {
+                    self.process_auto_resume(auto_resume).await?;
+                }
             }
-            _ => {
-                warn!("Unhandled offline frame: {:?}", v1_frame.r#type());
+            frame_type @ (location_nearby_connections::v1_frame::FrameType::UnknownFrameType
+            | location_nearby_connections::v1_frame::FrameType::ConnectionRequest
+            | location_nearby_connections::v1_frame::FrameType::ConnectionResponse
+            | location_nearby_connections::v1_frame::FrameType::PairedKeyEncryption
+            | location_nearby_connections::v1_frame::FrameType::AuthenticationMessage
+            | location_nearby_connections::v1_frame::FrameType::AuthenticationResult) => {
+                log_skipped_frame(frame_type);
             }
         }
 
@@ -875,6 +1100,28 @@ impl InboundRequest {
             return Err(anyhow!(crate::errors::AppError::NotAnError));
         }
 
+        // Not part of the handshake, it can come at any point
+        if v1_frame.r#type() == sharing_nearby::v1_frame::FrameType::CertificateInfo {
+            if let Some(certificate_info) = &v1_frame.certificate_info {
+                debug!("Received {} public certificate(s)", certificate_info.public_certificate.len());
+                let certificate = self.state.peer_paired_key.as_ref().and_then(|frame| {
+                    certs::bound_certificate(
+                        &certificate_info.public_certificate,
+                        frame,
+                        self.state.auth_token.as_deref().unwrap_or_default(),
+                    )
+                });
+                match certificate {
+                    Some(certificate) => {
+                        self.update_state(|e| e.peer_certificate = Some(certificate), false)
+                            .await;
+                    }
+                    None => debug!("None of them is bound to this connection, ignoring them"),
+                }
+            }
+            return Ok(());
+        }
+
         match self.state.state {
             TransferState::SentConnectionResponse => {
                 debug!("Processing State::SentConnectionResponse");
@@ -917,16 +1164,32 @@ impl InboundRequest {
         &mut self,
         v1_frame: &sharing_nearby::V1Frame,
     ) -> Result<(), anyhow::Error> {
-        if v1_frame.paired_key_encryption.is_none() {
-            return Err(anyhow!("Missing required fields"));
-        }
+        let paired_key_encryption = v1_frame
+            .paired_key_encryption
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing required fields"))?;
+
+        let status = certs::verify(
+            paired_key_encryption,
+            self.state.auth_token.as_deref().unwrap_or_default(),
+        );
+        let verified = status == paired_key_result_frame::Status::Success;
+        let paired_key_encryption = paired_key_encryption.clone();
+        self.update_state(
+            |e| {
+                e.peer_verified = verified;
+                e.peer_paired_key = Some(paired_key_encryption);
+            },
+            false,
+        )
+        .await;
 
         let paired_result = sharing_nearby::Frame {
             version: Some(sharing_nearby::frame::Version::V1.into()),
             v1: Some(sharing_nearby::V1Frame {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::PairedKeyResult.into()),
                 paired_key_result: Some(sharing_nearby::PairedKeyResultFrame {
-                    status: Some(paired_key_result_frame::Status::Unable.into()),
+                    status: Some(status.into()),
                 }),
                 ..Default::default()
             }),
@@ -934,6 +1197,19 @@ impl InboundRequest {
 
         self.send_encrypted_frame(&paired_result).await?;
 
+        // For the peer to recognize us next time
+        if let Some(certificate_info) = certs::certificate_info() {
+            let frame = sharing_nearby::Frame {
+                version: Some(sharing_nearby::frame::Version::V1.into()),
+                v1: Some(sharing_nearby::V1Frame {
+                    r#type: Some(sharing_nearby::v1_frame::FrameType::CertificateInfo.into()),
+                    certificate_info: Some(certificate_info),
+                    ..Default::default()
+                }),
+            };
+            self.send_encrypted_frame(&frame).await?;
+        }
+
         Ok(())
     }
 
@@ -961,22 +1237,110 @@ impl InboundRequest {
         self.update_state(|e| e.state = TransferState::WaitingForUserConsent, false)
             .await;
 
-        if !introduction.file_metadata.is_empty() && introduction.text_metadata.is_empty() {
-            self.process_file_introduction(&introduction.file_metadata).await
-        } else if introduction.text_metadata.len() == 1 {
-            let meta = introduction.text_metadata.first()
-                .ok_or_else(|| anyhow!("Missing text_metadata"))?;
-            self.process_text_introduction(meta).await
-        } else if introduction.wifi_credentials_metadata.len() == 1 {
-            let meta = introduction.wifi_credentials_metadata.first()
-                .ok_or_else(|| anyhow!("Missing wifi_credentials_metadata"))?;
-            self.process_wifi_introduction(meta).await
-        } else {
-            self.reject_transfer(Some(
-                sharing_nearby::connection_response_frame::Status::UnsupportedAttachmentType,
-            ))
-            .await
+        let mut text_payloads = Vec::new();
+        let mut kinds = Vec::new();
+        let mut previews = Vec::new();
+
+        for meta in &introduction.text_metadata {
+            let (kind, text_payload) = match meta.r#type() {
+                text_metadata::Type::Url => {
+                    (TransferPayloadKind::Url, TextPayloadInfo::Url(meta.payload_id()))
+                }
+                text_metadata::Type::PhoneNumber
+                | text_metadata::Type::Address
+                | text_metadata::Type::Text => {
+                    (TransferPayloadKind::Text, TextPayloadInfo::Text(meta.payload_id()))
+                }
+                text_metadata::Type::Unknown => {
+                    return self.reject_transfer(Some(
+                        sharing_nearby::connection_response_frame::Status::UnsupportedAttachmentType,
+                    ))
+                    .await;
+                }
+            };
+            kinds.push(kind);
+            text_payloads.push(text_payload);
+            previews.push(meta.text_title.clone().unwrap_or_default());
+        }
+
+        for meta in &introduction.wifi_credentials_metadata {
+            kinds.push(TransferPayloadKind::WiFi);
+            text_payloads.push(TextPayloadInfo::Wifi((
+                meta.payload_id(),
+                meta.ssid().to_owned(),
+                meta.security_type(),
+            )));
+            previews.push(meta.ssid.clone().unwrap_or_default());
         }
+
+        let files_name = self.process_file_introduction(&introduction.file_metadata);
+        let total_bytes = self.state.transferred_files.values()
+            .map(|f| u64::try_from(f.total_size).unwrap_or_default())
+            .sum();
+
+        // A single text/url/wifi keeps its own kind, the payload is known once received
+        let (payload_kind, payload) = match (files_name.is_empty(), kinds.len()) {
+            (true, 0) => {
+                return self.reject_transfer(Some(
+                    sharing_nearby::connection_response_frame::Status::UnsupportedAttachmentType,
+                ))
+                .await;
+            }
+            (false, 0) => (TransferPayloadKind::Files, Some(TransferPayload::Files(files_name))),
+            (true, 1) => (kinds.remove(0), None),
+            (true, _) => (TransferPayloadKind::Mixed, Some(TransferPayload::Mixed(Vec::new()))),
+            (false, _) => (
+                TransferPayloadKind::Mixed,
+                Some(TransferPayload::Mixed(vec![TransferPayload::Files(files_name)])),
+            ),
+        };
+
+        let previews: Vec<String> = previews.into_iter().filter(|p| !p.is_empty()).collect();
+        let metadata = TransferMetadata {
+            id: self.state.id.clone(),
+            source: self.state.remote_device_info.clone(),
+            payload_kind,
+            payload_preview: (!text_payloads.is_empty()).then(|| previews.join(", ")),
+            payload,
+            files: self.state.history_files.iter().map(TransferFile::from).collect(),
+            pin_code: self.state.pin_code.clone(),
+            peer_verified: self.state.peer_verified,
+            total_bytes,
+            ack_bytes: Default::default(),
+            file_outcomes: Vec::new(),
+            rejection: None,
+        };
+
+        info!("Asking for user consent: {metadata:?}");
+        self.update_state(
+            |e| {
+                e.text_payloads = text_payloads
+                    .into_iter()
+                    .map(|tp| (tp.get_i64_value(), tp))
+                    .collect();
+                e.transfer_metadata = Some(metadata);
+            },
+            true,
+        )
+        .await;
+        self.consent_deadline = consent_timeout().map(|t| Instant::now() + t);
+        Ok(())
+    }
+
+    /// Nobody answered the consent prompt in time.
+    async fn consent_timed_out(&mut self) -> Result<(), anyhow::Error> {
+        info!("No answer to the consent prompt, timing out transfer {}", self.state.id);
+        self.update_state(
+            |e| {
+                e.state = TransferState::TimedOut;
+            },
+            true,
+        ).await;
+
+        self.reject_transfer(Some(
+            sharing_nearby::connection_response_frame::Status::TimedOut
+        )).await?;
+        Err(anyhow!(crate::errors::AppError::NotAnError))
     }
 
     /// Sanitize file name by replacing dangerous characters.
@@ -1006,7 +1370,7 @@ impl InboundRequest {
     }
 
     /// Resolve filename conflicts by appending (1), (2), etc.
-    fn resolve_filename_conflict(base_dir: &Path, file_name: &str) -> PathBuf {
+    pub(crate) fn resolve_filename_conflict(base_dir: &Path, file_name: &str) -> PathBuf {
         let safe_name = Self::sanitize_filename(file_name);
         let mut dest = base_dir.to_path_buf();
         dest.push(&safe_name);
@@ -1036,124 +1400,55 @@ impl InboundRequest {
         dest // Unreachable in practice
     }
 
-    async fn process_file_introduction(
+    /// Prepare the destination of every announced file, returns their names.
+    fn process_file_introduction(
         &mut self,
         file_metadata: &[sharing_nearby::FileMetadata],
-    ) -> Result<(), anyhow::Error> {
+    ) -> Vec<String> {
         trace!("process_introduction: handling file_metadata");
         let mut files_name = Vec::with_capacity(file_metadata.len());
-        let mut total_bytes: u64 = 0;
         let download_dir = get_download_dir();
+        // Picked once, a sink set mid-transfer only applies to the next one
+        self.state.payload_sink = get_custom_sink();
+        // Custom sinks own the data, only files written to disk are scanned
+        let scanning = get_scan_config().is_some() && self.state.payload_sink.is_none();
 
         for file in file_metadata {
             info!("File name: {}", file.name());
             let dest = Self::resolve_filename_conflict(&download_dir, file.name());
             info!("Destination: {dest:?}");
 
+            // Files waiting for a scan are kept hidden until they're known clean
+            let part_url = if scanning {
+                dest.file_name()
+                    .map(|n| dest.with_file_name(format!(".{}.kvakk-part", n.to_string_lossy())))
+            } else {
+                None
+            };
+
             let info = InternalFileInfo {
                 payload_id: file.payload_id(),
+                name: file.name().to_owned(),
                 file_url: dest,
+                part_url,
                 bytes_transferred: 0,
+                chunks_transferred: 0,
                 total_size: file.size(),
                 file: None,
+                sink: None,
             };
-            total_bytes += u64::try_from(info.total_size).unwrap_or_default();
             self.state.transferred_files.insert(file.payload_id(), info);
+            self.state.history_files.push(HistoryFile {
+                payload_id: file.payload_id(),
+                name: file.name().to_owned(),
+                size: u64::try_from(file.size()).unwrap_or_default(),
+                path: None,
+                completed: false,
+            });
             files_name.push(file.name().to_owned());
         }
 
-        let metadata = TransferMetadata {
-            id: self.state.id.clone(),
-            source: self.state.remote_device_info.clone(),
-            payload_kind: TransferPayloadKind::Files,
-            payload_preview: Default::default(),
-            payload: Some(TransferPayload::Files(files_name)),
-            pin_code: self.state.pin_code.clone(),
-            total_bytes,
-            ack_bytes: Default::default(),
-        };
-
-        info!("Asking for user consent: {metadata:?}");
-        self.update_state(|e| e.transfer_metadata = Some(metadata), true).await;
-        Ok(())
-    }
-
-    async fn process_text_introduction(
-        &mut self,
-        meta: &sharing_nearby::TextMetadata,
-    ) -> Result<(), anyhow::Error> {
-        trace!("process_introduction: handling text_metadata");
-
-        let (payload_kind, text_payload) = match meta.r#type() {
-            text_metadata::Type::Url => {
-                (TransferPayloadKind::Url, TextPayloadInfo::Url(meta.payload_id()))
-            }
-            text_metadata::Type::PhoneNumber
-            | text_metadata::Type::Address
-            | text_metadata::Type::Text => {
-                (TransferPayloadKind::Text, TextPayloadInfo::Text(meta.payload_id()))
-            }
-            text_metadata::Type::Unknown => {
-                return self.reject_transfer(Some(
-                    sharing_nearby::connection_response_frame::Status::UnsupportedAttachmentType,
-                ))
-                .await;
-            }
-        };
-
-        let metadata = TransferMetadata {
-            id: self.state.id.clone(),
-            source: self.state.remote_device_info.clone(),
-            payload_kind,
-            payload_preview: Some(meta.text_title.clone().unwrap_or_default()),
-            pin_code: self.state.pin_code.clone(),
-            payload: Default::default(),
-            total_bytes: Default::default(),
-            ack_bytes: Default::default(),
-        };
-
-        info!("Asking for user consent: {metadata:?}");
-        self.update_state(
-            |e| {
-                e.text_payload = Some(text_payload);
-                e.transfer_metadata = Some(metadata);
-            },
-            true,
-        )
-        .await;
-        Ok(())
-    }
-
-    async fn process_wifi_introduction(
-        &mut self,
-        meta: &sharing_nearby::WifiCredentialsMetadata,
-    ) -> Result<(), anyhow::Error> {
-        trace!("process_introduction: handling wifi_credentials_metadata");
-
-        let metadata = TransferMetadata {
-            id: self.state.id.clone(),
-            source: self.state.remote_device_info.clone(),
-            payload_kind: TransferPayloadKind::WiFi,
-            payload_preview: Some(meta.ssid.clone().unwrap_or_default()),
-            pin_code: self.state.pin_code.clone(),
-            payload: Default::default(),
-            total_bytes: Default::default(),
-            ack_bytes: Default::default(),
-        };
-
-        self.update_state(
-            |e| {
-                e.text_payload = Some(TextPayloadInfo::Wifi((
-                    meta.payload_id(),
-                    meta.ssid().to_owned(),
-                    meta.security_type(),
-                )));
-                e.transfer_metadata = Some(metadata);
-            },
-            true,
-        )
-        .await;
-        Ok(())
+        files_name
     }
 
     async fn disconnection(&mut self) -> Result<(), anyhow::Error> {
@@ -1186,28 +1481,7 @@ impl InboundRequest {
 
         info!("Sending PAYLOAD_RECEIVED_ACK for payload {payload_id}");
 
-        let frame = location_nearby_connections::OfflineFrame {
-            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
-            v1: Some(location_nearby_connections::V1Frame {
-                r#type: Some(
-                    location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
-                ),
-                payload_transfer: Some(PayloadTransferFrame {
-                    packet_type: Some(PacketType::Control.into()),
-                    payload_header: Some(PayloadHeader {
-                        id: Some(payload_id),
-                        r#type: Some(payload_header::PayloadType::File.into()),
-                        ..Default::default()
-                    }),
-                    control_message: Some(ControlMessage {
-                        event: Some(control_message::EventType::PayloadReceivedAck.into()),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                }),
-                ..Default::default()
-            }),
-        };
+        let frame = control_frame(payload_id, control_message::EventType::PayloadReceivedAck);
 
         let mut last_error = None;
         for attempt in 1..=MAX_RETRIES {
@@ -1236,6 +1510,259 @@ impl InboundRequest {
         Err(last_error.unwrap_or_else(|| anyhow!("Failed to send PAYLOAD_RECEIVED_ACK after {MAX_RETRIES} attempts")))
     }
 
+    /// Listen on the address the peer reached us on and tell it to connect there.
+    pub(super) async fn offer_upgrade(&mut self) -> Result<(), anyhow::Error> {
+        // Same interface as the current connection (keeping the scope of link-local IPv6),
+        // and plain IPv4 when the dual-stack listener handed us a mapped address.
+        let mut local = self.socket.local_addr()?;
+        local.set_port(0);
+        if let IpAddr::V4(ip) = local.ip().to_canonical() {
+            local = SocketAddr::new(IpAddr::V4(ip), 0);
+        }
+        let listener = TcpListener::bind(local).await?;
+        let addr = listener.local_addr()?;
+        info!("Offering a bandwidth upgrade to {addr}");
+
+        self.encrypt_and_send(&upgrade_path_available_frame(addr)).await?;
+        self.upgrade = Upgrade::Offered {
+            listener,
+            deadline: Instant::now() + BWU_HANDSHAKE_TIMEOUT,
+        };
+
+        Ok(())
+    }
+
+    async fn process_upgrade_event(
+        &mut self,
+        event: Result<UpgradeEvent, anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        match event {
+            Ok(UpgradeEvent::Connected(socket)) => {
+                // Only the peer may take the connection over, anyone else is ignored
+                let peer_ip = self.socket.peer_addr()?.ip().to_canonical();
+                match socket.peer_addr() {
+                    Ok(from) if from.ip().to_canonical() == peer_ip => {}
+                    from => {
+                        warn!("Ignoring a connection to the upgrade socket from {from:?}");
+                        return Ok(());
+                    }
+                }
+                self.upgrade = Upgrade::None;
+                if let Err(e) = self.complete_upgrade(socket).await {
+                    warn!("Bandwidth upgrade failed: {e}");
+                }
+            }
+            Ok(UpgradeEvent::PriorFrame(length_buf)) => {
+                self.state.keep_alive.last_received = Instant::now();
+                let Upgrade::Migrating { prior, .. } = &mut self.upgrade else {
+                    return Ok(());
+                };
+                let frame_data = read_frame_body(prior, length_buf).await?;
+                let smsg = SecureMessage::decode(&*frame_data)?;
+                self.decrypt_and_process_secure_message(&smsg).await?;
+                self.release_held_frame().await?;
+            }
+            Err(e) => {
+                // The transfer carries on over the current socket
+                debug!("Bandwidth upgrade socket closed: {e}");
+                if self.upgrade.is_migrating() {
+                    self.upgrade.prior_done();
+                    self.release_held_frame().await?;
+                } else {
+                    self.upgrade = Upgrade::None;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Process the frame held back for the one still on the prior socket, once it's its turn.
+    async fn release_held_frame(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(smsg) = self.upgrade.take_held(self.state.client_seq + 1) {
+            Box::pin(self.decrypt_and_process_secure_message(&smsg)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The responder connected, check its introduction and move over.
+    async fn complete_upgrade(&mut self, mut socket: TcpStream) -> Result<(), anyhow::Error> {
+        let frame_data = tokio::time::timeout(BWU_HANDSHAKE_TIMEOUT, read_frame(&mut socket)).await??;
+        let frame = OfflineFrame::decode(&*frame_data)?;
+        if bwu_event(&frame) != Some(EventType::ClientIntroduction) {
+            return Err(anyhow!("expected a CLIENT_INTRODUCTION"));
+        }
+
+        let endpoint_id = frame
+            .v1
+            .as_ref()
+            .and_then(|v1| v1.bandwidth_upgrade_negotiation.as_ref())
+            .and_then(|bwu| bwu.client_introduction.as_ref())
+            .map(|ci| ci.endpoint_id().to_owned());
+        if endpoint_id.is_none() || endpoint_id != self.state.remote_endpoint_id {
+            return Err(anyhow!("introduction from unexpected endpoint {endpoint_id:?}"));
+        }
+
+        write_frame(&mut socket, &client_introduction_ack_frame().encode_to_vec()).await?;
+        self.migrate_to(socket).await
+    }
+
+    /// Last write on the current socket, from now on everything goes to the new one.
+    async fn migrate_to(&mut self, socket: TcpStream) -> Result<(), anyhow::Error> {
+        if let Err(e) = socket.set_nodelay(true) {
+            warn!("Failed to set TCP_NODELAY: {e}");
+        }
+
+        self.encrypt_and_send(&bwu_event_frame(EventType::LastWriteToPriorChannel)).await?;
+        info!("Connection upgraded to {:?}", socket.peer_addr());
+
+        let prior = std::mem::replace(&mut self.socket, socket);
+        self.upgrade = Upgrade::migrating(prior, std::mem::take(&mut self.length_reader));
+        self.update_state(|e| { e.upgraded = true; }, false).await;
+
+        Ok(())
+    }
+
+    async fn process_bandwidth_upgrade(
+        &mut self,
+        bwu: &location_nearby_connections::BandwidthUpgradeNegotiationFrame,
+    ) -> Result<(), anyhow::Error> {
+        match bwu.event_type() {
+            EventType::LastWriteToPriorChannel => {
+                debug!("Peer is done with the prior socket");
+                let frame = self.encrypt_frame(&bwu_event_frame(EventType::SafeToClosePriorChannel)).await?;
+                if let Upgrade::Migrating { prior, .. } = &mut self.upgrade
+                    && let Err(e) = write_frame(prior, &frame).await
+                {
+                    // The peer may already have closed it
+                    debug!("Failed to send SAFE_TO_CLOSE_PRIOR_CHANNEL: {e}");
+                }
+                self.upgrade.prior_drained();
+            }
+            EventType::SafeToClosePriorChannel => {
+                debug!("Peer closed the prior socket");
+                self.upgrade.prior_done();
+            }
+            EventType::UpgradeFailure => {
+                warn!("Peer couldn't upgrade the connection");
+                self.upgrade = Upgrade::None;
+            }
+            EventType::UpgradePathAvailable => {
+                // We're the side offering upgrades
+                let medium = bwu.upgrade_path_info.as_ref().map_or(Medium::UnknownMedium, UpgradePathInfo::medium);
+                debug!("Declining an upgrade to {medium:?}");
+                self.encrypt_and_send(&upgrade_failure_frame(medium)).await?;
+            }
+            EventType::ClientIntroduction | EventType::ClientIntroductionAck | EventType::UnknownEventType => {
+                warn!("Unexpected bandwidth upgrade event: {:?}", bwu.event_type());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The sender reconnected after a dropped connection, take its transfer over.
+    async fn process_auto_reconnect(&mut self, frame: &AutoReconnectFrame) -> Result<(), anyhow::Error> {
+        if frame.event_type() != auto_reconnect_frame::EventType::ClientIntroduction {
+            warn!("Unexpected auto-reconnect event: {:?}", frame.event_type());
+            return Ok(());
+        }
+
+        // The sender proved owning its certificate again with its paired key encryption
+        let endpoint_id = frame.endpoint_id();
+        if self.state.state != TransferState::SentPairedKeyResult
+            || self.state.remote_endpoint_id.as_deref() != Some(endpoint_id)
+        {
+            return Err(anyhow!(
+                "Unexpected auto-reconnect introduction from {endpoint_id} in state {:?}",
+                self.state.state
+            ));
+        }
+
+        let last_endpoint_id = Some(frame.last_endpoint_id())
+            .filter(|id| !id.is_empty())
+            .unwrap_or(endpoint_id);
+        let peer_ip = self.socket.peer_addr()?.ip().to_canonical();
+        let proof = self.state.peer_paired_key.clone().unwrap_or_default();
+        let auth_token = self.state.auth_token.clone().unwrap_or_default();
+        let claimed = resume::claim(last_endpoint_id, peer_ip, &proof, &auth_token).await;
+        let Some(transfer) = claimed else {
+            info!("No interrupted transfer from {last_endpoint_id} to resume");
+            self.disconnection().await?;
+            return Err(anyhow!(crate::errors::AppError::NotAnError));
+        };
+
+        info!("Resuming transfer {} from {last_endpoint_id}", transfer.id);
+        self.registration =
+            resume::register(endpoint_id, peer_ip, transfer.peer_certificate.as_ref());
+        self.update_state(
+            |e| {
+                e.resume(transfer);
+                e.state = TransferState::ReceivingFiles;
+            },
+            true,
+        )
+        .await;
+
+        self.encrypt_and_send(&reconnect_frame(
+            endpoint_id,
+            auto_reconnect_frame::EventType::ClientIntroductionAck,
+        ))
+        .await
+    }
+
+    /// Tell the sender where to continue a file of a resumed transfer.
+    async fn process_auto_resume(&mut self, frame: &AutoResumeFrame) -> Result<(), anyhow::Error> {
+        if frame.event_type() != auto_resume_frame::EventType::PayloadResumeTransferStart
+            || !self.state.resuming
+        {
+            warn!("Unexpected auto-resume event: {:?}", frame.event_type());
+            return Ok(());
+        }
+
+        let payload_id = frame.pending_payload_id();
+        if let Some(file) = self.state.transferred_files.get(&payload_id) {
+            let next_chunk_index = file.chunks_transferred;
+            info!(
+                "Resuming payload {payload_id} at chunk {next_chunk_index} ({} bytes received)",
+                file.bytes_transferred
+            );
+            self.encrypt_and_send(&resume_frame(
+                auto_resume_frame::EventType::PayloadResumeTransferAck,
+                payload_id,
+                next_chunk_index,
+            ))
+            .await
+        } else if self.state.history_files.iter().any(|f| f.payload_id == payload_id && f.completed) {
+            // Our ack was lost with the previous connection
+            self.send_payload_received_ack(payload_id).await
+        } else {
+            warn!("Asked to resume unknown payload {payload_id}");
+            Ok(())
+        }
+    }
+
+    /// We don't upgrade to any other medium, answer with an empty list.
+    async fn send_bandwidth_upgrade_retry_reply(&mut self) -> Result<(), anyhow::Error> {
+        debug!("Answering BANDWIDTH_UPGRADE_RETRY");
+        let frame = location_nearby_connections::OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(location_nearby_connections::V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeRetry.into(),
+                ),
+                bandwidth_upgrade_retry: Some(location_nearby_connections::BandwidthUpgradeRetryFrame {
+                    supported_medium: Vec::new(),
+                    is_request: Some(false),
+                }),
+                ..Default::default()
+            }),
+        };
+
+        self.encrypt_and_send(&frame).await
+    }
+
     /// Send disconnect acknowledgment (sends ack_safe_to_disconnect: true)
     async fn send_disconnect_ack(&mut self) -> Result<(), anyhow::Error> {
         debug!("Sending ack_safe_to_disconnect");
@@ -1286,15 +1813,17 @@ impl InboundRequest {
     }
 
     async fn accept_transfer(&mut self) -> Result<(), anyhow::Error> {
-        let ids: Vec<i64> = self.state.transferred_files.keys().copied().collect();
-
-        for id in ids {
-            let mfi = self.state.transferred_files.get_mut(&id)
-                .ok_or_else(|| anyhow!("Missing transferred_file entry"))?;
-
-            let file = File::create(&mfi.file_url)?;
-            info!("Created file: {:?}", &file);
-            mfi.file = Some(file);
+        let sink = self.state.payload_sink.clone().unwrap_or_else(|| Arc::new(FsSink));
+
+        for mfi in self.state.transferred_files.values_mut() {
+            let incoming = IncomingFile {
+                transfer_id: self.state.id.clone(),
+                payload_id: mfi.payload_id,
+                name: mfi.name.clone(),
+                path: mfi.part_url.clone().unwrap_or_else(|| mfi.file_url.clone()),
+                total_size: mfi.total_size,
+            };
+            mfi.sink = Some(sink.create(&incoming)?);
         }
 
         let frame = sharing_nearby::Frame {
@@ -1317,6 +1846,11 @@ impl InboundRequest {
             true,
         )
         .await;
+        if let Some(endpoint_id) = &self.state.remote_endpoint_id {
+            let peer_ip = self.socket.peer_addr()?.ip().to_canonical();
+            self.registration =
+                resume::register(endpoint_id, peer_ip, self.state.peer_certificate.as_ref());
+        }
 
         Ok(())
     }
@@ -1415,6 +1949,7 @@ impl InboundRequest {
                 e.send_hmac_key = Some(server_hmac_key);
                 e.pin_code = Some(to_four_digit_string(&auth_string));
                 e.encryption_done = true;
+                e.auth_token = Some(auth_string);
             },
             false,
         )
@@ -1510,6 +2045,11 @@ impl InboundRequest {
     }
 
     async fn encrypt_and_send(&mut self, frame: &OfflineFrame) -> Result<(), anyhow::Error> {
+        let data = self.encrypt_frame(frame).await?;
+        self.send_frame(data).await
+    }
+
+    async fn encrypt_frame(&mut self, frame: &OfflineFrame) -> Result<Vec<u8>, anyhow::Error> {
         let d2d_msg = DeviceToDeviceMessage {
             sequence_number: Some(self.get_server_seq_inc().await),
             message: Some(frame.encode_to_vec()),
@@ -1554,9 +2094,7 @@ impl InboundRequest {
             signature: result.into_bytes().to_vec(),
         };
 
-        self.send_frame(smsg.encode_to_vec()).await?;
-
-        Ok(())
+        Ok(smsg.encode_to_vec())
     }
 
     async fn send_keepalive(&mut self, ack: bool) -> Result<(), anyhow::Error> {
@@ -1569,6 +2107,7 @@ impl InboundRequest {
             }),
         };
 
+        self.state.keep_alive.last_sent = Instant::now();
         if self.state.encryption_done {
             self.encrypt_and_send(&ack_frame).await
         } else {
@@ -1626,11 +2165,11 @@ impl InboundRequest {
         trace!("Sending msg into the channel");
         drop(self.sender.send(ChannelMessage {
             id: self.state.id.clone(),
-            msg: channel::Message::Client(MessageClient {
+            msg: channel::Message::Client(Box::new(MessageClient {
                 kind: TransferKind::Inbound,
                 state: Some(self.state.state.clone()),
                 metadata: self.state.transfer_metadata.clone(),
-            }),
+            })),
         }));
         // Add a small sleep timer to allow the Tokio runtime to have
         // some spare time to process channel's message. Otherwise it
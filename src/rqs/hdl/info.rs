@@ -3,7 +3,10 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
+use crate::history::HistoryFile;
+use crate::sharing_nearby::connection_response_frame;
 use crate::sharing_nearby::wifi_credentials_metadata::SecurityType;
+use crate::sink::SinkFile;
 use crate::utils::RemoteDeviceInfo;
 
 use super::TextPayloadType;
@@ -11,10 +14,19 @@ use super::TextPayloadType;
 #[derive(Debug)]
 pub struct InternalFileInfo {
     pub payload_id: i64,
+    /// File name as announced in the introduction
+    pub name: String,
     pub file_url: PathBuf,
+    /// Where the data is written while it still has to be scanned
+    pub part_url: Option<PathBuf>,
     pub bytes_transferred: i64,
+    /// Chunks with data, the unit auto-resume counts in
+    pub chunks_transferred: i32,
     pub total_size: i64,
+    /// Source file, for outbound transfers
     pub file: Option<File>,
+    /// Destination, for inbound transfers (created once accepted)
+    pub sink: Option<Box<dyn SinkFile>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,14 +39,18 @@ pub enum TransferPayload {
         password: String,
         security_type: SecurityType,
     },
+    /// Introductions combining files, texts and/or Wi-Fi networks.
+    /// Text items are appended as they are received.
+    Mixed(Vec<TransferPayload>),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TransferPayloadKind {
     Files,
     Text,
     Url,
     WiFi,
+    Mixed,
 }
 
 impl TransferPayload {
@@ -47,7 +63,91 @@ impl TransferPayload {
                 password: _,
                 security_type: _,
             } => Some(TextPayloadType::Wifi),
-            TransferPayload::Files(_items) => None,
+            TransferPayload::Files(_) | TransferPayload::Mixed(_) => None,
+        }
+    }
+
+    pub fn has_files(&self) -> bool {
+        match self {
+            TransferPayload::Files(_) => true,
+            TransferPayload::Mixed(items) => items.iter().any(Self::has_files),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FileOutcomeKind {
+    /// Flagged by the scanner (or couldn't be scanned) and moved to the quarantine dir
+    Quarantined { path: PathBuf, reason: String },
+    /// Cancelled before it was complete, what was received of it is deleted
+    Cancelled,
+    /// The other side reported an error for it, what was received of it is deleted
+    Failed { reason: String },
+}
+
+/// Per-file result, for the files that didn't simply end up in the download dir.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileOutcome {
+    pub payload_id: i64,
+    pub name: String,
+    pub kind: FileOutcomeKind,
+}
+
+/// Why the receiver turned an outbound transfer down.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RejectionReason {
+    Declined,
+    NotEnoughSpace,
+    UnsupportedAttachmentType,
+    /// Nobody answered the consent prompt in time
+    TimedOut,
+    Unknown,
+}
+
+impl RejectionReason {
+    pub fn from_status(status: connection_response_frame::Status) -> Self {
+        match status {
+            connection_response_frame::Status::Reject => Self::Declined,
+            connection_response_frame::Status::NotEnoughSpace => Self::NotEnoughSpace,
+            connection_response_frame::Status::UnsupportedAttachmentType => {
+                Self::UnsupportedAttachmentType
+            }
+            connection_response_frame::Status::TimedOut => Self::TimedOut,
+            connection_response_frame::Status::Accept | connection_response_frame::Status::Unknown => {
+                Self::Unknown
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Declined => "Declined by the receiver",
+            Self::NotEnoughSpace => "Not enough space on the receiver",
+            Self::UnsupportedAttachmentType => "The receiver can't accept this type of content",
+            Self::TimedOut => "The receiver didn't answer in time",
+            Self::Unknown => "Rejected by the receiver for an unknown reason",
+        })
+    }
+}
+
+/// A file of the transfer, as announced in the introduction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferFile {
+    /// What `TransferAction::CancelPayload` takes
+    pub payload_id: i64,
+    pub name: String,
+    pub size: u64,
+}
+
+impl From<&HistoryFile> for TransferFile {
+    fn from(file: &HistoryFile) -> Self {
+        Self {
+            payload_id: file.payload_id,
+            name: file.name.clone(),
+            size: file.size,
         }
     }
 }
@@ -57,6 +157,8 @@ pub struct TransferMetadata {
     pub id: String,
     pub source: Option<RemoteDeviceInfo>,
     pub pin_code: Option<String>,
+    /// The peer proved it owns a certificate we kept from a previous transfer
+    pub peer_verified: bool,
 
     // This exists since the client may want to know
     // the type before receiving the complete payload
@@ -64,7 +166,11 @@ pub struct TransferMetadata {
     /// Only exists for Text data
     pub payload_preview: Option<String>,
     pub payload: Option<TransferPayload>,
+    pub files: Vec<TransferFile>,
 
     pub total_bytes: u64,
     pub ack_bytes: u64,
+    pub file_outcomes: Vec<FileOutcome>,
+    /// Set when the receiver turned the transfer down (outbound only)
+    pub rejection: Option<RejectionReason>,
 }
@@ -1,72 +1,51 @@
-use std::net::Ipv4Addr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
 use tokio::sync::broadcast::Receiver;
 use tokio::time::interval;
 use tokio_util::sync::CancellationToken;
 
-use crate::utils::{gen_mdns_endpoint_info, gen_mdns_name, DeviceType};
-use crate::DEVICE_NAME;
-
-/// Check if interface name belongs to a virtual/tunnel network
-fn is_virtual_interface(name: &str) -> bool {
-    let name_lower = name.to_lowercase();
-    name_lower.starts_with("docker")
-        || name_lower.starts_with("br-")
-        || name_lower.starts_with("veth")
-        || name_lower.starts_with("virbr")
-        || name_lower.starts_with("tailscale")
-        || name_lower.starts_with("tun")
-        || name_lower.starts_with("tap")
+use crate::interfaces::{AddressWatcher, local_network_ips};
+use crate::utils::{gen_mdns_endpoint_info, gen_mdns_name, get_device_type};
+use crate::{DEVICE_NAME, VISIBILITY};
+
+/// Whether the service is advertised over mDNS. Sending works in every mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum Visibility {
+    /// Not advertised, nearby devices can't find us
+    Hidden,
+    /// Advertised until then, hidden afterwards
+    Until(SystemTime),
+    #[default]
+    Always,
 }
 
-/// Find all usable IPv4 addresses (excluding loopback/link-local/virtual networks)
-fn get_local_network_ips() -> Vec<Ipv4Addr> {
-    let mut ips = Vec::new();
+impl Visibility {
+    /// Visible for the next `duration`.
+    pub fn for_duration(duration: Duration) -> Self {
+        Self::Until(SystemTime::now() + duration)
+    }
 
-    if let Ok(interfaces) = get_if_addrs::get_if_addrs() {
-        for iface in interfaces {
-            // Skip loopback
-            if iface.is_loopback() {
-                continue;
-            }
-            // Skip virtual/tunnel interfaces by name
-            if is_virtual_interface(&iface.name) {
-                continue;
-            }
-            // Skip non-IPv4
-            let ip = match iface.ip() {
-                std::net::IpAddr::V4(ip) => ip,
-                _ => continue,
-            };
-            // Skip link-local (169.254.x.x)
-            if ip.octets()[0] == 169 && ip.octets()[1] == 254 {
-                continue;
-            }
-            // Skip virtualization networks (172.16.x.x - 172.31.x.x)
-            // Covers Docker, WSL2, Hyper-V, VMware, VirtualBox, etc.
-            if ip.octets()[0] == 172 && (16..=31).contains(&ip.octets()[1]) {
-                continue;
-            }
-            // Skip Tailscale CGNAT range (100.64.0.0/10 = 100.64.x.x - 100.127.x.x)
-            if ip.octets()[0] == 100 && (64..=127).contains(&ip.octets()[1]) {
-                continue;
-            }
-            ips.push(ip);
+    pub fn is_visible(&self) -> bool {
+        match self {
+            Self::Hidden => false,
+            Self::Until(until) => SystemTime::now() < *until,
+            Self::Always => true,
         }
     }
-
-    ips
 }
 
 const INNER_NAME: &str = "MDnsServer";
+/// How long to wait for the daemon to confirm the goodbye packets went out
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct MDnsServer {
     daemon: ServiceDaemon,
-    service_info: ServiceInfo,
+    endpoint_id: [u8; 4],
+    service_port: u16,
+    /// The service, while registered
+    service_info: Option<ServiceInfo>,
     ble_receiver: Receiver<()>,
-    registered: bool,
 }
 
 impl MDnsServer {
@@ -75,32 +54,49 @@ impl MDnsServer {
         service_port: u16,
         ble_receiver: Receiver<()>,
     ) -> Result<Self, anyhow::Error> {
-        let service_info = Self::build_service(endpoint_id, service_port, DeviceType::Laptop)?;
-
         Ok(Self {
             daemon: ServiceDaemon::new()?,
-            service_info,
+            endpoint_id,
+            service_port,
+            service_info: None,
             ble_receiver,
-            registered: false,
         })
     }
 
     pub async fn run(&mut self, ctk: CancellationToken) -> Result<(), anyhow::Error> {
-        let monitor = self.daemon.monitor()?;
-        let ble_receiver = &mut self.ble_receiver;
+        let result = self.serve(ctk).await;
 
-        // Register the mDNS service
-        self.daemon.register(self.service_info.clone())?;
-        self.registered = true;
-        info!("{INNER_NAME}: service registered and running");
+        // Unregister the mDNS service - we're shutting down
+        self.unregister_service().await;
+
+        result
+    }
+
+    async fn serve(&mut self, ctk: CancellationToken) -> Result<(), anyhow::Error> {
+        let monitor = self.daemon.monitor()?;
+        let mut visibility = VISIBILITY.subscribe();
 
         // Periodic re-announcement interval (every 5 seconds for first 30 seconds, then stop)
         // This helps Android devices discover us even if they started looking before we registered
         let mut reannounce_interval = interval(Duration::from_secs(5));
         let mut reannounce_count = 0u8;
         const MAX_REANNOUNCEMENTS: u8 = 6; // 30 seconds of re-announcements
+        let mut addresses = AddressWatcher::new();
+
+        // Register the mDNS service, unless hidden
+        if visibility.borrow_and_update().is_visible() {
+            self.register_service()?;
+        } else {
+            info!("{INNER_NAME}: hidden, not registering the service");
+        }
 
         loop {
+            let current = *visibility.borrow();
+            let expires_in = match current {
+                Visibility::Until(until) => until.duration_since(SystemTime::now()).unwrap_or_default(),
+                _ => Duration::MAX,
+            };
+
             tokio::select! {
                 _ = ctk.cancelled() => {
                     info!("{INNER_NAME}: tracker cancelled, breaking");
@@ -112,39 +108,105 @@ impl MDnsServer {
                         Err(err) => return Err(err.into()),
                     }
                 },
-                _ = ble_receiver.recv() => {
+                r = visibility.changed() => {
+                    if r.is_err() {
+                        continue;
+                    }
+                    let visible = visibility.borrow_and_update().is_visible();
+                    info!("{INNER_NAME}: visibility changed to {:?}", *visibility.borrow());
+                    if self.apply_visibility(visible).await? {
+                        reannounce_count = 0;
+                        reannounce_interval.reset();
+                    }
+                },
+                _ = tokio::time::sleep(expires_in), if matches!(current, Visibility::Until(_)) => {
+                    info!("{INNER_NAME}: temporary visibility expired, hiding");
+                    // Picked up by the visibility branch, clients subscribed to it see it too
+                    VISIBILITY.send_replace(Visibility::Hidden);
+                },
+                _ = self.ble_receiver.recv(), if self.service_info.is_some() => {
                     debug!("{INNER_NAME}: ble_receiver: got event, re-announcing");
                     // Android can sometimes not see the mDNS service if the service
                     // was running BEFORE Android started the Discovery phase for QuickShare.
                     // So resend a broadcast if there's an Android device sending.
-                    self.daemon.register(self.service_info.clone())?;
+                    self.reannounce()?;
                 },
-                _ = reannounce_interval.tick(), if reannounce_count < MAX_REANNOUNCEMENTS => {
+                _ = reannounce_interval.tick(), if self.service_info.is_some() && reannounce_count < MAX_REANNOUNCEMENTS => {
                     reannounce_count += 1;
                     debug!("{INNER_NAME}: periodic re-announcement {reannounce_count}/{MAX_REANNOUNCEMENTS}");
-                    self.daemon.register(self.service_info.clone())?;
+                    self.reannounce()?;
+                },
+                ips = addresses.changed() => {
+                    // Also when nothing was registered for lack of an address
+                    if !visibility.borrow().is_visible() {
+                        continue;
+                    }
+                    info!("{INNER_NAME}: addresses changed to {ips:?}, re-registering");
+                    self.reregister_service().await?;
+                    // Announce the new addresses as eagerly as at startup
+                    reannounce_count = 0;
+                    reannounce_interval.reset();
                 },
             }
         }
 
-        // Unregister the mDNS service - we're shutting down
-        self.unregister_service();
+        Ok(())
+    }
+
+    /// Build the service again (picking up the current name and addresses) and register it.
+    /// Nothing is published while no address passes the interface filter.
+    fn register_service(&mut self) -> Result<(), anyhow::Error> {
+        let Some(service_info) = Self::build_service(self.endpoint_id, self.service_port)? else {
+            warn!("{INNER_NAME}: no usable address, not registering the service until there is one");
+            return Ok(());
+        };
+        self.daemon.register(service_info.clone())?;
+        self.service_info = Some(service_info);
+        info!("{INNER_NAME}: service registered and running");
 
         Ok(())
     }
 
-    /// Unregister the mDNS service and wait for confirmation
-    fn unregister_service(&mut self) {
-        if !self.registered {
-            return;
+    /// Register the service again, picking up the new addresses.
+    async fn reregister_service(&mut self) -> Result<(), anyhow::Error> {
+        self.unregister_service().await;
+        self.register_service()
+    }
+
+    /// Register or unregister the service, returns whether it was just registered.
+    async fn apply_visibility(&mut self, visible: bool) -> Result<bool, anyhow::Error> {
+        if !visible {
+            self.unregister_service().await;
+            return Ok(false);
+        }
+        if self.service_info.is_some() {
+            return Ok(false);
+        }
+        self.register_service()?;
+
+        Ok(true)
+    }
+
+    fn reannounce(&self) -> Result<(), anyhow::Error> {
+        if let Some(service_info) = &self.service_info {
+            self.daemon.register(service_info.clone())?;
         }
-        self.registered = false;
 
-        match self.daemon.unregister(self.service_info.get_fullname()) {
+        Ok(())
+    }
+
+    /// Unregister the mDNS service and wait (at most `UNREGISTER_TIMEOUT`) for confirmation
+    async fn unregister_service(&mut self) {
+        let Some(service_info) = self.service_info.take() else {
+            return;
+        };
+
+        match self.daemon.unregister(service_info.get_fullname()) {
             Ok(receiver) => {
-                // Wait for unregister confirmation (with timeout via recv)
-                if let Ok(event) = receiver.recv() {
-                    info!("{INNER_NAME}: service unregistered: {:?}", &event);
+                match tokio::time::timeout(UNREGISTER_TIMEOUT, receiver.recv_async()).await {
+                    Ok(Ok(event)) => info!("{INNER_NAME}: service unregistered: {:?}", &event),
+                    Ok(Err(e)) => warn!("{INNER_NAME}: no unregister confirmation: {e}"),
+                    Err(_) => warn!("{INNER_NAME}: unregister not confirmed in time"),
                 }
             }
             Err(e) => {
@@ -156,8 +218,7 @@ impl MDnsServer {
     fn build_service(
         endpoint_id: [u8; 4],
         service_port: u16,
-        device_type: DeviceType,
-    ) -> Result<ServiceInfo, anyhow::Error> {
+    ) -> Result<Option<ServiceInfo>, anyhow::Error> {
         let name = gen_mdns_name(endpoint_id);
         let hostname = format!("{name}.local.");
         let device_name = DEVICE_NAME
@@ -165,23 +226,23 @@ impl MDnsServer {
             .map_err(|e| anyhow::anyhow!("Failed to read device name: {e}"))?
             .clone();
 
-        // Find all usable IPv4 addresses (local network + Tailscale)
-        let local_ips = get_local_network_ips();
+        // Find all usable addresses, A and AAAA records are published for them
+        let local_ips = local_network_ips();
+        if local_ips.is_empty() {
+            return Ok(None);
+        }
         info!("Broadcasting with: device_name={device_name}, host_name={hostname}, ips={local_ips:?}");
 
-        let endpoint_info = gen_mdns_endpoint_info(device_type as u8, &device_name);
+        let visible = VISIBILITY.borrow().is_visible();
+        let endpoint_info = gen_mdns_endpoint_info(get_device_type() as u8, &device_name, visible);
         let properties = [("n", endpoint_info)];
 
-        // Pass IPs as comma-separated string, or empty for auto-detection
-        let ip_str = if local_ips.is_empty() {
-            String::new()
-        } else {
-            local_ips
-                .iter()
-                .map(std::string::ToString::to_string)
-                .collect::<Vec<_>>()
-                .join(",")
-        };
+        // Pass IPs as comma-separated string
+        let ip_str = local_ips
+            .iter()
+            .map(std::string::ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
 
         let mut si = ServiceInfo::new(
             "_FC9F5ED42C8A._tcp.local.",
@@ -192,22 +253,22 @@ impl MDnsServer {
             &properties[..],
         )?;
 
-        // If no specific IPs were set, enable auto-detection but limit to IPv4
-        if local_ips.is_empty() {
-            si = si.enable_addr_auto();
-        }
-
-        // Only broadcast on IPv4 interfaces
-        si.set_interfaces(vec![IfKind::IPv4]);
+        // Broadcast on both IPv4 and IPv6 interfaces
+        si.set_interfaces(vec![IfKind::IPv4, IfKind::IPv6]);
 
-        Ok(si)
+        Ok(Some(si))
     }
 }
 
 impl Drop for MDnsServer {
     fn drop(&mut self) {
         // Ensure service is unregistered when MDnsServer is dropped
-        // This sends a "goodbye" packet so other devices know we're gone
-        self.unregister_service();
+        // This sends a "goodbye" packet so other devices know we're gone,
+        // without waiting for the confirmation (run() does when shutting down cleanly)
+        if let Some(service_info) = self.service_info.take()
+            && let Err(e) = self.daemon.unregister(service_info.get_fullname())
+        {
+            warn!("{INNER_NAME}: failed to unregister service: {e}");
+        }
     }
 }
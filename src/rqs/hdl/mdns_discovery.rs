@@ -1,50 +1,77 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::{SocketAddr, SocketAddrV6};
 
 use base64::Engine;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
-use mdns_sd::{ServiceDaemon, ServiceEvent};
-use tokio::net::TcpStream;
+use mdns_sd::{Receiver, ScopedIp, ServiceDaemon, ServiceEvent};
 use tokio::sync::broadcast;
 use tokio_util::sync::CancellationToken;
 
 
-use crate::utils::{is_not_self_ip, parse_mdns_endpoint_info};
+use crate::interfaces::AddressWatcher;
+use crate::peers::{self, PEER_REFRESH_INTERVAL, PEER_TTL};
+use crate::utils::{get_endpoint_id, parse_mdns_endpoint_info};
 use crate::DeviceType;
 
 /// Expected Service ID bytes in Quick Share mDNS names (positions 5-7)
 const SERVICE_ID: [u8; 3] = [0xFC, 0x9F, 0x5E];
 
-/// Validate that a service name contains the correct Quick Share Service ID.
+/// The endpoint id of a service, if its name contains the correct Quick Share Service ID.
 /// Service name is base64url encoded, 10 bytes:
 /// [0]: PCP marker (0x23)
 /// [1-4]: endpoint ID (random)
 /// [5-7]: Service ID (must be 0xFC, 0x9F, 0x5E)
 /// [8-9]: padding (0x00, 0x00)
-fn is_valid_quickshare_service(fullname: &str) -> bool {
+fn service_endpoint_id(fullname: &str) -> Option<String> {
     // Extract service name (first component before the first '.')
-    let service_name = match fullname.split('.').next() {
-        Some(name) => name,
-        None => return false,
-    };
+    let service_name = fullname.split('.').next()?;
 
     // Decode from base64url
-    let decoded = match URL_SAFE_NO_PAD.decode(service_name) {
-        Ok(bytes) => bytes,
-        Err(_) => return false,
-    };
+    let decoded = URL_SAFE_NO_PAD.decode(service_name).ok()?;
 
     // Must be at least 8 bytes to contain PCP marker + endpoint ID + service ID
     if decoded.len() < 8 {
-        return false;
+        return None;
     }
 
-    // Check PCP marker at byte 0
-    if decoded[0] != 0x23 {
-        return false;
+    // Check PCP marker at byte 0 and Service ID at bytes 5-7
+    if decoded[0] != 0x23 || decoded[5..8] != SERVICE_ID {
+        return None;
     }
 
-    // Check Service ID at bytes 5-7
-    decoded[5..8] == SERVICE_ID
+    Some(String::from_utf8_lossy(&decoded[1..5]).to_string())
+}
+
+/// Where to reach a resolved service, best first:
+/// IPv4, then routable IPv6, then link-local IPv6 (with the scope id it was seen on).
+fn service_addrs(addresses: &HashSet<ScopedIp>, port: u16) -> Vec<SocketAddr> {
+    let rank = |ip: &ScopedIp| match ip {
+        ScopedIp::V4(_) => 0,
+        ScopedIp::V6(v6) if v6.addr().is_unicast_link_local() => 2,
+        _ => 1,
+    };
+
+    let mut candidates: Vec<&ScopedIp> = addresses.iter().collect();
+    // Then by address, so the preferred one (and the endpoint id) doesn't change between resolutions
+    candidates.sort_by_key(|ip| (rank(ip), ip.to_ip_addr()));
+
+    candidates
+        .into_iter()
+        .map(|ip| match ip {
+            ScopedIp::V6(v6) if v6.addr().is_unicast_link_local() => {
+                SocketAddr::V6(SocketAddrV6::new(*v6.addr(), port, 0, v6.scope_id().index))
+            }
+            ip => SocketAddr::new(ip.to_ip_addr(), port),
+        })
+        .collect()
+}
+
+/// The address in its text form, with the scope id of link-local IPv6 ones (`fe80::1%3`).
+fn scoped_ip(addr: SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(v6) if v6.scope_id() != 0 => format!("{}%{}", v6.ip(), v6.scope_id()),
+        addr => addr.ip().to_string(),
+    }
 }
 
 #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
@@ -54,10 +81,27 @@ pub struct EndpointInfo {
     pub name: Option<String>,
     pub ip: Option<String>,
     pub port: Option<String>,
+    /// Every address the service resolved to (`ip:port`), best first. `ip` is the first one.
+    #[serde(default)]
+    pub addresses: Vec<String>,
     pub rtype: Option<DeviceType>,
+    /// Advertised in a mode that isn't visible to everyone
+    pub hidden: Option<bool>,
     pub present: Option<bool>,
 }
 
+impl EndpointInfo {
+    /// `ip:port`, bracketed for IPv6, what `SendInfo::addr` takes.
+    pub fn socket_addr(&self) -> Option<String> {
+        let (ip, port) = (self.ip.as_ref()?, self.port.as_ref()?);
+        Some(if ip.contains(':') {
+            format!("[{ip}]:{port}")
+        } else {
+            format!("{ip}:{port}")
+        })
+    }
+}
+
 pub struct MDnsDiscovery {
     daemon: ServiceDaemon,
     sender: broadcast::Sender<EndpointInfo>,
@@ -70,14 +114,22 @@ impl MDnsDiscovery {
         Ok(Self { daemon, sender })
     }
 
+    fn restart_browse(&self, service_type: &str) -> Result<Receiver<ServiceEvent>, anyhow::Error> {
+        if let Err(e) = self.daemon.stop_browse(service_type) {
+            warn!("MDnsDiscovery: failed to stop browsing: {e}");
+        }
+
+        Ok(self.daemon.browse(service_type)?)
+    }
+
     pub async fn run(self, ctk: CancellationToken) -> Result<(), anyhow::Error> {
         info!("MDnsDiscovery: service starting");
 
         let service_type = "_FC9F5ED42C8A._tcp.local.";
-        let receiver = self.daemon.browse(service_type)?;
-
-        // Map with fullname as key and EndpointInfo as value
-        let mut cache: HashMap<String, EndpointInfo> = HashMap::new();
+        let mut receiver = self.daemon.browse(service_type)?;
+        let mut addresses = AddressWatcher::new();
+        let mut refresh = tokio::time::interval(PEER_REFRESH_INTERVAL);
+        refresh.tick().await;
 
         loop {
             tokio::select! {
@@ -92,21 +144,11 @@ impl MDnsDiscovery {
                                 ServiceEvent::ServiceResolved(info) => {
                                     let port = info.get_port();
 
-                                    let ip_hash = info.get_addresses_v4();
-                                    if ip_hash.is_empty() {
+                                    let addrs = service_addrs(&info.addresses, port);
+                                    let Some(&addr) = addrs.first() else {
                                         continue;
-                                    }
-
-                                    let ip = match ip_hash.iter().next() {
-                                        Some(i) => i,
-                                        None => continue,
                                     };
 
-                                    // Check that the IP is not a "self IP"
-                                    if !is_not_self_ip(ip) {
-                                        continue;
-                                    }
-
                                     // Decode the "n" text properties
                                     let n = match info.get_property("n") {
                                         Some(_n) => _n,
@@ -114,45 +156,45 @@ impl MDnsDiscovery {
                                     };
 
                                     // Parse the endpoint info
-                                    let (dt, dn) = match parse_mdns_endpoint_info(n.val_str()) {
+                                    let rdi = match parse_mdns_endpoint_info(n.val_str()) {
                                         Ok(r) => r,
                                         Err(_) => continue
                                     };
 
-                                    let ip_port = format!("{ip}:{port}");
                                     let fullname = info.get_fullname().to_string();
 
                                     // Validate Service ID to ensure this is a real Quick Share service
-                                    if !is_valid_quickshare_service(&fullname) {
+                                    let Some(endpoint_id) = service_endpoint_id(&fullname) else {
                                         debug!("MDnsDiscovery: Invalid service ID in {fullname}, skipping");
                                         continue;
+                                    };
+                                    // Our own service, other instances on this host have their own endpoint id
+                                    if endpoint_id.as_bytes() == get_endpoint_id() {
+                                        continue;
                                     }
 
-                                    if TcpStream::connect(&ip_port).await.is_ok() {
-                                        let ei = EndpointInfo {
-                                            fullname: fullname.clone(),
-                                            id: ip_port,
-                                            name: Some(dn),
-                                            ip: Some(ip.to_string()),
-                                            port: Some(port.to_string()),
-                                            rtype: Some(dt),
-                                            present: Some(true),
-                                        };
-                                        info!("ServiceResolved: Resolved a new service: {ei:?}");
-                                        cache.insert(fullname.clone(), ei.clone());
-                                        drop(self.sender.send(ei));
-                                    }
+                                    // Not probed, which address works is only found out when sending
+                                    let ei = EndpointInfo {
+                                        fullname: fullname.clone(),
+                                        id: endpoint_id,
+                                        name: Some(rdi.name),
+                                        ip: Some(scoped_ip(addr)),
+                                        port: Some(port.to_string()),
+                                        addresses: addrs.iter().map(ToString::to_string).collect(),
+                                        rtype: Some(rdi.device_type),
+                                        hidden: Some(rdi.hidden),
+                                        present: Some(true),
+                                    };
+                                    info!("ServiceResolved: Resolved a new service: {ei:?}");
+                                    peers::seen(ei.clone());
+                                    drop(self.sender.send(ei));
                                 }
                                 ServiceEvent::ServiceRemoved(_, fullname) => {
                                     trace!("ServiceRemoved: checking if should remove {fullname}");
-                                    // Only remove if it has not been seen in the last cleanup_threshold
-                                    let should_remove = cache.get(&fullname).map(|ei| ei.id.clone());
-
-                                    if let Some(id) = should_remove {
+                                    if let Some(peer) = service_endpoint_id(&fullname).and_then(|id| peers::remove(&id)) {
                                         info!("ServiceRemoved: Remove a previous service: {fullname}");
-                                        cache.remove(&fullname);
                                         drop(self.sender.send(EndpointInfo {
-                                            id,
+                                            id: peer.endpoint.id,
                                             ..Default::default()
                                         }));
                                     }
@@ -164,9 +206,28 @@ impl MDnsDiscovery {
                         Err(err) => error!("MDnsDiscovery: error: {err}"),
                     }
                 }
+                ips = addresses.changed() => {
+                    // Query again, so services are resolved on the new interfaces right away
+                    info!("MDnsDiscovery: addresses changed to {ips:?}, restarting browsing");
+                    receiver = self.restart_browse(service_type)?;
+                }
+                _ = refresh.tick() => {
+                    // Services still answering (or cached) are resolved again, refreshing their last-seen
+                    receiver = self.restart_browse(service_type)?;
+                    for peer in peers::expire(PEER_TTL) {
+                        info!("MDnsDiscovery: {} wasn't seen for {PEER_TTL:?}, removing", peer.endpoint.id);
+                        drop(self.sender.send(EndpointInfo {
+                            id: peer.endpoint.id,
+                            ..Default::default()
+                        }));
+                    }
+                }
             }
         }
 
+        // Whatever is known now won't be refreshed anymore
+        peers::clear();
+
         Ok(())
     }
 }
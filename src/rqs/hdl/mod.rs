@@ -1,15 +1,27 @@
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
 
 use info::{InternalFileInfo, TransferMetadata};
 use p256::{PublicKey, SecretKey};
+use tokio::time::Instant;
 use zeroize::Zeroize;
 
+use crate::history::HistoryFile;
+use crate::location_nearby_connections::payload_transfer_frame::{
+    self, ControlMessage, PayloadHeader, control_message, payload_header,
+};
+use crate::location_nearby_connections::v1_frame::FrameType;
+use crate::location_nearby_connections::{OfflineFrame, PayloadTransferFrame, V1Frame, offline_frame};
 use crate::securegcm::ukey2_client_init::CipherCommitment;
+use crate::sink::PayloadSink;
+use crate::sharing_nearby::{PairedKeyEncryptionFrame, PublicCertificate};
 use crate::sharing_nearby::wifi_credentials_metadata::SecurityType;
 use crate::utils::RemoteDeviceInfo;
 
 mod ble;
 pub use ble::*;
+mod bwu;
 #[cfg(target_os = "linux")]
 mod blea;
 #[cfg(target_os = "linux")]
@@ -23,9 +35,16 @@ mod mdns;
 pub use mdns::*;
 mod outbound;
 pub use outbound::*;
+pub(crate) mod resume;
 
 use serde::{Deserialize, Serialize};
 
+/// Keep-alive interval we request in our ConnectionRequest, and the default for peers that don't set one
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a peer may stay silent before the connection is considered dead
+pub const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long an incoming transfer waits for the user by default
+pub const DEFAULT_CONSENT_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
@@ -43,12 +62,16 @@ pub enum TransferState {
     SentIntroduction,
     ReceivedPairedKeyResult,
     WaitingForUserConsent,
+    /// Nobody answered the consent prompt in time, the sender was told so
+    TimedOut,
     ReceivingFiles,
     SendingFiles,
     /// All files sent, waiting for PAYLOAD_RECEIVED_ACK from receiver
     WaitingForPayloadAck,
     /// Sent request_safe_to_disconnect, waiting for ack_safe_to_disconnect
     WaitingForDisconnectAck,
+    /// The connection dropped mid-transfer, waiting for it to be re-established (see `resume`)
+    Reconnecting,
     Disconnected,
     Rejected,
     Cancelled,
@@ -68,6 +91,8 @@ pub struct InnerState {
     pub pin_code: Option<String>,
     pub transfer_metadata: Option<TransferMetadata>,
     pub transferred_files: HashMap<i64, InternalFileInfo>,
+    /// Custom sink picked when the files were introduced (None: the download dir)
+    pub payload_sink: Option<Arc<dyn PayloadSink>>,
 
     // Everything needed for encryption/decryption/verif
     pub cipher_commitment: Option<CipherCommitment>,
@@ -80,12 +105,20 @@ pub struct InnerState {
     pub recv_hmac_key: Option<Vec<u8>>,
     pub encrypt_key: Option<Vec<u8>>,
     pub send_hmac_key: Option<Vec<u8>>,
+    /// UKEY2 auth string, signed for the paired key encryption
+    pub auth_token: Option<Vec<u8>>,
+
+    // Paired key verification (see `certs`)
+    /// The peer proved it owns a certificate we kept
+    pub peer_verified: bool,
+    /// The peer's paired key encryption, to tell which of its certificates it owns
+    pub peer_paired_key: Option<PairedKeyEncryptionFrame>,
+    /// The public certificate the peer proved owning, kept once the transfer finished
+    pub peer_certificate: Option<PublicCertificate>,
 
     // Used to handle/track ingress transfer
-    pub text_payload: Option<TextPayloadInfo>,
-    // pub text_payload_id: i64,
-    // pub text_is_url: bool,
-    // pub wifi_ssid: Option<String>,
+    /// Text, URL and Wi-Fi payloads still to be received, by payload id
+    pub text_payloads: HashMap<i64, TextPayloadInfo>,
     pub payload_buffers: HashMap<i64, Vec<u8>>,
 
     // Used for outbound transfer completion handshake
@@ -93,6 +126,29 @@ pub struct InnerState {
     pub pending_payload_acks: HashSet<i64>,
     /// Timestamp when we started waiting for ACKs (for timeout)
     pub ack_wait_started: Option<std::time::Instant>,
+
+    pub keep_alive: KeepAlive,
+
+    // Bandwidth upgrade
+    /// Endpoint id from the peer's ConnectionRequest
+    pub remote_endpoint_id: Option<String>,
+    /// The peer listed Wi-Fi LAN in the mediums it can upgrade to
+    pub peer_supports_wifi_lan: bool,
+    /// The connection moved to a new socket, see `bwu`
+    pub upgraded: bool,
+
+    // Auto-resume
+    /// This connection continues a transfer interrupted by a dropped connection
+    pub resuming: bool,
+    /// Payload IDs the sender still waits for the receiver's resume point of
+    pub awaiting_resume: HashSet<i64>,
+
+    /// Files dropped mid-transfer, their chunks still in flight are skipped
+    pub cancelled_payloads: HashSet<i64>,
+
+    // Kept for the transfer history
+    pub started_at: std::time::SystemTime,
+    pub history_files: Vec<HistoryFile>,
 }
 
 impl InnerState {
@@ -108,6 +164,7 @@ impl InnerState {
             pin_code: None,
             transfer_metadata,
             transferred_files: HashMap::new(),
+            payload_sink: None,
             cipher_commitment: None,
             private_key: None,
             public_key: None,
@@ -118,12 +175,211 @@ impl InnerState {
             recv_hmac_key: None,
             encrypt_key: None,
             send_hmac_key: None,
-            text_payload: None,
+            auth_token: None,
+            peer_verified: false,
+            peer_paired_key: None,
+            peer_certificate: None,
+            text_payloads: HashMap::new(),
             payload_buffers: HashMap::new(),
             pending_payload_acks: HashSet::new(),
             ack_wait_started: None,
+            keep_alive: KeepAlive::default(),
+            remote_endpoint_id: None,
+            peer_supports_wifi_lan: false,
+            upgraded: false,
+            resuming: false,
+            awaiting_resume: HashSet::new(),
+            cancelled_payloads: HashSet::new(),
+            started_at: std::time::SystemTime::now(),
+            history_files: Vec::new(),
+        }
+    }
+}
+
+impl InnerState {
+    /// Move the transfer out, leaving the connection (keys, sequence numbers) behind.
+    pub fn take_resumable(&mut self) -> ResumableTransfer {
+        ResumableTransfer {
+            id: self.id.clone(),
+            remote_device_info: self.remote_device_info.clone(),
+            pin_code: self.pin_code.clone(),
+            transfer_metadata: self.transfer_metadata.take(),
+            transferred_files: std::mem::take(&mut self.transferred_files),
+            payload_sink: self.payload_sink.take(),
+            text_payloads: std::mem::take(&mut self.text_payloads),
+            pending_payload_acks: std::mem::take(&mut self.pending_payload_acks),
+            peer_verified: self.peer_verified,
+            peer_certificate: self.peer_certificate.take(),
+            started_at: self.started_at,
+            history_files: std::mem::take(&mut self.history_files),
         }
     }
+
+    /// Carry on with a transfer taken out of a previous connection.
+    pub fn resume(&mut self, transfer: ResumableTransfer) {
+        self.id = transfer.id;
+        self.remote_device_info = transfer.remote_device_info;
+        self.pin_code = transfer.pin_code;
+        self.transfer_metadata = transfer.transfer_metadata;
+        self.transferred_files = transfer.transferred_files;
+        self.payload_sink = transfer.payload_sink;
+        self.text_payloads = transfer.text_payloads;
+        self.pending_payload_acks = transfer.pending_payload_acks;
+        self.peer_verified = transfer.peer_verified;
+        self.peer_certificate = transfer.peer_certificate;
+        self.started_at = transfer.started_at;
+        self.history_files = transfer.history_files;
+        self.resuming = true;
+    }
+}
+
+/// What outlives a dropped connection, to be resumed over a new one.
+#[derive(Debug)]
+pub struct ResumableTransfer {
+    pub id: String,
+    pub remote_device_info: Option<RemoteDeviceInfo>,
+    pub pin_code: Option<String>,
+    pub transfer_metadata: Option<TransferMetadata>,
+    pub transferred_files: HashMap<i64, InternalFileInfo>,
+    pub payload_sink: Option<Arc<dyn PayloadSink>>,
+    pub text_payloads: HashMap<i64, TextPayloadInfo>,
+    pub pending_payload_acks: HashSet<i64>,
+    pub peer_verified: bool,
+    pub peer_certificate: Option<PublicCertificate>,
+    pub started_at: std::time::SystemTime,
+    pub history_files: Vec<HistoryFile>,
+}
+
+/// Keep-alive timer of a connection.
+///
+/// The values come from the ConnectionRequest: the one we sent for outbound
+/// transfers, the one the peer sent for inbound ones.
+#[derive(Debug)]
+pub struct KeepAlive {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub last_sent: Instant,
+    pub last_received: Instant,
+    /// Keep-alives are only sent once both sides switched to encrypted frames
+    pub enabled: bool,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self::new(KEEP_ALIVE_INTERVAL, KEEP_ALIVE_TIMEOUT)
+    }
+}
+
+impl KeepAlive {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            timeout,
+            last_sent: now,
+            last_received: now,
+            enabled: false,
+        }
+    }
+
+    /// Build from the millis of a ConnectionRequest, missing values fall back to the defaults.
+    pub fn negotiated(interval_millis: i32, timeout_millis: i32) -> Self {
+        let millis = |v: i32, default: Duration| {
+            u64::try_from(v)
+                .ok()
+                .filter(|v| *v > 0)
+                .map_or(default, Duration::from_millis)
+        };
+        let timeout = millis(timeout_millis, KEEP_ALIVE_TIMEOUT);
+        // A single lost keep-alive shouldn't be enough for the peer to give up
+        let interval = millis(interval_millis, KEEP_ALIVE_INTERVAL).min(timeout / 2);
+
+        Self::new(interval, timeout)
+    }
+
+    /// The peer didn't send anything for longer than the timeout.
+    pub fn is_expired(&self) -> bool {
+        self.last_received.elapsed() >= self.timeout
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.enabled && self.last_sent.elapsed() >= self.interval
+    }
+
+    /// When the timer next needs attention.
+    pub fn next_deadline(&self) -> Instant {
+        let expiry = self.last_received + self.timeout;
+        if self.enabled {
+            expiry.min(self.last_sent + self.interval)
+        } else {
+            expiry
+        }
+    }
+}
+
+/// What happens to an (encrypted) OfflineFrame once the connection is set up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameHandling {
+    /// Part of the transfer
+    Process,
+    /// Answered, without any effect on the transfer
+    Acknowledge,
+    /// Known, but for features we don't implement
+    Ignore,
+    /// Only valid during the handshake (or not valid at all)
+    Unexpected,
+}
+
+pub fn offline_frame_handling(frame_type: FrameType) -> FrameHandling {
+    match frame_type {
+        FrameType::PayloadTransfer
+        | FrameType::KeepAlive
+        | FrameType::Disconnection
+        | FrameType::BandwidthUpgradeNegotiation
+        | FrameType::AutoResume
+        | FrameType::AutoReconnect => FrameHandling::Process,
+        // Android sends it (type 12) right after the connection is set up
+        FrameType::BandwidthUpgradeRetry => FrameHandling::Acknowledge,
+        FrameType::PairedKeyEncryption
+        | FrameType::AuthenticationMessage
+        | FrameType::AuthenticationResult => FrameHandling::Ignore,
+        FrameType::UnknownFrameType | FrameType::ConnectionRequest | FrameType::ConnectionResponse => {
+            FrameHandling::Unexpected
+        }
+    }
+}
+
+/// Log a frame that is ignored, at a level depending on whether it was expected.
+pub fn log_skipped_frame(frame_type: FrameType) {
+    if offline_frame_handling(frame_type) == FrameHandling::Unexpected {
+        warn!("Unexpected offline frame: {frame_type:?}");
+    } else {
+        debug!("Ignoring offline frame: {frame_type:?}");
+    }
+}
+
+/// A CONTROL packet about a file payload (ack, cancellation, error).
+pub fn control_frame(payload_id: i64, event: control_message::EventType) -> OfflineFrame {
+    OfflineFrame {
+        version: Some(offline_frame::Version::V1.into()),
+        v1: Some(V1Frame {
+            r#type: Some(FrameType::PayloadTransfer.into()),
+            payload_transfer: Some(PayloadTransferFrame {
+                packet_type: Some(payload_transfer_frame::PacketType::Control.into()),
+                payload_header: Some(PayloadHeader {
+                    id: Some(payload_id),
+                    r#type: Some(payload_header::PayloadType::File.into()),
+                    ..Default::default()
+                }),
+                control_message: Some(ControlMessage {
+                    event: Some(event.into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+    }
 }
 
 impl Drop for InnerState {
@@ -141,6 +397,9 @@ impl Drop for InnerState {
         if let Some(ref mut key) = self.send_hmac_key {
             key.zeroize();
         }
+        if let Some(ref mut key) = self.auth_token {
+            key.zeroize();
+        }
         // Zeroize intermediate key derivation data
         if let Some(ref mut data) = self.server_init_data {
             data.zeroize();
@@ -152,17 +411,11 @@ impl Drop for InnerState {
             data.zeroize();
         }
 
-        // Clean up partial files from failed transfers
-        if self.state != TransferState::Finished {
-            for file_info in self.transferred_files.values() {
-                if file_info.bytes_transferred > 0 && file_info.bytes_transferred < file_info.total_size {
-                    // This is a partial file - attempt cleanup
-                    if let Err(e) = std::fs::remove_file(&file_info.file_url) {
-                        log::warn!("Failed to cleanup partial file {:?}: {e}", file_info.file_url);
-                    } else {
-                        log::info!("Cleaned up partial file: {:?}", file_info.file_url);
-                    }
-                }
+        // Clean up partial files from failed transfers.
+        // Completed files are no longer tracked here, whatever is left is partial.
+        for file_info in self.transferred_files.values_mut() {
+            if let Some(sink) = file_info.sink.take() {
+                sink.abort();
             }
         }
     }
@@ -183,7 +436,7 @@ pub enum TextPayloadType {
 }
 
 impl TextPayloadInfo {
-    fn get_i64_value(&self) -> i64 {
+    pub fn get_i64_value(&self) -> i64 {
         match self {
             TextPayloadInfo::Url(value)
             | TextPayloadInfo::Text(value)
@@ -191,3 +444,331 @@ impl TextPayloadInfo {
         }
     }
 }
+
+/// An inbound and an outbound handler connected over loopback, with the keys a UKEY2
+/// handshake would have left them: tests can start right away with encrypted frames.
+#[cfg(test)]
+pub(crate) async fn connected_pair(
+    sender: tokio::sync::broadcast::Sender<crate::channel::ChannelMessage>,
+) -> Result<(InboundRequest, OutboundRequest), anyhow::Error> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let client = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+    let (server, _) = listener.accept().await?;
+
+    // One key pair per direction
+    let (to_inbound, to_inbound_hmac) = (crate::utils::gen_random(32), crate::utils::gen_random(32));
+    let (to_outbound, to_outbound_hmac) = (crate::utils::gen_random(32), crate::utils::gen_random(32));
+
+    let mut inbound = InboundRequest::new(server, "inbound".into(), sender.clone());
+    inbound.state.state = TransferState::SentPairedKeyResult;
+    inbound.state.remote_endpoint_id = Some("OUTB".into());
+    inbound.state.decrypt_key = Some(to_inbound.clone());
+    inbound.state.recv_hmac_key = Some(to_inbound_hmac.clone());
+    inbound.state.encrypt_key = Some(to_outbound.clone());
+    inbound.state.send_hmac_key = Some(to_outbound_hmac.clone());
+
+    let mut outbound = OutboundRequest::new(
+        *b"OUTB",
+        client,
+        "outbound".into(),
+        sender,
+        OutboundPayload::Files(Vec::new()),
+        RemoteDeviceInfo::new("inbound".into(), crate::utils::DeviceType::Laptop),
+    );
+    outbound.state.state = TransferState::SentIntroduction;
+    outbound.state.encrypt_key = Some(to_inbound);
+    outbound.state.send_hmac_key = Some(to_inbound_hmac);
+    outbound.state.decrypt_key = Some(to_outbound);
+    outbound.state.recv_hmac_key = Some(to_outbound_hmac);
+
+    Ok((inbound, outbound))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use prost::Message;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::channel::{ChannelMessage, TransferAction};
+    use crate::hdl::info::{FileOutcomeKind, TransferPayload, TransferPayloadKind};
+    use crate::location_nearby_connections::BandwidthUpgradeRetryFrame;
+    use crate::location_nearby_connections::payload_transfer_frame::PayloadChunk;
+    use crate::location_nearby_connections::payload_transfer_frame::control_message::EventType;
+    use crate::securemessage::SecureMessage;
+    use crate::sink::{FsSink, IncomingFile, PayloadSink};
+
+    /// Decode a V1 frame that only has its type set, as a peer would send it
+    fn decode_frame_type(raw: u8) -> FrameType {
+        // version = V1, v1 = { type = raw }
+        let bytes = [0x08, 0x01, 0x12, 0x02, 0x08, raw];
+        let frame = OfflineFrame::decode(&bytes[..]).unwrap();
+        frame.v1.unwrap().r#type()
+    }
+
+    #[test]
+    fn test_frame_handling() {
+        let frames = [
+            (0, FrameType::UnknownFrameType, FrameHandling::Unexpected),
+            (1, FrameType::ConnectionRequest, FrameHandling::Unexpected),
+            (2, FrameType::ConnectionResponse, FrameHandling::Unexpected),
+            (3, FrameType::PayloadTransfer, FrameHandling::Process),
+            (4, FrameType::BandwidthUpgradeNegotiation, FrameHandling::Process),
+            (5, FrameType::KeepAlive, FrameHandling::Process),
+            (6, FrameType::Disconnection, FrameHandling::Process),
+            (7, FrameType::PairedKeyEncryption, FrameHandling::Ignore),
+            (8, FrameType::AuthenticationMessage, FrameHandling::Ignore),
+            (9, FrameType::AuthenticationResult, FrameHandling::Ignore),
+            (10, FrameType::AutoResume, FrameHandling::Process),
+            (11, FrameType::AutoReconnect, FrameHandling::Process),
+            (12, FrameType::BandwidthUpgradeRetry, FrameHandling::Acknowledge),
+        ];
+        for (raw, frame_type, handling) in frames {
+            assert_eq!(decode_frame_type(raw), frame_type, "type {raw}");
+            assert_eq!(offline_frame_handling(frame_type), handling, "{frame_type:?}");
+        }
+
+        // Types newer than the vendored protos decode as unknown
+        assert_eq!(decode_frame_type(99), FrameType::UnknownFrameType);
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_upgrade_retry_is_answered() {
+        let (sender, _) = tokio::sync::broadcast::channel(10);
+        let (mut inbound, mut outbound) = connected_pair(sender).await.unwrap();
+
+        let request = OfflineFrame {
+            version: Some(offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(FrameType::BandwidthUpgradeRetry.into()),
+                bandwidth_upgrade_retry: Some(BandwidthUpgradeRetryFrame {
+                    supported_medium: Vec::new(),
+                    is_request: Some(true),
+                }),
+                ..Default::default()
+            }),
+        };
+        outbound.encrypt_and_send(&request).await.unwrap();
+        inbound.handle().await.unwrap();
+
+        let frame_data = bwu::read_frame(&mut outbound.socket).await.unwrap();
+        let smsg = SecureMessage::decode(&*frame_data).unwrap();
+        let d2d_msg = outbound.decrypt_secure_message(&smsg).unwrap();
+        assert_eq!(d2d_msg.sequence_number(), 1);
+        let reply = OfflineFrame::decode(d2d_msg.message()).unwrap().v1.unwrap();
+        assert_eq!(reply.r#type(), FrameType::BandwidthUpgradeRetry);
+        assert!(!reply.bandwidth_upgrade_retry.unwrap().is_request());
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_sent_and_timeout_enforced() {
+        let (sender, _) = tokio::sync::broadcast::channel(10);
+        let (mut inbound, mut outbound) = connected_pair(sender).await.unwrap();
+        inbound.state.keep_alive = KeepAlive::new(Duration::from_millis(100), Duration::from_millis(500));
+        inbound.state.keep_alive.enabled = true;
+
+        let request = OfflineFrame {
+            version: Some(offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(FrameType::BandwidthUpgradeRetry.into()),
+                bandwidth_upgrade_retry: Some(BandwidthUpgradeRetryFrame {
+                    supported_medium: Vec::new(),
+                    is_request: Some(true),
+                }),
+                ..Default::default()
+            }),
+        };
+        let data = outbound.encrypt_frame(&request).await.unwrap();
+        let mut prefixed = u32::try_from(data.len()).unwrap().to_be_bytes().to_vec();
+        prefixed.extend_from_slice(&data);
+
+        // The keep-alive timer fires with half of the length read, which must not get lost
+        outbound.socket.write_all(&prefixed[..2]).await.unwrap();
+        inbound.handle().await.unwrap();
+        outbound.socket.write_all(&prefixed[2..]).await.unwrap();
+        inbound.handle().await.unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let frame_data = bwu::read_frame(&mut outbound.socket).await.unwrap();
+            let smsg = SecureMessage::decode(&*frame_data).unwrap();
+            let d2d_msg = outbound.decrypt_secure_message(&smsg).unwrap();
+            received.push(OfflineFrame::decode(d2d_msg.message()).unwrap().v1.unwrap().r#type());
+        }
+        assert_eq!(received, [FrameType::KeepAlive, FrameType::BandwidthUpgradeRetry]);
+
+        // The outbound goes silent
+        let started = Instant::now();
+        let err = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Err(e) = inbound.handle().await {
+                    return e;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(err.to_string(), "Keep-alive timeout");
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    /// A chunk of a file as the sender sends it, without a body for the last chunk marker
+    fn file_chunk(payload_id: i64, offset: i64, body: &[u8]) -> OfflineFrame {
+        OfflineFrame {
+            version: Some(offline_frame::Version::V1.into()),
+            v1: Some(V1Frame {
+                r#type: Some(FrameType::PayloadTransfer.into()),
+                payload_transfer: Some(PayloadTransferFrame {
+                    packet_type: Some(payload_transfer_frame::PacketType::Data.into()),
+                    payload_header: Some(PayloadHeader {
+                        id: Some(payload_id),
+                        r#type: Some(payload_header::PayloadType::File.into()),
+                        total_size: Some(4),
+                        ..Default::default()
+                    }),
+                    payload_chunk: Some(PayloadChunk {
+                        offset: Some(offset),
+                        flags: Some(i32::from(body.is_empty())),
+                        body: Some(body.to_vec()),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// The next frame the inbound sent, decrypted
+    async fn next_frame(outbound: &mut OutboundRequest) -> V1Frame {
+        let frame_data = bwu::read_frame(&mut outbound.socket).await.unwrap();
+        let smsg = SecureMessage::decode(&*frame_data).unwrap();
+        let d2d_msg = outbound.decrypt_secure_message(&smsg).unwrap();
+        OfflineFrame::decode(d2d_msg.message()).unwrap().v1.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cancel_one_of_two_files() {
+        let (sender, _) = tokio::sync::broadcast::channel(100);
+        let (mut inbound, mut outbound) = connected_pair(sender.clone()).await.unwrap();
+
+        let dir = std::env::temp_dir().join(format!("kvakk-cancel-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (payload_id, name) in [(1, "cancelled.txt"), (2, "kept.txt")] {
+            let incoming = IncomingFile {
+                transfer_id: inbound.state.id.clone(),
+                payload_id,
+                name: name.into(),
+                path: dir.join(name),
+                total_size: 4,
+            };
+            let info = InternalFileInfo {
+                payload_id,
+                name: name.into(),
+                file_url: dir.join(name),
+                part_url: None,
+                bytes_transferred: 0,
+                chunks_transferred: 0,
+                total_size: 4,
+                file: None,
+                sink: Some(FsSink.create(&incoming).unwrap()),
+            };
+            inbound.state.transferred_files.insert(payload_id, info);
+        }
+        inbound.state.transfer_metadata = Some(TransferMetadata {
+            id: inbound.state.id.clone(),
+            source: None,
+            pin_code: None,
+            peer_verified: false,
+            payload_kind: TransferPayloadKind::Files,
+            payload_preview: None,
+            payload: Some(TransferPayload::Files(vec!["cancelled.txt".into(), "kept.txt".into()])),
+            files: Vec::new(),
+            total_bytes: 8,
+            ack_bytes: 0,
+            file_outcomes: Vec::new(),
+            rejection: None,
+        });
+        inbound.state.state = TransferState::ReceivingFiles;
+
+        // The handler also wakes up for its own state updates
+        let timeout = Duration::from_secs(5);
+        outbound.encrypt_and_send(&file_chunk(1, 0, b"ab")).await.unwrap();
+        tokio::time::timeout(timeout, async {
+            while inbound.state.transferred_files[&1].bytes_transferred == 0 {
+                inbound.handle().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        // The user cancels the first file
+        let cancel = ChannelMessage {
+            id: inbound.state.id.clone(),
+            msg: crate::channel::Message::Lib {
+                action: TransferAction::CancelPayload(1),
+            },
+        };
+        sender.send(cancel).unwrap();
+        tokio::time::timeout(timeout, async {
+            while inbound.state.transferred_files.contains_key(&1) {
+                inbound.handle().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        let frame = next_frame(&mut outbound).await.payload_transfer.unwrap();
+        assert_eq!(frame.payload_header.unwrap().id(), 1);
+        assert_eq!(frame.control_message.unwrap().event(), EventType::PayloadCanceled);
+        assert!(!dir.join("cancelled.txt").exists());
+        assert_eq!(inbound.state.state, TransferState::ReceivingFiles);
+
+        // A chunk of it still in flight is skipped, the other file completes the transfer
+        for frame in [
+            file_chunk(1, 2, b"cd"),
+            file_chunk(2, 0, b"kept"),
+            file_chunk(2, 4, b""),
+        ] {
+            outbound.encrypt_and_send(&frame).await.unwrap();
+        }
+        tokio::time::timeout(timeout, async {
+            while inbound.state.state != TransferState::Finished {
+                inbound.handle().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        let ack = next_frame(&mut outbound).await.payload_transfer.unwrap();
+        assert_eq!(ack.payload_header.unwrap().id(), 2);
+        assert_eq!(ack.control_message.unwrap().event(), EventType::PayloadReceivedAck);
+        let disconnection = next_frame(&mut outbound).await;
+        assert_eq!(disconnection.r#type(), FrameType::Disconnection);
+        assert!(disconnection.disconnection.unwrap().request_safe_to_disconnect());
+
+        assert_eq!(std::fs::read_to_string(dir.join("kept.txt")).unwrap(), "kept");
+        let tmd = inbound.state.transfer_metadata.as_ref().unwrap();
+        // What was received of the cancelled file still counts, the progress ends complete
+        assert_eq!(tmd.total_bytes, 6);
+        assert_eq!(tmd.ack_bytes, 6);
+        assert_eq!(tmd.file_outcomes.len(), 1);
+        assert_eq!(tmd.file_outcomes[0].payload_id, 1);
+        assert_eq!(tmd.file_outcomes[0].kind, FileOutcomeKind::Cancelled);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_alive_negotiated() {
+        let ka = KeepAlive::negotiated(5_000, 30_000);
+        assert_eq!(ka.interval, Duration::from_secs(5));
+        assert_eq!(ka.timeout, Duration::from_secs(30));
+
+        let ka = KeepAlive::negotiated(0, -1);
+        assert_eq!(ka.interval, KEEP_ALIVE_INTERVAL);
+        assert_eq!(ka.timeout, KEEP_ALIVE_TIMEOUT);
+
+        let ka = KeepAlive::negotiated(60_000, 20_000);
+        assert_eq!(ka.interval, Duration::from_secs(10));
+    }
+}
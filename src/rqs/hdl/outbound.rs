@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::net::SocketAddr;
+use std::task::Poll;
 use std::time::Duration;
 
 use anyhow::anyhow;
@@ -14,20 +16,37 @@ use p256::{EncodedPoint, PublicKey};
 use prost::Message;
 use rand::Rng;
 use sha2::{Digest, Sha256, Sha512};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::sync::broadcast::error::TryRecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::Instant;
 
-use super::info::{InternalFileInfo, TransferMetadata, TransferPayload, TransferPayloadKind};
-use super::{InnerState, TransferState};
+use super::info::{
+    FileOutcome, FileOutcomeKind, InternalFileInfo, RejectionReason, TransferFile, TransferMetadata,
+    TransferPayload, TransferPayloadKind,
+};
+use super::bwu::{
+    BWU_HANDSHAKE_TIMEOUT, Upgrade, UpgradeEvent, bwu_event, bwu_event_frame,
+    client_introduction_frame, read_frame, read_frame_body, upgrade_enabled, upgrade_failure_frame,
+    wifi_lan_addr, write_frame,
+};
+use super::resume::{CHUNK_SIZE, RESUME_TIMEOUT, chunk_offset, reconnect_frame, resume_frame};
+use super::{InnerState, TransferState, control_frame, log_skipped_frame};
+use crate::certs;
+use crate::history::HistoryFile;
+use crate::interfaces::on_local_network;
 use crate::channel::{self, ChannelMessage, MessageClient, TransferAction, TransferKind};
 use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::upgrade_path_info::Medium;
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::{EventType, UpgradePathInfo};
 use crate::location_nearby_connections::connection_response_frame::ResponseStatus;
 use crate::location_nearby_connections::payload_transfer_frame::{
-    PacketType, PayloadChunk, PayloadHeader, payload_header,
+    PacketType, PayloadChunk, PayloadHeader, control_message, payload_header,
+};
+use crate::location_nearby_connections::{
+    AutoReconnectFrame, AutoResumeFrame, KeepAliveFrame, OfflineFrame, PayloadTransferFrame,
+    auto_reconnect_frame, auto_resume_frame,
 };
-use crate::location_nearby_connections::{KeepAliveFrame, OfflineFrame, PayloadTransferFrame};
 use crate::securegcm::ukey2_alert::AlertType;
 use crate::securegcm::ukey2_client_init::CipherCommitment;
 use crate::securegcm::{
@@ -42,10 +61,10 @@ use crate::sharing_nearby::{
     FileMetadata, IntroductionFrame, file_metadata, paired_key_result_frame,
 };
 use crate::utils::{
-    DeviceType, RemoteDeviceInfo, encode_point, gen_ecdsa_keypair, gen_random, hkdf_extract_expand,
-    stream_read_exact, to_four_digit_string,
+    LengthReader, RemoteDeviceInfo, encode_point, gen_ecdsa_keypair, gen_random, get_device_type,
+    hkdf_extract_expand, stream_read_exact, to_four_digit_string,
 };
-use crate::{DEVICE_NAME, location_nearby_connections, sharing_nearby};
+use crate::{DEVICE_NAME, VISIBILITY, location_nearby_connections, sharing_nearby};
 
 type HmacSha256 = Hmac<Sha256>;
 
@@ -66,7 +85,10 @@ pub enum OutboundPayload {
 #[derive(Debug)]
 pub struct OutboundRequest {
     endpoint_id: [u8; 4],
-    socket: TcpStream,
+    pub(super) socket: TcpStream,
+    pub(super) upgrade: Upgrade,
+    /// Length of the next frame on `socket`, as far as it was read
+    length_reader: LengthReader,
     pub state: InnerState,
     sender: Sender<ChannelMessage>,
     receiver: Receiver<ChannelMessage>,
@@ -88,17 +110,23 @@ impl OutboundRequest {
         Self {
             endpoint_id,
             socket,
+            upgrade: Upgrade::None,
+            length_reader: LengthReader::default(),
             state: InnerState::new(
                 id,
                 Some(TransferMetadata {
                     source: Some(rdi),
                     payload_kind: TransferPayloadKind::Files,
                     payload: Some(TransferPayload::Files(files.clone())),
+                    files: Vec::new(),
                     id: String::new(),
                     pin_code: None,
+                    peer_verified: false,
                     payload_preview: None,
                     total_bytes: 0,
                     ack_bytes: 0,
+                    file_outcomes: Vec::new(),
+                    rejection: None,
                 }),
             ),
             sender,
@@ -109,30 +137,39 @@ impl OutboundRequest {
 
     pub async fn handle(&mut self) -> Result<(), anyhow::Error> {
         // Check for timeout based on current state
+        let mut ack_deadline = None;
         if let Some(started) = self.state.ack_wait_started {
             let timeout = match self.state.state {
                 TransferState::WaitingForPayloadAck => ACK_TIMEOUT,
                 TransferState::WaitingForDisconnectAck => DISCONNECT_TIMEOUT,
+                TransferState::Reconnecting => RESUME_TIMEOUT,
                 _ => ACK_TIMEOUT,
             };
+            if started.elapsed() > timeout && self.state.state == TransferState::Reconnecting {
+                self.update_state(|e| { e.state = TransferState::Disconnected; }, false).await;
+                return Err(anyhow!("The receiver didn't resume the transfer"));
+            }
             if started.elapsed() > timeout {
                 info!("Timeout reached in state {:?}, finishing transfer", self.state.state);
                 self.update_state(|e| { e.state = TransferState::Finished; }, true).await;
                 return Err(anyhow!(crate::errors::AppError::NotAnError));
             }
+            ack_deadline = Some(Instant::from_std(started + timeout));
         }
 
-        // Buffer for the 4-byte length
-        let mut length_buf = [0u8; 4];
+        if self.state.keep_alive.is_due() {
+            trace!("Sending keepalive");
+            self.send_keepalive(false).await?;
+        }
 
-        // Use a shorter timeout when waiting for ACKs/disconnect to allow periodic timeout checks
-        let read_timeout = if self.state.ack_wait_started.is_some() {
-            Duration::from_secs(1)
-        } else {
-            Duration::from_secs(30)
-        };
+        // Wake up for whichever comes first, the keep-alive timer or the ACK timeout
+        let keep_alive_deadline = self.state.keep_alive.next_deadline();
+        let deadline = ack_deadline.map_or(keep_alive_deadline, |d| d.min(keep_alive_deadline));
 
         tokio::select! {
+            // A frame that is already there shouldn't lose against the timers
+            biased;
+
             i = self.receiver.recv() => {
                 match i {
                     Ok(channel_msg) => {
@@ -142,15 +179,21 @@ impl OutboundRequest {
 
                         if let channel::Message::Lib { action }  = &channel_msg.msg {
                             debug!("outbound: got: {channel_msg:?}");
-                            if action == &TransferAction::TransferCancel {
-                                self.update_state(
-                                    |e| {
-                                        e.state = TransferState::Cancelled;
-                                    },
-                                    true,
-                                ).await;
-                                self.disconnection().await?;
-                                return Err(anyhow!(crate::errors::AppError::NotAnError));
+                            match action {
+                                TransferAction::TransferCancel => {
+                                    self.update_state(
+                                        |e| {
+                                            e.state = TransferState::Cancelled;
+                                        },
+                                        true,
+                                    ).await;
+                                    self.disconnection().await?;
+                                    return Err(anyhow!(crate::errors::AppError::NotAnError));
+                                }
+                                TransferAction::CancelPayload(payload_id) => {
+                                    self.cancel_payload(*payload_id).await?;
+                                }
+                                TransferAction::ConsentAccept | TransferAction::ConsentDecline => {}
                             }
                         }
                     }
@@ -159,21 +202,23 @@ impl OutboundRequest {
                     }
                 }
             },
-            result = tokio::time::timeout(read_timeout, stream_read_exact(&mut self.socket, &mut length_buf)) => {
-                match result {
-                    Ok(h) => {
-                        h?;
-                        self._handle(length_buf).await?;
-                    }
-                    Err(_) => {
-                        // Timeout - if we're waiting for ACKs, this is expected
-                        // Just return Ok to let the loop continue and check timeout
-                        if self.state.ack_wait_started.is_some() {
-                            return Ok(());
-                        }
-                        // Otherwise, this is a real timeout error
-                        return Err(anyhow!("Read timeout"));
-                    }
+            // While migrating, the prior socket is drained first to keep the frames in order
+            h = self.length_reader.read(&mut self.socket), if self.upgrade.reads_current() => {
+                self.state.keep_alive.last_received = Instant::now();
+                self._handle(h?).await?;
+            }
+            event = self.upgrade.next_event() => {
+                self.process_upgrade_event(event).await?;
+            }
+            // Only reached when nothing is waiting on the socket
+            () = tokio::time::sleep_until(deadline) => {
+                if self.state.keep_alive.is_expired() {
+                    warn!(
+                        "No frame from the receiver for {}s in state {:?}",
+                        self.state.keep_alive.timeout.as_secs(),
+                        self.state.state
+                    );
+                    return Err(anyhow!("Keep-alive timeout"));
                 }
             }
         }
@@ -223,12 +268,18 @@ impl OutboundRequest {
                 let frame = location_nearby_connections::OfflineFrame::decode(&*frame_data)?;
                 self.process_connection_response(&frame).await?;
 
-                // Advance current state
+                // Advance current state, a resumed transfer only proves our certificate again
                 self.update_state(
                     |e: &mut InnerState| {
-                        e.state = TransferState::SentPairedKeyEncryption;
+                        if e.resuming {
+                            e.state = TransferState::Reconnecting;
+                            e.ack_wait_started = Some(std::time::Instant::now());
+                        } else {
+                            e.state = TransferState::SentPairedKeyEncryption;
+                        }
                         e.server_init_data = Some(frame_data);
                         e.encryption_done = true;
+                        e.keep_alive.enabled = true;
                     },
                     false,
                 )
@@ -258,18 +309,19 @@ impl OutboundRequest {
                     endpoint_id: Some(String::from_utf8_lossy(&self.endpoint_id).to_string()),
                     endpoint_name: Some(device_name.clone().into()),
                     endpoint_info: Some(
-                        RemoteDeviceInfo {
-                            name: device_name.clone(),
-                            device_type: DeviceType::Laptop,
-                        }
-                        .serialize(),
+                        RemoteDeviceInfo::new(device_name.clone(), get_device_type())
+                        .serialize(VISIBILITY.borrow().is_visible()),
                     ),
                     mediums: vec![Medium::WifiLan.into()],
                     // Nonce for simultaneous connection tiebreaking
                     nonce: Some(rand::rng().random()),
-                    // Keepalive configuration (10 second interval, 30 second timeout)
-                    keep_alive_interval_millis: Some(10_000),
-                    keep_alive_timeout_millis: Some(30_000),
+                    // Both sides use these for their keep-alive timers
+                    keep_alive_interval_millis: Some(
+                        i32::try_from(self.state.keep_alive.interval.as_millis()).unwrap_or(i32::MAX),
+                    ),
+                    keep_alive_timeout_millis: Some(
+                        i32::try_from(self.state.keep_alive.timeout.as_millis()).unwrap_or(i32::MAX),
+                    ),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -436,25 +488,34 @@ impl OutboundRequest {
             version: Some(sharing_nearby::frame::Version::V1.into()),
             v1: Some(sharing_nearby::V1Frame {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::PairedKeyEncryption.into()),
-                paired_key_encryption: Some(sharing_nearby::PairedKeyEncryptionFrame {
-                    secret_id_hash: Some(gen_random(6)),
-                    signed_data: Some(gen_random(72)),
-                    ..Default::default()
-                }),
+                paired_key_encryption: Some(certs::paired_key_encryption(
+                    self.state.auth_token.as_deref().unwrap_or_default(),
+                )),
                 ..Default::default()
             }),
         };
 
         self.send_encrypted_frame(&paired_encryption).await?;
 
+        // Proving our certificate again is what lets the receiver hand the transfer over
+        if self.state.resuming {
+            info!("Asking the receiver to resume transfer {}", self.state.id);
+            let endpoint_id = String::from_utf8_lossy(&self.endpoint_id).to_string();
+            self.encrypt_and_send(&reconnect_frame(
+                &endpoint_id,
+                auto_reconnect_frame::EventType::ClientIntroduction,
+            ))
+            .await?;
+        }
+
         Ok(())
     }
 
-    #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
-    async fn decrypt_and_process_secure_message(
-        &mut self,
+    /// Check and decrypt a frame of the receiver, without looking at its sequence number.
+    pub(super) fn decrypt_secure_message(
+        &self,
         smsg: &SecureMessage,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<DeviceToDeviceMessage, anyhow::Error> {
         let recv_hmac_key = self.state.recv_hmac_key.as_ref()
             .ok_or_else(|| anyhow!("Missing recv_hmac_key"))?;
         let mut hmac = HmacSha256::new_from_slice(recv_hmac_key)?;
@@ -473,7 +534,22 @@ impl OutboundRequest {
         cipher.set_auto_padding(true);
         let decrypted = cipher.cbc_decrypt(header_and_body.header.iv(), &msg_data);
 
-        let d2d_msg = DeviceToDeviceMessage::decode(&*decrypted)?;
+        Ok(DeviceToDeviceMessage::decode(&*decrypted)?)
+    }
+
+    #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
+    async fn decrypt_and_process_secure_message(
+        &mut self,
+        smsg: &SecureMessage,
+    ) -> Result<(), anyhow::Error> {
+        let d2d_msg = self.decrypt_secure_message(smsg)?;
+
+        // The peer's SAFE_TO_CLOSE_PRIOR_CHANNEL may still be on the prior socket of an upgrade
+        let next = self.state.client_seq + 1;
+        if d2d_msg.sequence_number() != next && self.upgrade.hold(d2d_msg.sequence_number(), smsg) {
+            debug!("Holding frame {} until {next} is read from the prior socket", d2d_msg.sequence_number());
+            return Ok(());
+        }
 
         let seq = self.get_client_seq_inc().await;
         if d2d_msg.sequence_number() != seq {
@@ -512,28 +588,28 @@ impl OutboundRequest {
                                 info!("Received PAYLOAD_RECEIVED_ACK for payload {payload_id}");
                                 // Remove from pending set
                                 self.state.pending_payload_acks.remove(&payload_id);
+                                if let Some(hf) = self.state.history_files.iter_mut().find(|f| f.payload_id == payload_id) {
+                                    hf.completed = true;
+                                }
 
-                                // If we were waiting for ACKs and all are received, request disconnect
-                                if self.state.state == TransferState::WaitingForPayloadAck
-                                    && self.state.pending_payload_acks.is_empty()
-                                {
-                                    info!("All payload ACKs received, requesting safe disconnect");
-                                    self.update_state(|e| {
-                                        e.state = TransferState::WaitingForDisconnectAck;
-                                        // Reset timer for disconnect phase
-                                        e.ack_wait_started = Some(std::time::Instant::now());
-                                    }, false).await;
-                                    self.request_disconnection().await?;
+                                // The receiver got the whole file before the previous connection dropped
+                                if self.state.state == TransferState::Reconnecting {
+                                    self.state.transferred_files.remove(&payload_id);
+                                    self.state.awaiting_resume.remove(&payload_id);
+                                    self.finish_resume_if_ready().await?;
                                 }
+
+                                self.finish_if_all_acked().await?;
                             }
                             EventType::PayloadError => {
                                 warn!("Received PAYLOAD_ERROR for payload {payload_id}");
-                                // Remove from pending - we got a response even if it's an error
-                                self.state.pending_payload_acks.remove(&payload_id);
+                                self.drop_payload(payload_id, FileOutcomeKind::Failed {
+                                    reason: "the receiver reported an error".into(),
+                                }).await?;
                             }
                             EventType::PayloadCanceled => {
                                 info!("Received PAYLOAD_CANCELED for payload {payload_id}");
-                                self.state.pending_payload_acks.remove(&payload_id);
+                                self.drop_payload(payload_id, FileOutcomeKind::Cancelled).await?;
                             }
                             EventType::UnknownEventType => {
                                 debug!("Received unknown control event for payload {payload_id}");
@@ -615,8 +691,11 @@ impl OutboundRequest {
                 }
             }
             location_nearby_connections::v1_frame::FrameType::KeepAlive => {
-                trace!("Sending keepalive");
-                self.send_keepalive(true).await?;
+                // Only answer keep-alives, not the acks to ours
+                if !v1_frame.keep_alive.as_ref().is_some_and(KeepAliveFrame::ack) {
+                    trace!("Sending keepalive ack");
+                    self.send_keepalive(true).await?;
+                }
             }
             location_nearby_connections::v1_frame::FrameType::Disconnection => {
                 debug!("Received Disconnection frame");
@@ -633,10 +712,39 @@ impl OutboundRequest {
                         self.update_state(|e| { e.state = TransferState::Finished; }, true).await;
                         return Err(anyhow!(crate::errors::AppError::NotAnError));
                     }
+                    if self.state.state == TransferState::Reconnecting {
+                        self.update_state(|e| { e.state = TransferState::Disconnected; }, false).await;
+                        return Err(anyhow!("The receiver has no transfer to resume"));
+                    }
                 }
             }
-            _ => {
-                debug!("Unhandled offline frame type: {:?}", v1_frame.r#type());
+            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation => {
+                if let Some(bwu) = &v1_frame.bandwidth_upgrade_negotiation {
+                    self.process_bandwidth_upgrade(bwu).await?;
+                }
+            }
+            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeRetry => {
+                if v1_frame.bandwidth_upgrade_retry.as_ref().is_some_and(location_nearby_connections::BandwidthUpgradeRetryFrame::is_request) {
+                    self.send_bandwidth_upgrade_retry_reply().await?;
+                }
+            }
+            location_nearby_connections::v1_frame::FrameType::AutoReconnect => {
+                if let Some(auto_reconnect) = &v1_frame.auto_reconnect {
+                    self.process_auto_reconnect(auto_reconnect).await?;
+                }
+            }
+            location_nearby_connections::v1_frame::FrameType::AutoResume => {
+                if let Some(auto_resume) = &v1_frame.auto_resume {
+                    self.process_auto_resume(auto_resume).await?;
+                }
+            }
+            frame_type @ (location_nearby_connections::v1_frame::FrameType::UnknownFrameType
+            | location_nearby_connections::v1_frame::FrameType::ConnectionRequest
+            | location_nearby_connections::v1_frame::FrameType::ConnectionResponse
+            | location_nearby_connections::v1_frame::FrameType::PairedKeyEncryption
+            | location_nearby_connections::v1_frame::FrameType::AuthenticationMessage
+            | location_nearby_connections::v1_frame::FrameType::AuthenticationResult) => {
+                log_skipped_frame(frame_type);
             }
         }
 
@@ -665,6 +773,28 @@ impl OutboundRequest {
             return Err(anyhow!(crate::errors::AppError::NotAnError));
         }
 
+        // Not part of the handshake, it can come at any point
+        if v1_frame.r#type() == sharing_nearby::v1_frame::FrameType::CertificateInfo {
+            if let Some(certificate_info) = &v1_frame.certificate_info {
+                debug!("Received {} public certificate(s)", certificate_info.public_certificate.len());
+                let certificate = self.state.peer_paired_key.as_ref().and_then(|frame| {
+                    certs::bound_certificate(
+                        &certificate_info.public_certificate,
+                        frame,
+                        self.state.auth_token.as_deref().unwrap_or_default(),
+                    )
+                });
+                match certificate {
+                    Some(certificate) => {
+                        self.update_state(|e| e.peer_certificate = Some(certificate), false)
+                            .await;
+                    }
+                    None => debug!("None of them is bound to this connection, ignoring them"),
+                }
+            }
+            return Ok(());
+        }
+
         match self.state.state {
             TransferState::SentPairedKeyEncryption => {
                 debug!("Processing State::SentPairedKeyEncryption");
@@ -692,7 +822,8 @@ impl OutboundRequest {
                 debug!("Processing State::SentIntroduction");
                 self.process_consent(v1_frame).await?;
             }
-            TransferState::SendingFiles => {}
+            // The receiver answers our paired key encryption before taking the transfer over
+            TransferState::SendingFiles | TransferState::Reconnecting => {}
             _ => {
                 info!(
                     "Unhandled connection state in process_transfer_setup: {:?}",
@@ -708,16 +839,35 @@ impl OutboundRequest {
         &mut self,
         v1_frame: &sharing_nearby::V1Frame,
     ) -> Result<(), anyhow::Error> {
-        if v1_frame.paired_key_encryption.is_none() {
-            return Err(anyhow!("Missing required fields"));
-        }
+        let paired_key_encryption = v1_frame
+            .paired_key_encryption
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing required fields"))?;
+
+        let status = certs::verify(
+            paired_key_encryption,
+            self.state.auth_token.as_deref().unwrap_or_default(),
+        );
+        let verified = status == paired_key_result_frame::Status::Success;
+        let paired_key_encryption = paired_key_encryption.clone();
+        self.update_state(
+            |e| {
+                e.peer_verified = verified;
+                e.peer_paired_key = Some(paired_key_encryption);
+                if let Some(ref mut tm) = e.transfer_metadata {
+                    tm.peer_verified = verified;
+                }
+            },
+            false,
+        )
+        .await;
 
         let paired_result = sharing_nearby::Frame {
             version: Some(sharing_nearby::frame::Version::V1.into()),
             v1: Some(sharing_nearby::V1Frame {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::PairedKeyResult.into()),
                 paired_key_result: Some(sharing_nearby::PairedKeyResultFrame {
-                    status: Some(paired_key_result_frame::Status::Unable.into()),
+                    status: Some(status.into()),
                 }),
                 ..Default::default()
             }),
@@ -725,6 +875,19 @@ impl OutboundRequest {
 
         self.send_encrypted_frame(&paired_result).await?;
 
+        // For the peer to recognize us next time
+        if let Some(certificate_info) = certs::certificate_info() {
+            let frame = sharing_nearby::Frame {
+                version: Some(sharing_nearby::frame::Version::V1.into()),
+                v1: Some(sharing_nearby::V1Frame {
+                    r#type: Some(sharing_nearby::v1_frame::FrameType::CertificateInfo.into()),
+                    certificate_info: Some(certificate_info),
+                    ..Default::default()
+                }),
+            };
+            self.send_encrypted_frame(&frame).await?;
+        }
+
         Ok(())
     }
 
@@ -738,73 +901,20 @@ impl OutboundRequest {
 
         let mut file_metadata: Vec<FileMetadata> = vec![];
         let mut transferred_files: HashMap<i64, InternalFileInfo> = HashMap::new();
+        let mut history_files: Vec<HistoryFile> = vec![];
         let mut total_to_send = 0;
         // TODO - Handle sending Text
         match &self.payload {
             OutboundPayload::Files(files) => {
                 for f in files {
-                    let path = Path::new(f);
-                    if !path.is_file() {
-                        warn!("Path is not a file: {f}");
+                    let Some((fmeta, info, history)) = Self::outgoing_file(f)? else {
                         continue;
-                    }
-
-                    let file = match File::open(f) {
-                        Ok(_f) => _f,
-                        Err(e) => {
-                            error!("Failed to open file: {f}: {e:?}");
-                            continue;
-                        }
-                    };
-                    let fmetadata = match file.metadata() {
-                        Ok(_fm) => _fm,
-                        Err(e) => {
-                            error!("Failed to get metadata for: {f}: {e:?}");
-                            continue;
-                        }
-                    };
-
-                    let ftype = mime_guess::from_path(path)
-                        .first_or_octet_stream()
-                        .to_string();
-
-                    let meta_type = if ftype.starts_with("image/") {
-                        file_metadata::Type::Image
-                    } else if ftype.starts_with("video/") {
-                        file_metadata::Type::Video
-                    } else if ftype.starts_with("audio/") {
-                        file_metadata::Type::Audio
-                    } else if path.extension().unwrap_or_default() == "apk" {
-                        file_metadata::Type::App
-                    } else {
-                        file_metadata::Type::Unknown
                     };
 
-                    info!("File type to send: {ftype}");
-                    let fname = path
-                        .file_name()
-                        .ok_or_else(|| anyhow!("Failed to get file_name for {f}"))?;
-                    let file_size = i64::try_from(fmetadata.size()).unwrap_or(i64::MAX);
-                    let fmeta = FileMetadata {
-                        payload_id: Some(rand::rng().random::<i64>()),
-                        name: Some(fname.to_string_lossy().into_owned()),
-                        size: Some(file_size),
-                        mime_type: Some(ftype),
-                        r#type: Some(meta_type.into()),
-                        ..Default::default()
-                    };
-                    transferred_files.insert(
-                        fmeta.payload_id(),
-                        InternalFileInfo {
-                            payload_id: fmeta.payload_id(),
-                            file_url: path.to_path_buf(),
-                            bytes_transferred: 0,
-                            total_size: fmeta.size(),
-                            file: Some(file),
-                        },
-                    );
+                    total_to_send += history.size;
+                    transferred_files.insert(fmeta.payload_id(), info);
+                    history_files.push(history);
                     file_metadata.push(fmeta);
-                    total_to_send += fmetadata.size();
                 }
             }
         }
@@ -813,8 +923,10 @@ impl OutboundRequest {
             |e| {
                 if let Some(tmd) = e.transfer_metadata.as_mut() {
                     tmd.total_bytes = total_to_send;
+                    tmd.files = history_files.iter().map(TransferFile::from).collect();
                 }
                 e.transferred_files = transferred_files;
+                e.history_files = history_files;
             },
             false,
         )
@@ -837,29 +949,127 @@ impl OutboundRequest {
         Ok(())
     }
 
-    /// Check if a cancellation request was received.
-    /// Returns true if transfer should be cancelled.
-    fn check_for_cancellation(&mut self) -> bool {
+    /// Open a file to send and describe it for the introduction, None if it can't be sent.
+    fn outgoing_file(
+        f: &str,
+    ) -> Result<Option<(FileMetadata, InternalFileInfo, HistoryFile)>, anyhow::Error> {
+        let path = Path::new(f);
+        if !path.is_file() {
+            warn!("Path is not a file: {f}");
+            return Ok(None);
+        }
+
+        let file = match File::open(f) {
+            Ok(_f) => _f,
+            Err(e) => {
+                error!("Failed to open file: {f}: {e:?}");
+                return Ok(None);
+            }
+        };
+        let fmetadata = match file.metadata() {
+            Ok(_fm) => _fm,
+            Err(e) => {
+                error!("Failed to get metadata for: {f}: {e:?}");
+                return Ok(None);
+            }
+        };
+
+        let ftype = mime_guess::from_path(path)
+            .first_or_octet_stream()
+            .to_string();
+        let meta_type = Self::file_type(path, &ftype);
+
+        info!("File type to send: {ftype}");
+        let fname = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Failed to get file_name for {f}"))?
+            .to_string_lossy()
+            .into_owned();
+        let file_size = i64::try_from(fmetadata.size()).unwrap_or(i64::MAX);
+        let fmeta = FileMetadata {
+            payload_id: Some(rand::rng().random::<i64>()),
+            name: Some(fname.clone()),
+            size: Some(file_size),
+            mime_type: Some(ftype),
+            r#type: Some(meta_type.into()),
+            ..Default::default()
+        };
+        let info = InternalFileInfo {
+            payload_id: fmeta.payload_id(),
+            name: fname,
+            file_url: path.to_path_buf(),
+            part_url: None,
+            bytes_transferred: 0,
+            chunks_transferred: 0,
+            total_size: fmeta.size(),
+            file: Some(file),
+            sink: None,
+        };
+        let history = HistoryFile {
+            payload_id: fmeta.payload_id(),
+            name: fmeta.name().to_owned(),
+            size: fmetadata.size(),
+            path: Some(path.to_path_buf()),
+            completed: false,
+        };
+
+        Ok(Some((fmeta, info, history)))
+    }
+
+    /// The kind of file the receiver is told about, from its mime type.
+    fn file_type(path: &Path, mime_type: &str) -> file_metadata::Type {
+        if mime_type.starts_with("image/") {
+            file_metadata::Type::Image
+        } else if mime_type.starts_with("video/") {
+            file_metadata::Type::Video
+        } else if mime_type.starts_with("audio/") {
+            file_metadata::Type::Audio
+        } else if path.extension().unwrap_or_default() == "apk" {
+            file_metadata::Type::App
+        } else {
+            file_metadata::Type::Unknown
+        }
+    }
+
+    /// Check if a cancellation request (of the transfer or of a file) was received.
+    fn check_for_cancellation(&mut self) -> Option<TransferAction> {
         match self.receiver.try_recv() {
             Ok(channel_msg) => {
                 if channel_msg.id == self.state.id
-                    && let channel::Message::Lib { action } = &channel_msg.msg
+                    && let channel::Message::Lib { action } = channel_msg.msg
                 {
-                    debug!("outbound: got: {channel_msg:?}");
-                    if action == &TransferAction::TransferCancel {
-                        return true;
-                    }
+                    debug!("outbound: got: {action:?}");
+                    return matches!(action, TransferAction::TransferCancel | TransferAction::CancelPayload(_))
+                        .then_some(action);
                 }
-                false
+                None
             }
-            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Empty) => None,
             Err(e) => {
                 error!("outbound: channel error: {e}");
-                false
+                None
             }
         }
     }
 
+    /// Stop sending one file at the user's request, the rest of the transfer carries on.
+    async fn cancel_payload(&mut self, payload_id: i64) -> Result<(), anyhow::Error> {
+        if self.state.state != TransferState::SendingFiles {
+            warn!("Can't cancel payload {payload_id} in state {:?}", self.state.state);
+            return Ok(());
+        }
+        if self.state.transferred_files.get(&payload_id).is_none_or(|f| f.bytes_transferred >= f.total_size) {
+            warn!("Can't cancel payload {payload_id}: not a file still being sent");
+            return Ok(());
+        }
+
+        info!("Cancelling payload {payload_id}");
+        self.encrypt_and_send(&control_frame(payload_id, control_message::EventType::PayloadCanceled))
+            .await?;
+
+        self.drop_payload(payload_id, FileOutcomeKind::Cancelled).await
+    }
+
     /// Send a single file chunk and update state.
     /// Returns Ok(true) if more chunks to send, Ok(false) if file complete or should break.
     #[allow(clippy::too_many_lines)]
@@ -878,7 +1088,7 @@ impl OutboundRequest {
                 return Ok(false);
             }
 
-            let mut file = match curr_state.file.as_ref() {
+            let file = match curr_state.file.as_ref() {
                 Some(f) => f,
                 None => {
                     warn!("File {file_id} is none");
@@ -886,16 +1096,21 @@ impl OutboundRequest {
                 }
             };
 
-            let mut buffer = vec![0u8; 512 * 1024];
-            let bytes_read = file.read(&mut buffer)?;
+            // Full chunks, the receiver counts them when resuming
+            let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+            let bytes_read = file.take(CHUNK_SIZE as u64).read_to_end(&mut buffer)?;
 
             Some((
                 InternalFileInfo {
                     payload_id: curr_state.payload_id,
+                    name: curr_state.name.clone(),
                     file_url: curr_state.file_url.clone(),
+                    part_url: None,
                     bytes_transferred: curr_state.bytes_transferred,
+                    chunks_transferred: curr_state.chunks_transferred,
                     total_size: curr_state.total_size,
                     file: None,
+                    sink: None,
                 },
                 buffer,
                 bytes_read,
@@ -932,7 +1147,7 @@ impl OutboundRequest {
                     payload_chunk: Some(PayloadChunk {
                         offset: Some(curr_state.bytes_transferred),
                         flags: Some(0),
-                        body: Some(buffer[..bytes_read].to_vec()),
+                        body: Some(buffer),
                     }),
                     payload_header: Some(payload_header.clone()),
                     ..Default::default()
@@ -947,6 +1162,7 @@ impl OutboundRequest {
             |e| {
                 if let Some(mu) = e.transferred_files.get_mut(&file_id) {
                     mu.bytes_transferred += bytes_read_i64;
+                    mu.chunks_transferred += 1;
                 }
                 if let Some(tmd) = e.transfer_metadata.as_mut() {
                     tmd.ack_bytes += bytes_read as u64;
@@ -963,31 +1179,36 @@ impl OutboundRequest {
                 curr_state.total_size
             );
 
-            // Send final chunk marker
-            let final_wrapper = location_nearby_connections::OfflineFrame {
-                version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
-                v1: Some(location_nearby_connections::V1Frame {
-                    r#type: Some(location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into()),
-                    payload_transfer: Some(PayloadTransferFrame {
-                        packet_type: Some(PacketType::Data.into()),
-                        payload_chunk: Some(PayloadChunk {
-                            offset: Some(curr_state.total_size),
-                            flags: Some(1), // lastChunk
-                            body: Some(vec![]),
-                        }),
-                        payload_header: Some(payload_header),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                }),
-            };
-            self.encrypt_and_send(&final_wrapper).await?;
+            self.send_last_chunk(payload_header).await?;
             return Ok(false);
         }
 
         Ok(true)
     }
 
+    /// Send the final chunk marker of a file.
+    async fn send_last_chunk(&mut self, payload_header: PayloadHeader) -> Result<(), anyhow::Error> {
+        let final_wrapper = location_nearby_connections::OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(location_nearby_connections::V1Frame {
+                r#type: Some(location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into()),
+                payload_transfer: Some(PayloadTransferFrame {
+                    packet_type: Some(PacketType::Data.into()),
+                    payload_chunk: Some(PayloadChunk {
+                        offset: Some(payload_header.total_size()),
+                        flags: Some(1), // lastChunk
+                        body: Some(vec![]),
+                    }),
+                    payload_header: Some(payload_header),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        };
+
+        self.encrypt_and_send(&final_wrapper).await
+    }
+
     /// Send all accepted files. Returns true if completed, false if cancelled.
     async fn send_accepted_files(&mut self) -> Result<bool, anyhow::Error> {
         let ids: Vec<i64> = self.state.transferred_files.keys().copied().collect();
@@ -998,11 +1219,20 @@ impl OutboundRequest {
 
         for file_id in ids {
             loop {
+                // The receiver may have dropped this file (or cancelled everything) meanwhile
+                self.process_pending_frames().await?;
+
                 // Check for cancellation before each chunk
-                if self.check_for_cancellation() {
-                    self.update_state(|e| { e.state = TransferState::Cancelled; }, true).await;
-                    self.disconnection().await?;
-                    return Ok(false);
+                match self.check_for_cancellation() {
+                    Some(TransferAction::TransferCancel) => {
+                        self.update_state(|e| { e.state = TransferState::Cancelled; }, true).await;
+                        self.disconnection().await?;
+                        return Ok(false);
+                    }
+                    Some(TransferAction::CancelPayload(payload_id)) => {
+                        self.cancel_payload(payload_id).await?;
+                    }
+                    _ => {}
                 }
 
                 if !self.send_file_chunk(file_id).await? {
@@ -1033,6 +1263,85 @@ impl OutboundRequest {
         Ok(true)
     }
 
+    /// Handle the frames the receiver sent while we're streaming, without waiting for any.
+    async fn process_pending_frames(&mut self) -> Result<(), anyhow::Error> {
+        if !self.upgrade.reads_current() {
+            return Ok(());
+        }
+
+        loop {
+            let mut peek_buf = [0u8; 1];
+            let mut peek_buf = ReadBuf::new(&mut peek_buf);
+            let pending = std::future::poll_fn(|cx| match self.socket.poll_peek(cx, &mut peek_buf) {
+                Poll::Ready(r) => Poll::Ready(Some(r)),
+                Poll::Pending => Poll::Ready(None),
+            })
+            .await;
+
+            match pending {
+                None => return Ok(()),
+                Some(Ok(0)) => return Err(anyhow!("Connection closed by the receiver")),
+                Some(Ok(_)) => {
+                    // The rest of the frame follows shortly
+                    let length_buf = self.length_reader.read(&mut self.socket).await?;
+                    self.state.keep_alive.last_received = Instant::now();
+                    Box::pin(self._handle(length_buf)).await?;
+                }
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Stop sending a file that won't be received, the rest of the transfer carries on.
+    async fn drop_payload(&mut self, payload_id: i64, kind: FileOutcomeKind) -> Result<(), anyhow::Error> {
+        self.state.pending_payload_acks.remove(&payload_id);
+        self.state.awaiting_resume.remove(&payload_id);
+
+        // Removing it is what ends its send loop
+        if let Some(file_info) = self.state.transferred_files.remove(&payload_id) {
+            // What's left of it won't be sent, the progress is on the files still to be sent
+            let remaining = u64::try_from(file_info.total_size - file_info.bytes_transferred).unwrap_or_default();
+            let outcome = FileOutcome {
+                payload_id,
+                name: file_info.name,
+                kind,
+            };
+            self.update_state(
+                |e| {
+                    if let Some(tmd) = e.transfer_metadata.as_mut() {
+                        tmd.total_bytes = tmd.total_bytes.saturating_sub(remaining);
+                        tmd.file_outcomes.push(outcome);
+                    }
+                },
+                true,
+            )
+            .await;
+        }
+
+        if self.state.state == TransferState::Reconnecting {
+            // Goes back to send_accepted_files, which may have called us
+            return Box::pin(self.finish_resume_if_ready()).await;
+        }
+        self.finish_if_all_acked().await
+    }
+
+    /// Once every file sent was acknowledged (or dropped), request the disconnection.
+    async fn finish_if_all_acked(&mut self) -> Result<(), anyhow::Error> {
+        if self.state.state != TransferState::WaitingForPayloadAck
+            || !self.state.pending_payload_acks.is_empty()
+        {
+            return Ok(());
+        }
+
+        info!("All payload ACKs received, requesting safe disconnect");
+        self.update_state(|e| {
+            e.state = TransferState::WaitingForDisconnectAck;
+            // Reset timer for disconnect phase
+            e.ack_wait_started = Some(std::time::Instant::now());
+        }, false).await;
+        self.request_disconnection().await
+    }
+
     /// Request safe disconnection from the receiver (sends request_safe_to_disconnect: true)
     async fn request_disconnection(&mut self) -> Result<(), anyhow::Error> {
         debug!("Sending request_safe_to_disconnect");
@@ -1060,6 +1369,262 @@ impl OutboundRequest {
         Ok(())
     }
 
+    async fn process_upgrade_event(
+        &mut self,
+        event: Result<UpgradeEvent, anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        match event {
+            Ok(UpgradeEvent::PriorFrame(length_buf)) => {
+                self.state.keep_alive.last_received = Instant::now();
+                let Upgrade::Migrating { prior, .. } = &mut self.upgrade else {
+                    return Ok(());
+                };
+                let frame_data = read_frame_body(prior, length_buf).await?;
+                let smsg = SecureMessage::decode(&*frame_data)?;
+                self.decrypt_and_process_secure_message(&smsg).await?;
+                self.release_held_frame().await?;
+            }
+            // We never offer upgrades
+            Ok(UpgradeEvent::Connected(_)) => {
+                self.upgrade = Upgrade::None;
+            }
+            Err(e) => {
+                // The transfer carries on over the current socket
+                debug!("Bandwidth upgrade socket closed: {e}");
+                if self.upgrade.is_migrating() {
+                    self.upgrade.prior_done();
+                    self.release_held_frame().await?;
+                } else {
+                    self.upgrade = Upgrade::None;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Process the frame held back for the one still on the prior socket, once it's its turn.
+    async fn release_held_frame(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(smsg) = self.upgrade.take_held(self.state.client_seq + 1) {
+            Box::pin(self.decrypt_and_process_secure_message(&smsg)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Connect to the socket offered by the receiver and introduce ourselves.
+    async fn accept_upgrade(&mut self, info: &UpgradePathInfo) -> Result<(), anyhow::Error> {
+        let mut addr = wifi_lan_addr(info)?;
+        // The frame carries no scope id, a link-local offer is on the interface we're already using
+        if let (SocketAddr::V6(offered), SocketAddr::V6(current)) = (&mut addr, self.socket.peer_addr()?)
+            && offered.ip().is_unicast_link_local()
+        {
+            offered.set_scope_id(current.scope_id());
+        }
+        // Don't let the receiver point us anywhere else
+        let ip = addr.ip().to_canonical();
+        if ip != self.socket.peer_addr()?.ip().to_canonical() && !on_local_network(&ip) {
+            return Err(anyhow!("{addr} is neither the receiver's address nor on a local network"));
+        }
+        info!("Upgrading the connection to {addr}");
+
+        let mut socket = tokio::time::timeout(BWU_HANDSHAKE_TIMEOUT, TcpStream::connect(addr)).await??;
+        let endpoint_id = String::from_utf8_lossy(&self.endpoint_id).to_string();
+        write_frame(&mut socket, &client_introduction_frame(&endpoint_id).encode_to_vec()).await?;
+
+        if info.supports_client_introduction_ack() {
+            let frame_data = tokio::time::timeout(BWU_HANDSHAKE_TIMEOUT, read_frame(&mut socket)).await??;
+            let frame = OfflineFrame::decode(&*frame_data)?;
+            if bwu_event(&frame) != Some(EventType::ClientIntroductionAck) {
+                return Err(anyhow!("expected a CLIENT_INTRODUCTION_ACK"));
+            }
+        }
+
+        self.migrate_to(socket).await
+    }
+
+    /// Last write on the current socket, from now on everything goes to the new one.
+    async fn migrate_to(&mut self, socket: TcpStream) -> Result<(), anyhow::Error> {
+        if let Err(e) = socket.set_nodelay(true) {
+            warn!("Failed to set TCP_NODELAY: {e}");
+        }
+
+        self.encrypt_and_send(&bwu_event_frame(EventType::LastWriteToPriorChannel)).await?;
+        info!("Connection upgraded to {:?}", socket.peer_addr());
+
+        let prior = std::mem::replace(&mut self.socket, socket);
+        self.upgrade = Upgrade::migrating(prior, std::mem::take(&mut self.length_reader));
+        self.update_state(|e| { e.upgraded = true; }, false).await;
+
+        Ok(())
+    }
+
+    async fn process_bandwidth_upgrade(
+        &mut self,
+        bwu: &location_nearby_connections::BandwidthUpgradeNegotiationFrame,
+    ) -> Result<(), anyhow::Error> {
+        match bwu.event_type() {
+            EventType::UpgradePathAvailable => {
+                let info = bwu.upgrade_path_info.clone().unwrap_or_default();
+                if !upgrade_enabled() {
+                    debug!("Declining an upgrade to {:?}, bandwidth upgrades are disabled", info.medium());
+                    self.encrypt_and_send(&upgrade_failure_frame(info.medium())).await?;
+                } else if let Err(e) = self.accept_upgrade(&info).await {
+                    warn!("Bandwidth upgrade to {:?} failed: {e}", info.medium());
+                    self.encrypt_and_send(&upgrade_failure_frame(info.medium())).await?;
+                }
+            }
+            EventType::LastWriteToPriorChannel => {
+                debug!("Peer is done with the prior socket");
+                let frame = self.encrypt_frame(&bwu_event_frame(EventType::SafeToClosePriorChannel)).await?;
+                if let Upgrade::Migrating { prior, .. } = &mut self.upgrade
+                    && let Err(e) = write_frame(prior, &frame).await
+                {
+                    // The peer may already have closed it
+                    debug!("Failed to send SAFE_TO_CLOSE_PRIOR_CHANNEL: {e}");
+                }
+                self.upgrade.prior_drained();
+            }
+            EventType::SafeToClosePriorChannel => {
+                debug!("Peer closed the prior socket");
+                self.upgrade.prior_done();
+            }
+            EventType::UpgradeFailure
+            | EventType::ClientIntroduction
+            | EventType::ClientIntroductionAck
+            | EventType::UnknownEventType => {
+                warn!("Unexpected bandwidth upgrade event: {:?}", bwu.event_type());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The receiver took the transfer over, ask where to continue each file it doesn't have yet.
+    async fn process_auto_reconnect(&mut self, frame: &AutoReconnectFrame) -> Result<(), anyhow::Error> {
+        if frame.event_type() != auto_reconnect_frame::EventType::ClientIntroductionAck
+            || self.state.state != TransferState::Reconnecting
+        {
+            warn!("Unexpected auto-reconnect event: {:?}", frame.event_type());
+            return Ok(());
+        }
+
+        let pending = self.state.pending_payload_acks.clone();
+        info!("Receiver resumed the transfer, {} payload(s) pending", pending.len());
+        self.state.transferred_files.retain(|id, _| pending.contains(id));
+        self.state.awaiting_resume.clone_from(&pending);
+
+        for payload_id in pending {
+            self.encrypt_and_send(&resume_frame(
+                auto_resume_frame::EventType::PayloadResumeTransferStart,
+                payload_id,
+                0,
+            ))
+            .await?;
+        }
+
+        self.finish_resume_if_ready().await
+    }
+
+    /// Rewind a file to the chunk the receiver needs next.
+    async fn process_auto_resume(&mut self, frame: &AutoResumeFrame) -> Result<(), anyhow::Error> {
+        let payload_id = frame.pending_payload_id();
+        if frame.event_type() != auto_resume_frame::EventType::PayloadResumeTransferAck
+            || !self.state.awaiting_resume.contains(&payload_id)
+        {
+            warn!("Unexpected auto-resume event for payload {payload_id}: {:?}", frame.event_type());
+            return Ok(());
+        }
+
+        let file = self
+            .state
+            .transferred_files
+            .get_mut(&payload_id)
+            .ok_or_else(|| anyhow!("File payload ID ({payload_id}) is not known"))?;
+        let offset = chunk_offset(frame.next_payload_chunk_index(), file.total_size);
+        if offset > file.bytes_transferred {
+            return Err(anyhow!(
+                "Receiver asks to resume {payload_id} at {offset}, only {} bytes were sent",
+                file.bytes_transferred
+            ));
+        }
+
+        info!("Resuming payload {payload_id} at offset {offset}");
+        if let Some(f) = file.file.as_mut() {
+            f.seek(SeekFrom::Start(u64::try_from(offset)?))?;
+        }
+        let rewound = u64::try_from(file.bytes_transferred - offset).unwrap_or_default();
+        file.bytes_transferred = offset;
+        file.chunks_transferred = frame.next_payload_chunk_index();
+        let payload_header = PayloadHeader {
+            id: Some(payload_id),
+            r#type: Some(payload_header::PayloadType::File.into()),
+            total_size: Some(file.total_size),
+            is_sensitive: Some(false),
+            file_name: file.file_url.file_name().map(|n| n.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let complete = offset == file.total_size;
+
+        self.update_state(
+            |e| {
+                e.awaiting_resume.remove(&payload_id);
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.ack_bytes = tmd.ack_bytes.saturating_sub(rewound);
+                }
+            },
+            false,
+        )
+        .await;
+
+        // All the data made it, only the final marker got lost
+        if complete {
+            self.send_last_chunk(payload_header).await?;
+        }
+
+        self.finish_resume_if_ready().await
+    }
+
+    /// Go back to sending once every pending file has its resume point.
+    async fn finish_resume_if_ready(&mut self) -> Result<(), anyhow::Error> {
+        if !self.state.awaiting_resume.is_empty() {
+            return Ok(());
+        }
+
+        info!("Resuming transfer {}", self.state.id);
+        self.update_state(
+            |e| {
+                e.state = TransferState::SendingFiles;
+                e.ack_wait_started = None;
+            },
+            true,
+        )
+        .await;
+        self.send_accepted_files().await?;
+
+        Ok(())
+    }
+
+    /// We don't upgrade to any other medium, answer with an empty list.
+    async fn send_bandwidth_upgrade_retry_reply(&mut self) -> Result<(), anyhow::Error> {
+        debug!("Answering BANDWIDTH_UPGRADE_RETRY");
+        let frame = location_nearby_connections::OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(location_nearby_connections::V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeRetry.into(),
+                ),
+                bandwidth_upgrade_retry: Some(location_nearby_connections::BandwidthUpgradeRetryFrame {
+                    supported_medium: Vec::new(),
+                    is_request: Some(false),
+                }),
+                ..Default::default()
+            }),
+        };
+
+        self.encrypt_and_send(&frame).await
+    }
+
     /// Send disconnect acknowledgment (sends ack_safe_to_disconnect: true)
     async fn send_disconnect_ack(&mut self) -> Result<(), anyhow::Error> {
         debug!("Sending ack_safe_to_disconnect");
@@ -1103,18 +1668,18 @@ impl OutboundRequest {
                 self.update_state(|e| { e.state = TransferState::SendingFiles; }, true).await;
                 self.send_accepted_files().await?;
             }
-            sharing_nearby::connection_response_frame::Status::Reject
-            | sharing_nearby::connection_response_frame::Status::NotEnoughSpace
-            | sharing_nearby::connection_response_frame::Status::UnsupportedAttachmentType
-            | sharing_nearby::connection_response_frame::Status::TimedOut => {
-                warn!("Cannot process: consent denied: {:?}", connection_response.status());
-                self.update_state(|e| { e.state = TransferState::Disconnected; }, true).await;
-                self.disconnection().await?;
-                return Err(anyhow!(crate::errors::AppError::NotAnError));
-            }
-            sharing_nearby::connection_response_frame::Status::Unknown => {
-                error!("Unknown consent type: aborting");
-                self.update_state(|e| { e.state = TransferState::Disconnected; }, true).await;
+            status => {
+                let reason = RejectionReason::from_status(status);
+                warn!("Cannot process: consent denied: {status:?}");
+                self.update_state(
+                    |e| {
+                        e.state = TransferState::Rejected;
+                        if let Some(tmd) = e.transfer_metadata.as_mut() {
+                            tmd.rejection = Some(reason);
+                        }
+                    },
+                    true,
+                ).await;
                 self.disconnection().await?;
                 return Err(anyhow!(crate::errors::AppError::NotAnError));
             }
@@ -1225,6 +1790,7 @@ impl OutboundRequest {
                 if let Some(ref mut tm) = e.transfer_metadata {
                     tm.pin_code = Some(to_four_digit_string(&auth_string));
                 }
+                e.auth_token = Some(auth_string);
             },
             true,
         )
@@ -1319,7 +1885,12 @@ impl OutboundRequest {
         Ok(())
     }
 
-    async fn encrypt_and_send(&mut self, frame: &OfflineFrame) -> Result<(), anyhow::Error> {
+    pub(super) async fn encrypt_and_send(&mut self, frame: &OfflineFrame) -> Result<(), anyhow::Error> {
+        let data = self.encrypt_frame(frame).await?;
+        self.send_frame(data).await
+    }
+
+    pub(super) async fn encrypt_frame(&mut self, frame: &OfflineFrame) -> Result<Vec<u8>, anyhow::Error> {
         let d2d_msg = DeviceToDeviceMessage {
             sequence_number: Some(self.get_server_seq_inc().await),
             message: Some(frame.encode_to_vec()),
@@ -1371,9 +1942,7 @@ impl OutboundRequest {
             signature: result.into_bytes().to_vec(),
         };
 
-        self.send_frame(smsg.encode_to_vec()).await?;
-
-        Ok(())
+        Ok(smsg.encode_to_vec())
     }
 
     async fn send_keepalive(&mut self, ack: bool) -> Result<(), anyhow::Error> {
@@ -1386,6 +1955,7 @@ impl OutboundRequest {
             }),
         };
 
+        self.state.keep_alive.last_sent = Instant::now();
         if self.state.encryption_done {
             self.encrypt_and_send(&ack_frame).await
         } else {
@@ -1400,8 +1970,14 @@ impl OutboundRequest {
         prefixed_length.extend_from_slice(&length.to_be_bytes());
         prefixed_length.extend_from_slice(&data);
 
-        self.socket.write_all(&prefixed_length).await?;
-        self.socket.flush().await?;
+        // A peer that vanished (Wi-Fi roaming, ...) would otherwise block us until the OS gives up
+        let timeout = self.state.keep_alive.timeout;
+        tokio::time::timeout(timeout, async {
+            self.socket.write_all(&prefixed_length).await?;
+            self.socket.flush().await
+        })
+        .await
+        .map_err(|_| anyhow!("No progress writing to the socket for {}s", timeout.as_secs()))??;
 
         Ok(())
     }
@@ -1442,11 +2018,11 @@ impl OutboundRequest {
 
         drop(self.sender.send(ChannelMessage {
             id: self.state.id.clone(),
-            msg: channel::Message::Client(MessageClient {
+            msg: channel::Message::Client(Box::new(MessageClient {
                 kind: TransferKind::Outbound,
                 state: Some(self.state.state.clone()),
                 metadata: self.state.transfer_metadata.clone(),
-            }),
+            })),
         }));
         // Add a small sleep timer to allow the Tokio runtime to have
         // some spare time to process channel's message. Otherwise it
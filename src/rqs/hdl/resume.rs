@@ -0,0 +1,459 @@
+//! Auto-reconnect and auto-resume of transfers interrupted by a dropped connection.
+//!
+//! 1. receiver: the connection drops while receiving files, the transfer is parked
+//!    (partial files kept) for `RESUME_WINDOW`, keyed by the sender's endpoint id
+//! 2. sender: reconnects, goes through the connection request and UKEY2 again, sends its
+//!    paired key encryption, then an `AutoReconnectFrame` `CLIENT_INTRODUCTION` instead of
+//!    the introduction
+//! 3. receiver: takes the parked transfer over (same transfer id, no new consent) if the
+//!    paired key encryption was made with the certificate the sender proved owning on the
+//!    first connection, and answers `CLIENT_INTRODUCTION_ACK`
+//! 4. sender: `PAYLOAD_RESUME_TRANSFER_START` for every file not acknowledged yet
+//! 5. receiver: `PAYLOAD_RESUME_TRANSFER_ACK` with the index of the next chunk it needs
+//!    (or `PAYLOAD_RECEIVED_ACK` for a file it already has), the sender continues from there
+//!
+//! When the sender notices the drop first, the receiver may still hold the old connection:
+//! the new one supersedes it, which parks the transfer right away.
+//!
+//! A transfer from a sender that didn't prove owning a certificate is never parked: the
+//! endpoint id and the address alone don't tell the original sender from another device.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast::Sender;
+use tokio_util::sync::CancellationToken;
+
+use super::{InnerState, ResumableTransfer, TransferState};
+use crate::AUTO_RESUME;
+use crate::certs;
+use crate::channel::{self, ChannelMessage, MessageClient, TransferKind};
+use crate::history::{self, HistoryEntry};
+use crate::location_nearby_connections::auto_reconnect_frame;
+use crate::location_nearby_connections::auto_resume_frame;
+use crate::location_nearby_connections::{
+    AutoReconnectFrame, AutoResumeFrame, OfflineFrame, V1Frame, offline_frame, v1_frame,
+};
+use crate::sharing_nearby::{PairedKeyEncryptionFrame, PublicCertificate};
+
+/// How long the receiver keeps an interrupted transfer around
+pub const RESUME_WINDOW: Duration = Duration::from_secs(60);
+/// Pause before each reconnection attempt of the sender
+pub const RECONNECT_DELAYS: [Duration; 4] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+/// How long a reconnection attempt may take to connect
+pub const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the sender waits for the receiver to take the transfer over
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(15);
+/// Size of the chunks we send, the receiver's chunk index maps back to an offset with it
+pub const CHUNK_SIZE: usize = 512 * 1024;
+
+/// How long a reconnection waits for the connection it supersedes to be parked
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(5);
+const CLAIM_POLL: Duration = Duration::from_millis(100);
+const AUTO_RESUME_VERSION: i32 = 1;
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static TRANSFERS: LazyLock<Mutex<HashMap<String, Slot>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Resumable transfers of the receiver, by endpoint id of the sender.
+#[derive(Debug)]
+enum Slot {
+    /// Still connected, cancelling the token makes the connection give up (and be parked)
+    Active {
+        generation: u64,
+        peer_ip: IpAddr,
+        certificate: Box<PublicCertificate>,
+        token: CancellationToken,
+    },
+    Parked { generation: u64, peer_ip: IpAddr, transfer: Box<ResumableTransfer> },
+}
+
+/// Registration of a connection receiving files. Dropping it unregisters the connection.
+#[derive(Debug)]
+pub struct Registration {
+    endpoint_id: String,
+    generation: u64,
+    token: CancellationToken,
+}
+
+impl Registration {
+    /// Resolves once a reconnection of the same sender took over.
+    pub async fn superseded(&self) {
+        self.token.cancelled().await;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut transfers) = TRANSFERS.lock()
+            && matches!(
+                transfers.get(&self.endpoint_id),
+                Some(Slot::Active { generation, .. }) if *generation == self.generation
+            )
+        {
+            transfers.remove(&self.endpoint_id);
+        }
+    }
+}
+
+/// Never resolves without a registration.
+pub async fn superseded(registration: Option<&Registration>) {
+    match registration {
+        Some(registration) => registration.superseded().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A parked transfer, to expire if nobody claims it in time.
+#[derive(Debug)]
+pub struct ParkTicket {
+    endpoint_id: String,
+    generation: u64,
+}
+
+pub fn auto_resume_enabled() -> bool {
+    AUTO_RESUME.read().map(|g| *g).unwrap_or(true)
+}
+
+fn next_generation() -> u64 {
+    GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Register a connection that started receiving files from the owner of `certificate`.
+pub fn register(
+    endpoint_id: &str,
+    peer_ip: IpAddr,
+    certificate: Option<&PublicCertificate>,
+) -> Option<Registration> {
+    if !auto_resume_enabled() {
+        return None;
+    }
+    let Some(certificate) = certificate else {
+        debug!("{endpoint_id} didn't prove owning a certificate, its transfer can't be resumed");
+        return None;
+    };
+
+    let generation = next_generation();
+    let token = CancellationToken::new();
+    let mut transfers = TRANSFERS.lock().ok()?;
+    transfers.insert(
+        endpoint_id.to_owned(),
+        Slot::Active {
+            generation,
+            peer_ip,
+            certificate: Box::new(certificate.clone()),
+            token: token.clone(),
+        },
+    );
+
+    Some(Registration {
+        endpoint_id: endpoint_id.to_owned(),
+        generation,
+        token,
+    })
+}
+
+/// Keep the transfer of a connection that dropped while receiving files.
+pub fn park(state: &mut InnerState, peer_ip: IpAddr) -> Option<ParkTicket> {
+    if !auto_resume_enabled()
+        || state.state != TransferState::ReceivingFiles
+        || state.transferred_files.is_empty()
+        || state.peer_certificate.is_none()
+    {
+        return None;
+    }
+    let endpoint_id = state.remote_endpoint_id.clone()?;
+
+    let generation = next_generation();
+    let mut transfers = TRANSFERS.lock().ok()?;
+    info!("Parking transfer {} from {endpoint_id} for {}s", state.id, RESUME_WINDOW.as_secs());
+    transfers.insert(
+        endpoint_id.clone(),
+        Slot::Parked {
+            generation,
+            peer_ip,
+            transfer: Box::new(state.take_resumable()),
+        },
+    );
+
+    Some(ParkTicket { endpoint_id, generation })
+}
+
+/// The sender lost the connection in the middle of the files.
+pub fn can_reconnect(state: &InnerState) -> bool {
+    auto_resume_enabled()
+        && !state.pending_payload_acks.is_empty()
+        && match state.state {
+            TransferState::SendingFiles | TransferState::WaitingForPayloadAck | TransferState::Reconnecting => true,
+            // A reconnection that failed before the receiver took the transfer over
+            TransferState::Initial | TransferState::SentUkeyClientInit | TransferState::SentUkeyClientFinish => {
+                state.resuming
+            }
+            _ => false,
+        }
+}
+
+/// Take over the transfer parked for this sender.
+/// A connection still registered for it is told to give up first.
+/// Either way, only a reconnection from the sender's address, whose paired key encryption
+/// `proof` was made with the sender's certificate, is let in.
+pub async fn claim(
+    endpoint_id: &str,
+    peer_ip: IpAddr,
+    proof: &PairedKeyEncryptionFrame,
+    auth_token: &[u8],
+) -> Option<ResumableTransfer> {
+    let proves = |certificate: &PublicCertificate| certs::proves(certificate, proof, auth_token);
+    let deadline = tokio::time::Instant::now() + CLAIM_TIMEOUT;
+
+    loop {
+        {
+            let mut transfers = TRANSFERS.lock().ok()?;
+            match transfers.get(endpoint_id) {
+                Some(Slot::Parked { peer_ip: ip, transfer, .. })
+                    if *ip == peer_ip && transfer.peer_certificate.as_ref().is_some_and(proves) =>
+                {
+                    let Some(Slot::Parked { transfer, .. }) = transfers.remove(endpoint_id) else {
+                        return None;
+                    };
+                    return Some(*transfer);
+                }
+                Some(Slot::Active { peer_ip: ip, certificate, token, .. })
+                    if *ip == peer_ip && proves(certificate) =>
+                {
+                    token.cancel();
+                }
+                Some(_) => {
+                    warn!(
+                        "Reconnection of {endpoint_id} from another address or device, not resuming"
+                    );
+                    return None;
+                }
+                None => return None,
+            }
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            return None;
+        }
+        tokio::time::sleep(CLAIM_POLL).await;
+    }
+}
+
+/// Give up on a parked transfer once `RESUME_WINDOW` is over: partial files are removed,
+/// the client is told and the transfer recorded.
+pub async fn expire_after(ticket: ParkTicket, sender: Sender<ChannelMessage>) {
+    tokio::time::sleep(RESUME_WINDOW).await;
+
+    let transfer = {
+        let Ok(mut transfers) = TRANSFERS.lock() else {
+            return;
+        };
+        match transfers.get(&ticket.endpoint_id) {
+            Some(Slot::Parked { generation, .. }) if *generation == ticket.generation => {}
+            _ => return,
+        }
+        match transfers.remove(&ticket.endpoint_id) {
+            Some(Slot::Parked { transfer, .. }) => *transfer,
+            _ => return,
+        }
+    };
+
+    let mut state = InnerState::new(String::new(), None);
+    state.resume(transfer);
+    state.state = TransferState::ReceivingFiles;
+    info!("Transfer {} wasn't resumed in time", state.id);
+
+    drop(sender.send(ChannelMessage {
+        id: state.id.clone(),
+        msg: channel::Message::Client(Box::new(MessageClient {
+            kind: TransferKind::Inbound,
+            state: Some(TransferState::Disconnected),
+            metadata: state.transfer_metadata.clone(),
+        })),
+    }));
+    history::record(&HistoryEntry::from_state(
+        TransferKind::Inbound,
+        &state,
+        Some(format!("not resumed within {}s", RESUME_WINDOW.as_secs())),
+    ));
+    // Dropping the state aborts the partial files
+}
+
+/// Offset of the chunk the receiver asks for.
+pub fn chunk_offset(next_chunk_index: i32, total_size: i64) -> i64 {
+    let chunk_size = i64::try_from(CHUNK_SIZE).unwrap_or(i64::MAX);
+    i64::from(next_chunk_index.max(0))
+        .saturating_mul(chunk_size)
+        .min(total_size)
+}
+
+pub fn reconnect_frame(endpoint_id: &str, event: auto_reconnect_frame::EventType) -> OfflineFrame {
+    OfflineFrame {
+        version: Some(offline_frame::Version::V1.into()),
+        v1: Some(V1Frame {
+            r#type: Some(v1_frame::FrameType::AutoReconnect.into()),
+            auto_reconnect: Some(AutoReconnectFrame {
+                endpoint_id: Some(endpoint_id.to_owned()),
+                event_type: Some(event.into()),
+                // Endpoint ids don't change across reconnections
+                last_endpoint_id: Some(endpoint_id.to_owned()),
+            }),
+            ..Default::default()
+        }),
+    }
+}
+
+pub fn resume_frame(
+    event: auto_resume_frame::EventType,
+    payload_id: i64,
+    next_chunk_index: i32,
+) -> OfflineFrame {
+    OfflineFrame {
+        version: Some(offline_frame::Version::V1.into()),
+        v1: Some(V1Frame {
+            r#type: Some(v1_frame::FrameType::AutoResume.into()),
+            auto_resume: Some(AutoResumeFrame {
+                event_type: Some(event.into()),
+                pending_payload_id: Some(payload_id),
+                next_payload_chunk_index: Some(next_chunk_index),
+                version: Some(AUTO_RESUME_VERSION),
+            }),
+            ..Default::default()
+        }),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::hdl::info::InternalFileInfo;
+    use crate::utils::gen_random;
+
+    /// Our own certificate stands for the sender's, with its proof for a new connection.
+    fn certificate() -> PublicCertificate {
+        certs::certificate_info().unwrap().public_certificate.remove(0)
+    }
+
+    fn proof() -> (PairedKeyEncryptionFrame, Vec<u8>) {
+        let auth_token = gen_random(32);
+        (certs::paired_key_encryption(&auth_token), auth_token)
+    }
+
+    /// A device with its own certificate, which the receiver never saw.
+    fn proof_of_another_device() -> (PairedKeyEncryptionFrame, Vec<u8>) {
+        let frame = PairedKeyEncryptionFrame {
+            secret_id_hash: Some(gen_random(6)),
+            signed_data: Some(gen_random(72)),
+            ..Default::default()
+        };
+        (frame, gen_random(32))
+    }
+
+    fn receiving_state(endpoint_id: &str) -> InnerState {
+        let mut state = InnerState::new("transfer".into(), None);
+        state.state = TransferState::ReceivingFiles;
+        state.remote_endpoint_id = Some(endpoint_id.into());
+        state.peer_certificate = Some(certificate());
+        state.transferred_files.insert(
+            1,
+            InternalFileInfo {
+                payload_id: 1,
+                name: "video.mp4".into(),
+                file_url: "video.mp4".into(),
+                part_url: None,
+                bytes_transferred: 42,
+                chunks_transferred: 1,
+                total_size: 100,
+                file: None,
+                sink: None,
+            },
+        );
+        state
+    }
+
+    #[tokio::test]
+    async fn test_park_and_claim() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        let mut state = receiving_state("PARK");
+        assert!(park(&mut state, ip).is_some());
+        assert!(state.transferred_files.is_empty());
+        let (proof, auth_token) = proof();
+
+        // Only the address the transfer came from can take it over
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(claim("PARK", localhost, &proof, &auth_token).await.is_none());
+        // And only the device that proved owning the certificate, on this connection
+        let (other_proof, other_auth_token) = proof_of_another_device();
+        assert!(claim("PARK", ip, &other_proof, &other_auth_token).await.is_none());
+        assert!(claim("PARK", ip, &proof, &gen_random(32)).await.is_none());
+
+        let transfer = claim("PARK", ip, &proof, &auth_token).await.unwrap();
+        assert_eq!(transfer.id, "transfer");
+        assert_eq!(transfer.transferred_files[&1].bytes_transferred, 42);
+        assert!(claim("PARK", ip, &proof, &auth_token).await.is_none());
+    }
+
+    #[test]
+    fn test_no_park_without_certificate() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 6));
+        let mut state = receiving_state("NOCR");
+        state.peer_certificate = None;
+        assert!(park(&mut state, ip).is_none());
+        assert!(register("NOCR", ip, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_claim_supersedes_active_connection() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 3));
+        let registration = register("SUPR", ip, Some(&certificate())).unwrap();
+        let (proof, auth_token) = proof();
+
+        let old_connection = async {
+            registration.superseded().await;
+            let mut state = receiving_state("SUPR");
+            park(&mut state, ip)
+        };
+        let (ticket, transfer) =
+            tokio::join!(old_connection, claim("SUPR", ip, &proof, &auth_token));
+        assert!(ticket.is_some());
+        assert!(transfer.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_claim_from_another_device_keeps_active_connection() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 4));
+        let registration = register("OTHR", ip, Some(&certificate())).unwrap();
+        let (proof, auth_token) = proof();
+        let (other_proof, other_auth_token) = proof_of_another_device();
+
+        let other_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5));
+        assert!(claim("OTHR", other_ip, &proof, &auth_token).await.is_none());
+        assert!(claim("OTHR", ip, &other_proof, &other_auth_token).await.is_none());
+        assert!(!registration.token.is_cancelled());
+        assert!(matches!(
+            TRANSFERS.lock().unwrap().get("OTHR"),
+            Some(Slot::Active { generation, .. }) if *generation == registration.generation
+        ));
+    }
+
+    #[test]
+    fn test_chunk_offset() {
+        let chunk = i64::try_from(CHUNK_SIZE).unwrap();
+        assert_eq!(chunk_offset(0, 10 * chunk), 0);
+        assert_eq!(chunk_offset(3, 10 * chunk), 3 * chunk);
+        assert_eq!(chunk_offset(12, 10 * chunk), 10 * chunk);
+        assert_eq!(chunk_offset(-1, 10 * chunk), 0);
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::channel::TransferKind;
+use crate::hdl::info::{FileOutcome, TransferPayloadKind};
+use crate::hdl::{InnerState, TransferState};
+use crate::utils::DeviceType;
+
+/// Serializes the writers, entries are appended from each transfer's task
+static HISTORY_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// A file announced in a transfer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryFile {
+    pub payload_id: i64,
+    pub name: String,
+    pub size: u64,
+    /// Source file for outbound transfers, final location for inbound ones
+    /// (None until received, or when delivered to a custom sink)
+    pub path: Option<PathBuf>,
+    /// Fully received, or acknowledged by the receiver
+    pub completed: bool,
+}
+
+/// One line of the history store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub direction: TransferKind,
+    pub peer_name: Option<String>,
+    pub device_type: Option<DeviceType>,
+    pub payload_kind: Option<TransferPayloadKind>,
+    pub files: Vec<HistoryFile>,
+    pub file_outcomes: Vec<FileOutcome>,
+    /// Unix timestamps, in seconds
+    pub started_at: u64,
+    pub ended_at: u64,
+    /// Finished, Cancelled, Rejected, TimedOut or Disconnected
+    pub outcome: TransferState,
+    pub failure_reason: Option<String>,
+}
+
+impl HistoryEntry {
+    /// Build the entry for a transfer whose connection just ended.
+    pub fn from_state(direction: TransferKind, state: &InnerState, error: Option<String>) -> Self {
+        let terminal = matches!(
+            state.state,
+            TransferState::Finished
+                | TransferState::Cancelled
+                | TransferState::Rejected
+                | TransferState::TimedOut
+                | TransferState::Disconnected
+        );
+        let metadata = state.transfer_metadata.as_ref();
+        let (outcome, failure_reason) = if terminal {
+            let rejection = metadata.and_then(|m| m.rejection.as_ref()).map(ToString::to_string);
+            (state.state.clone(), error.or(rejection))
+        } else {
+            (
+                TransferState::Disconnected,
+                Some(error.unwrap_or_else(|| format!("interrupted while {:?}", state.state))),
+            )
+        };
+        // Outbound transfers only know the peer from the metadata
+        let peer = state
+            .remote_device_info
+            .as_ref()
+            .or_else(|| metadata.and_then(|m| m.source.as_ref()));
+
+        Self {
+            id: state.id.clone(),
+            direction,
+            peer_name: peer.map(|r| r.name.clone()),
+            device_type: peer.map(|r| r.device_type.clone()),
+            payload_kind: metadata.map(|m| m.payload_kind.clone()),
+            files: state.history_files.clone(),
+            file_outcomes: metadata.map(|m| m.file_outcomes.clone()).unwrap_or_default(),
+            started_at: unix_secs(state.started_at),
+            ended_at: unix_secs(SystemTime::now()),
+            outcome,
+            failure_reason,
+        }
+    }
+
+    /// Case-insensitive match on the peer name, the file names and paths and the failure reason.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        let contains = |s: &str| s.to_lowercase().contains(&query);
+
+        self.peer_name.as_deref().is_some_and(contains)
+            || self.files.iter().any(|f| {
+                contains(&f.name)
+                    || f.path.as_ref().is_some_and(|p| contains(&p.to_string_lossy()))
+            })
+            || self.failure_reason.as_deref().is_some_and(contains)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn history_path() -> PathBuf {
+    directories::ProjectDirs::from("", "", "kvakk")
+        .map(|d| d.data_dir().join("history.jsonl"))
+        .unwrap_or_else(|| std::env::temp_dir().join("kvakk-history.jsonl"))
+}
+
+/// Append an entry to the store (one JSON object per line).
+pub fn append_entry(entry: &HistoryEntry) -> Result<(), anyhow::Error> {
+    let line = serde_json::to_string(entry)?;
+    let path = history_path();
+
+    let _guard = HISTORY_LOCK.lock().map_err(|_| anyhow::anyhow!("history lock poisoned"))?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{line}")?;
+
+    Ok(())
+}
+
+/// Record a transfer, failures are only logged.
+pub fn record(entry: &HistoryEntry) {
+    if let Err(e) = append_entry(entry) {
+        error!("Failed to record transfer {} in the history: {e}", entry.id);
+    }
+}
+
+/// All the entries, oldest first. Unreadable lines are skipped.
+pub fn list_entries() -> Result<Vec<HistoryEntry>, anyhow::Error> {
+    let _guard = HISTORY_LOCK.lock().map_err(|_| anyhow::anyhow!("history lock poisoned"))?;
+    let file = match std::fs::File::open(history_path()) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!("Skipping unreadable history entry: {e}"),
+        }
+    }
+
+    Ok(entries)
+}
+
+pub fn clear_entries() -> Result<(), anyhow::Error> {
+    let _guard = HISTORY_LOCK.lock().map_err(|_| anyhow::anyhow!("history lock poisoned"))?;
+    match std::fs::remove_file(history_path()) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_matches() {
+        let entry = HistoryEntry {
+            id: "1".into(),
+            direction: TransferKind::Inbound,
+            peer_name: Some("Pixel 8".into()),
+            device_type: Some(DeviceType::Phone),
+            payload_kind: Some(TransferPayloadKind::Files),
+            files: vec![HistoryFile {
+                payload_id: 1,
+                name: "Holiday.jpg".into(),
+                size: 42,
+                path: Some(PathBuf::from("/home/user/Downloads/Holiday.jpg")),
+                completed: true,
+            }],
+            file_outcomes: Vec::new(),
+            started_at: 0,
+            ended_at: 1,
+            outcome: TransferState::Finished,
+            failure_reason: None,
+        };
+
+        assert!(entry.matches("pixel"));
+        assert!(entry.matches("holiday"));
+        assert!(entry.matches("downloads"));
+        assert!(!entry.matches("laptop"));
+    }
+}
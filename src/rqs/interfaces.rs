@@ -0,0 +1,307 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use get_if_addrs::IfAddr;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Interval, MissedTickBehavior, interval};
+
+use crate::INTERFACE_FILTER;
+
+/// An address block like `172.20.0.0/16` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, anyhow::Error> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(anyhow!("prefix /{prefix} is too long for {addr}"));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u128::from(net.to_bits()), u128::from(ip.to_bits()), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (net.to_bits(), ip.to_bits(), 128),
+            _ => return false,
+        };
+        let host_bits = bits - u32::from(self.prefix);
+        // checked_shr: a /0 shifts everything out
+        net.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').ok_or_else(|| anyhow!("missing /prefix in {s:?}"))?;
+        Self::new(addr.parse()?, prefix.parse()?)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Which interfaces and addresses are advertised over mDNS and accept connections.
+///
+/// Names and address blocks are checked separately, an address is used when both pass.
+/// For each of them: anything matching an include is used, anything else is dropped
+/// if the include list isn't empty or if it matches an exclude.
+/// Names compare case-insensitively, a trailing `*` matches by prefix (`docker*`).
+/// Loopback is never advertised.
+///
+/// The listener is bound to every address (`[::]`), so addresses can come and go without
+/// rebinding: connections to an address the filter drops are accepted, then closed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InterfaceFilter {
+    pub include_names: Vec<String>,
+    pub exclude_names: Vec<String>,
+    pub include_cidrs: Vec<Cidr>,
+    pub exclude_cidrs: Vec<Cidr>,
+}
+
+impl Default for InterfaceFilter {
+    /// Skips container bridges, VPN tunnels, IPv4 link-local and the ranges used
+    /// by virtualization (Docker, WSL2, Hyper-V, ...) and Tailscale (CGNAT).
+    fn default() -> Self {
+        let cidr = |addr: [u8; 4], prefix| Cidr {
+            addr: IpAddr::from(addr),
+            prefix,
+        };
+
+        Self {
+            include_names: Vec::new(),
+            exclude_names: ["docker*", "br-*", "veth*", "virbr*", "tailscale*", "tun*", "tap*"]
+                .map(String::from)
+                .to_vec(),
+            include_cidrs: Vec::new(),
+            exclude_cidrs: vec![
+                cidr([169, 254, 0, 0], 16),
+                cidr([172, 16, 0, 0], 12),
+                cidr([100, 64, 0, 0], 10),
+            ],
+        }
+    }
+}
+
+impl InterfaceFilter {
+    pub fn allows(&self, name: &str, ip: &IpAddr) -> bool {
+        let name_matches = |pattern: &String| {
+            let (name, pattern) = (name.to_lowercase(), pattern.to_lowercase());
+            match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            }
+        };
+        let ip_matches = |cidr: &Cidr| cidr.contains(ip);
+
+        passes(&self.include_names, &self.exclude_names, name_matches)
+            && passes(&self.include_cidrs, &self.exclude_cidrs, ip_matches)
+    }
+
+    /// Whether connections made to this local address should be accepted.
+    /// Loopback always is, so local tools keep working.
+    pub fn allows_local(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if ip.is_loopback() {
+            return true;
+        }
+
+        get_if_addrs::get_if_addrs()
+            .map(|ifaces| {
+                ifaces
+                    .iter()
+                    .any(|iface| iface.ip() == ip && self.allows(&iface.name, &ip))
+            })
+            .unwrap_or(true)
+    }
+}
+
+fn passes<T>(include: &[T], exclude: &[T], matches: impl Fn(&T) -> bool) -> bool {
+    if include.iter().any(&matches) {
+        return true;
+    }
+
+    include.is_empty() && !exclude.iter().any(matches)
+}
+
+pub fn get_interface_filter() -> InterfaceFilter {
+    INTERFACE_FILTER.read().map(|g| g.clone()).unwrap_or_default()
+}
+
+/// How often addresses are checked for changes (suspend/resume, docking, switching Wi-Fi).
+/// Matches the interval at which the mDNS daemon rescans its own interfaces.
+pub const ADDRESS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The addresses to advertise, IPv4 and IPv6 (link-local ones included,
+/// peers connect to them with their own scope id). Sorted, so they can be compared.
+pub fn local_network_ips() -> Vec<IpAddr> {
+    let filter = get_interface_filter();
+
+    let mut ips: Vec<IpAddr> = get_if_addrs::get_if_addrs()
+        .map(|ifaces| {
+            ifaces
+                .into_iter()
+                .filter(|iface| !iface.is_loopback() && filter.allows(&iface.name, &iface.ip()))
+                .map(|iface| iface.ip())
+                .collect()
+        })
+        .unwrap_or_default();
+    ips.sort_unstable();
+    ips.dedup();
+    ips
+}
+
+/// Whether `ip` is on the network of one of the interfaces we use.
+pub fn on_local_network(ip: &IpAddr) -> bool {
+    let filter = get_interface_filter();
+
+    get_if_addrs::get_if_addrs()
+        .map(|ifaces| {
+            ifaces.iter().any(|iface| {
+                let (addr, prefix) = match &iface.addr {
+                    IfAddr::V4(v4) => (IpAddr::V4(v4.ip), v4.netmask.to_bits().count_ones()),
+                    IfAddr::V6(v6) => (IpAddr::V6(v6.ip), v6.netmask.to_bits().count_ones()),
+                };
+                !iface.is_loopback()
+                    && filter.allows(&iface.name, &addr)
+                    && u8::try_from(prefix)
+                        .ok()
+                        .and_then(|prefix| Cidr::new(addr, prefix).ok())
+                        .is_some_and(|net| net.contains(ip))
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Polls `local_network_ips` for changes.
+pub struct AddressWatcher {
+    ips: Vec<IpAddr>,
+    interval: Interval,
+}
+
+impl AddressWatcher {
+    pub fn new() -> Self {
+        let mut interval = interval(ADDRESS_POLL_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            ips: local_network_ips(),
+            interval,
+        }
+    }
+
+    /// Resolves with the new addresses once they differ from the last ones seen.
+    /// Cancel safe, so it can be used in a `select!`.
+    pub async fn changed(&mut self) -> Vec<IpAddr> {
+        loop {
+            self.interval.tick().await;
+            let ips = local_network_ips();
+            if ips != self.ips {
+                self.ips.clone_from(&ips);
+                return ips;
+            }
+        }
+    }
+}
+
+impl Default for AddressWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let lan: Cidr = "172.16.0.0/12".parse().unwrap();
+        assert!(lan.contains(&ip("172.20.1.2")));
+        assert!(lan.contains(&ip("::ffff:172.31.255.255")));
+        assert!(!lan.contains(&ip("172.32.0.1")));
+        assert!(!lan.contains(&ip("fd00::1")));
+
+        let ula: Cidr = "fd00::/8".parse().unwrap();
+        assert!(ula.contains(&ip("fd12::1")));
+        assert!(!ula.contains(&ip("fe80::1")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&ip("8.8.8.8")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_default_filter() {
+        let filter = InterfaceFilter::default();
+        assert!(filter.allows("wlan0", &ip("192.168.1.10")));
+        assert!(filter.allows("wlan0", &ip("fe80::1")));
+        assert!(!filter.allows("Docker0", &ip("192.168.1.10")));
+        assert!(!filter.allows("eth0", &ip("172.20.0.5")));
+        assert!(!filter.allows("eth0", &ip("100.100.1.1")));
+    }
+
+    #[test]
+    fn test_include_overrides_default_excludes() {
+        let filter = InterfaceFilter {
+            include_cidrs: vec!["172.20.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(filter.allows("eth0", &ip("172.20.0.5")));
+        assert!(!filter.allows("eth0", &ip("192.168.1.10")));
+        assert!(!filter.allows("docker0", &ip("172.20.0.5")));
+
+        let filter = InterfaceFilter {
+            include_names: vec!["eth*".into()],
+            exclude_cidrs: Vec::new(),
+            ..Default::default()
+        };
+        assert!(filter.allows("eth1", &ip("172.17.0.1")));
+        assert!(!filter.allows("wlan0", &ip("192.168.1.10")));
+    }
+
+    #[test]
+    fn test_on_local_network() {
+        for ip in local_network_ips() {
+            assert!(on_local_network(&ip));
+        }
+        // TEST-NET-3
+        assert!(!on_local_network(&ip("203.0.113.7")));
+        assert!(!on_local_network(&ip("127.0.0.1")));
+    }
+}
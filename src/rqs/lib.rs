@@ -2,15 +2,15 @@
 extern crate log;
 
 use std::path::PathBuf;
-use std::sync::{LazyLock, RwLock};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
 
 use anyhow::anyhow;
 use channel::ChannelMessage;
 #[cfg(target_os = "linux")]
 use hdl::BleAdvertiser;
 use hdl::MDnsDiscovery;
-use tokio::net::TcpListener;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 
@@ -18,14 +18,28 @@ use crate::hdl::BleListener;
 use crate::hdl::MDnsServer;
 use crate::manager::TcpServer;
 
+pub mod certs;
 pub mod channel;
 pub mod errors;
 pub mod hdl;
+pub mod history;
+pub mod interfaces;
 pub mod manager;
+pub mod peers;
+pub mod scanner;
+pub mod sink;
+pub mod text_actions;
 pub mod utils;
 
-pub use hdl::{EndpointInfo, OutboundPayload, TransferState};
+pub use certs::KnownDevice;
+pub use hdl::{BleConfig, EndpointInfo, OutboundPayload, TransferState, Visibility};
+pub use history::{HistoryEntry, HistoryFile};
+pub use interfaces::{Cidr, InterfaceFilter};
 pub use manager::SendInfo;
+pub use peers::{Peer, StaticPeer};
+pub use scanner::{ScanConfig, ScanHook};
+pub use sink::{FsSink, IncomingFile, PayloadSink, SinkFile};
+pub use text_actions::{TextActions, WifiExport, WifiExportFormat};
 pub use utils::DeviceType;
 
 pub mod sharing_nearby {
@@ -45,6 +59,22 @@ pub mod location_nearby_connections {
 }
 
 static CUSTOM_DOWNLOAD: LazyLock<RwLock<Option<PathBuf>>> = LazyLock::new(|| RwLock::new(None));
+static SCAN_CONFIG: LazyLock<RwLock<Option<ScanConfig>>> = LazyLock::new(|| RwLock::new(None));
+static PAYLOAD_SINK: LazyLock<RwLock<Option<Arc<dyn PayloadSink>>>> =
+    LazyLock::new(|| RwLock::new(None));
+static BANDWIDTH_UPGRADE: LazyLock<RwLock<bool>> = LazyLock::new(|| RwLock::new(false));
+static AUTO_RESUME: LazyLock<RwLock<bool>> = LazyLock::new(|| RwLock::new(true));
+static CONSENT_TIMEOUT: LazyLock<RwLock<Option<Duration>>> =
+    LazyLock::new(|| RwLock::new(Some(hdl::DEFAULT_CONSENT_TIMEOUT)));
+static INTERFACE_FILTER: LazyLock<RwLock<InterfaceFilter>> =
+    LazyLock::new(|| RwLock::new(InterfaceFilter::default()));
+static VISIBILITY: LazyLock<watch::Sender<Visibility>> =
+    LazyLock::new(|| watch::Sender::new(Visibility::default()));
+static DEVICE_TYPE: LazyLock<RwLock<DeviceType>> = LazyLock::new(|| RwLock::new(DeviceType::Laptop));
+static STATIC_PEERS: LazyLock<watch::Sender<Vec<StaticPeer>>> = LazyLock::new(|| watch::Sender::new(Vec::new()));
+static BLE_CONFIG: LazyLock<watch::Sender<BleConfig>> =
+    LazyLock::new(|| watch::Sender::new(BleConfig::default()));
+static TEXT_ACTIONS: LazyLock<RwLock<TextActions>> = LazyLock::new(|| RwLock::new(TextActions::default()));
 static DEVICE_NAME: LazyLock<RwLock<String>> = LazyLock::new(|| {
     RwLock::new(
         hostname::get()
@@ -115,8 +145,8 @@ impl RQS {
         self.ctoken = Some(ctoken.clone());
 
         let endpoint_id = utils::get_endpoint_id();
-        let tcp_listener =
-            TcpListener::bind(format!("0.0.0.0:{}", self.port_number.unwrap_or(0))).await?;
+        let port = u16::try_from(self.port_number.unwrap_or(0))?;
+        let tcp_listener = utils::bind_dual_stack(port)?;
         let binded_addr = tcp_listener.local_addr()?;
         info!("TcpListener on: {binded_addr}");
 
@@ -137,13 +167,11 @@ impl RQS {
         let ctk = ctoken.clone();
         tracker.spawn(async move { server.run(ctk).await });
 
-        if let Ok(ble) = BleListener::new(self.ble_sender.clone())
-            .await
-            .inspect_err(|err| warn!("BleListener: {err}"))
-        {
-            let ctk = ctoken.clone();
-            tracker.spawn(async move { ble.run(ctk).await });
-        }
+        let ble_sender = self.ble_sender.clone();
+        tracker.spawn(hdl::supervise("BleListener", |c| c.listen, ctoken.clone(), move |config, ctk| {
+            let sender = ble_sender.clone();
+            async move { BleListener::new(sender, &config).await?.run(ctk).await }
+        }));
 
         // Start MDnsServer in own "task"
         let mut mdns = MDnsServer::new(
@@ -172,22 +200,13 @@ impl RQS {
         self.discovery_ctk = Some(ctk.clone());
 
         #[cfg(target_os = "linux")]
-        {
-            let ctk_blea = ctk.clone();
-            tracker.spawn(async move {
-                let blea = match BleAdvertiser::new().await {
-                    Ok(b) => b,
-                    Err(e) => {
-                        error!("Couldn't init BleAdvertiser: {e}");
-                        return;
-                    }
-                };
-
-                if let Err(e) = blea.run(ctk_blea).await {
-                    error!("Couldn't start BleAdvertiser: {e}");
-                }
-            });
-        }
+        tracker.spawn(hdl::supervise("BleAdvertiser", |c| c.advertise, ctk.clone(), |config, ctk| async move {
+            BleAdvertiser::new(&config).await?.run(ctk).await
+        }));
+
+        let ctk_static = ctk.clone();
+        let static_sender = sender.clone();
+        tracker.spawn(async move { peers::probe_static_peers(static_sender, ctk_static).await });
 
         let discovery = MDnsDiscovery::new(sender)?;
         tracker.spawn(async move { discovery.run(ctk.clone()).await });
@@ -230,6 +249,120 @@ impl RQS {
         }
     }
 
+    /// Scan received files before they are moved into the download dir.
+    /// Setting None disables scanning (the default).
+    pub fn set_scan_config(&self, config: Option<ScanConfig>) {
+        debug!("Setting the scan config to {config:?}");
+        if let Ok(mut guard) = SCAN_CONFIG.write() {
+            *guard = config;
+        }
+    }
+
+    /// Deliver received files to a custom sink instead of the download dir.
+    /// Setting None resumes writing to the filesystem.
+    pub fn set_payload_sink(&self, sink: Option<Arc<dyn PayloadSink>>) {
+        debug!("Setting the payload sink to {sink:?}");
+        if let Ok(mut guard) = PAYLOAD_SINK.write() {
+            *guard = sink;
+        }
+    }
+
+    /// What to do with received text, URLs and Wi-Fi credentials.
+    /// By default they are only reported to the client.
+    pub fn set_text_actions(&self, actions: TextActions) {
+        debug!("Setting the text actions to {actions:?}");
+        if let Ok(mut guard) = TEXT_ACTIONS.write() {
+            *guard = actions;
+        }
+    }
+
+    /// Which interfaces and addresses are advertised and accept connections.
+    /// Takes effect while running: the advertised addresses follow it within a few
+    /// seconds and every new connection is checked against it.
+    /// The listener stays bound to all addresses, a connection to a filtered one is
+    /// accepted and closed without a reply. The default skips virtual and VPN networks.
+    pub fn set_interface_filter(&self, filter: InterfaceFilter) {
+        debug!("Setting the interface filter to {filter:?}");
+        if let Ok(mut guard) = INTERFACE_FILTER.write() {
+            *guard = filter;
+        }
+    }
+
+    /// Offer peers that support it to move incoming connections to a new Wi-Fi LAN socket.
+    /// Disabled by default.
+    pub fn set_bandwidth_upgrade(&self, enabled: bool) {
+        debug!("Setting bandwidth upgrade to {enabled}");
+        if let Ok(mut guard) = BANDWIDTH_UPGRADE.write() {
+            *guard = enabled;
+        }
+    }
+
+    /// Reconnect and continue file transfers interrupted by a dropped connection.
+    /// Enabled by default.
+    pub fn set_auto_resume(&self, enabled: bool) {
+        debug!("Setting auto-resume to {enabled}");
+        if let Ok(mut guard) = AUTO_RESUME.write() {
+            *guard = enabled;
+        }
+    }
+
+    /// How long an incoming transfer waits for `ConsentAccept`/`ConsentDecline` before
+    /// the sender is told it timed out. `None` waits as long as the sender does.
+    /// One minute by default.
+    pub fn set_consent_timeout(&self, timeout: Option<Duration>) {
+        debug!("Setting the consent timeout to {timeout:?}");
+        if let Ok(mut guard) = CONSENT_TIMEOUT.write() {
+            *guard = timeout;
+        }
+    }
+
+    /// Every transfer recorded so far, oldest first.
+    pub fn history(&self) -> Result<Vec<HistoryEntry>, anyhow::Error> {
+        history::list_entries()
+    }
+
+    /// Transfers whose peer name, file names/paths or failure reason contain `query`
+    /// (case-insensitive), oldest first.
+    pub fn search_history(&self, query: &str) -> Result<Vec<HistoryEntry>, anyhow::Error> {
+        Ok(history::list_entries()?
+            .into_iter()
+            .filter(|e| e.matches(query))
+            .collect())
+    }
+
+    pub fn clear_history(&self) -> Result<(), anyhow::Error> {
+        debug!("Clearing the transfer history");
+        history::clear_entries()
+    }
+
+    /// Devices recognized through their paired key certificate, oldest first.
+    /// A device is added once a transfer with it finished.
+    pub fn known_devices(&self) -> Result<Vec<KnownDevice>, anyhow::Error> {
+        certs::known_devices()
+    }
+
+    /// Stop recognizing the device with this certificate id, returns whether it was known.
+    pub fn forget_device(&self, id: &str) -> Result<bool, anyhow::Error> {
+        debug!("Forgetting device {id}");
+        certs::forget_device(id)
+    }
+
+    /// Whether nearby devices can find us, applied right away. Always visible by default.
+    /// `Visibility::for_duration` hides again once it expires.
+    pub fn set_visibility(&self, visibility: Visibility) {
+        debug!("Setting the visibility to {visibility:?}");
+        VISIBILITY.send_replace(visibility);
+    }
+
+    pub fn get_visibility(&self) -> Visibility {
+        *VISIBILITY.borrow()
+    }
+
+    /// Follow visibility changes, including a temporary one expiring.
+    pub fn subscribe_visibility(&self) -> watch::Receiver<Visibility> {
+        VISIBILITY.subscribe()
+    }
+
     /// For this to properly take effect,
     /// `MdnsServer` would need to be reset which is done by `RQS::stop` followed by `RQS::run`.
     ///
@@ -241,6 +374,41 @@ impl RQS {
         }
     }
 
+    /// The type peers see us as. Laptop by default, advertised from the next registration.
+    pub fn set_device_type(&self, device_type: DeviceType) {
+        debug!("Setting the device type to {device_type:?}");
+        if let Ok(mut guard) = DEVICE_TYPE.write() {
+            *guard = device_type;
+        }
+    }
+
+    pub fn get_device_type(&self) -> DeviceType {
+        utils::get_device_type()
+    }
+
+    /// Bluetooth adapter and what it's used for, applied right away.
+    /// The adapter is only powered on if `power_on` allows it.
+    pub fn set_ble_config(&self, config: BleConfig) {
+        debug!("Setting the Bluetooth config to {config:?}");
+        BLE_CONFIG.send_replace(config);
+    }
+
+    pub fn get_ble_config(&self) -> BleConfig {
+        hdl::get_ble_config()
+    }
+
+    /// Peers to list while discovering even if mDNS finds nothing, checked by connecting to them.
+    /// Applied right away, they're probed again each time they're set.
+    pub fn set_static_peers(&self, static_peers: Vec<StaticPeer>) {
+        debug!("Setting the static peers to {static_peers:?}");
+        STATIC_PEERS.send_replace(static_peers);
+    }
+
+    /// The devices discovery currently knows about, most recently seen first.
+    pub fn peers(&self) -> Vec<Peer> {
+        peers::snapshot()
+    }
+
     pub fn get_device_name(&self) -> String {
         DEVICE_NAME
             .read()
@@ -1,4 +1,6 @@
+use std::net::SocketAddr;
 use std::sync::LazyLock;
+use std::time::Duration;
 
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::broadcast::Sender;
@@ -9,11 +11,18 @@ use tokio_util::sync::CancellationToken;
 
 use crate::channel::{self, ChannelMessage, MessageClient, TransferKind};
 use crate::errors::AppError;
-use crate::hdl::{InboundRequest, OutboundPayload, OutboundRequest, TransferState};
+use crate::hdl::resume::{self, RECONNECT_DELAYS, RECONNECT_TIMEOUT};
+use crate::hdl::{InboundRequest, InnerState, OutboundPayload, OutboundRequest, TransferState};
+use crate::certs;
+use crate::history::{self, HistoryEntry};
+use crate::interfaces;
 use crate::utils::RemoteDeviceInfo;
 
 const INNER_NAME: &str = "TcpServer";
 
+/// How long to wait for each address of a peer before trying the next one
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Maximum concurrent connections to prevent DoS/resource exhaustion
 const MAX_CONCURRENT_CONNECTIONS: usize = 100;
 
@@ -25,9 +34,56 @@ pub struct SendInfo {
     pub id: String,
     pub name: String,
     pub addr: String,
+    /// Other addresses the peer resolved to, tried in order when `addr` can't be reached
+    #[serde(default)]
+    pub fallback_addrs: Vec<String>,
     pub ob: OutboundPayload,
 }
 
+impl SendInfo {
+    /// Send straight to `host:port`, without discovering the peer first.
+    pub fn to_address(addr: impl Into<String>, name: Option<String>, ob: OutboundPayload) -> Self {
+        let addr = addr.into();
+        Self {
+            id: addr.clone(),
+            name: name.unwrap_or_else(|| addr.clone()),
+            addr,
+            fallback_addrs: Vec::new(),
+            ob,
+        }
+    }
+
+    /// `addr` followed by the fallbacks, without duplicates.
+    fn addresses(&self) -> Vec<String> {
+        let mut addrs = vec![self.addr.clone()];
+        for addr in &self.fallback_addrs {
+            if !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        addrs
+    }
+}
+
+/// Connect to the first address that answers within `timeout`.
+async fn connect_any(addrs: &[String], timeout: Duration) -> Result<(TcpStream, String), std::io::Error> {
+    let mut last_err = std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to connect to");
+    for addr in addrs {
+        match tokio::time::timeout(timeout, TcpStream::connect(addr.as_str())).await {
+            Ok(Ok(socket)) => return Ok((socket, addr.clone())),
+            Ok(Err(e)) => {
+                debug!("{INNER_NAME}: couldn't connect to {addr}: {e}");
+                last_err = e;
+            }
+            Err(_) => {
+                debug!("{INNER_NAME}: couldn't connect to {addr}: timed out");
+                last_err = std::io::ErrorKind::TimedOut.into();
+            }
+        }
+    }
+    Err(last_err)
+}
+
 pub struct TcpServer {
     endpoint_id: [u8; 4],
     tcp_listener: TcpListener,
@@ -70,8 +126,19 @@ impl TcpServer {
                 r = self.tcp_listener.accept() => {
                     match r {
                         Ok((socket, remote_addr)) => {
+                            // IPv4 peers arrive as IPv4-mapped addresses on the dual-stack listener
+                            let remote_addr = SocketAddr::new(remote_addr.ip().to_canonical(), remote_addr.port());
                             trace!("{INNER_NAME}: new client: {remote_addr}");
 
+                            // The listener is on every address, so it survives address changes:
+                            // close connections to the ones the filter leaves out, without a reply
+                            let filter = interfaces::get_interface_filter();
+                            if !socket.local_addr().is_ok_and(|local| filter.allows_local(&local.ip())) {
+                                debug!("{INNER_NAME}: {remote_addr} connected on a filtered address, rejecting");
+                                drop(socket);
+                                continue;
+                            }
+
                             // Set TCP socket options for better performance
                             if let Err(e) = socket.set_nodelay(true) {
                                 warn!("{INNER_NAME}: failed to set TCP_NODELAY: {e}");
@@ -94,6 +161,7 @@ impl TcpServer {
                                 // Permit is moved into the task and released when dropped
                                 let _permit = permit;
                                 let mut ir = InboundRequest::new(socket, remote_addr.to_string(), csender);
+                                let mut failure = None;
 
                                 loop {
                                     match ir.handle().await {
@@ -106,15 +174,8 @@ impl TcpServer {
                                                 }
 
                                                 if ir.state.state != TransferState::Finished {
-                                                    drop(esender.send(ChannelMessage {
-                                                        id: remote_addr.to_string(),
-                                                        msg: channel::Message::Client(MessageClient {
-                                                            kind: TransferKind::Inbound,
-                                                            state: Some(TransferState::Disconnected),
-                                                            metadata: Default::default()
-                                                        }),
-                                                    }));
                                                     error!("{INNER_NAME}: error while handling client: {e} ({:?})", ir.state.state);
+                                                    failure = Some(e.to_string());
                                                 } else {
                                                     debug!("{INNER_NAME}: connection closed after transfer complete");
                                                 }
@@ -123,6 +184,36 @@ impl TcpServer {
                                         },
                                     }
                                 }
+
+                                if failure.is_some() {
+                                    // Partial files are kept for a while, the sender may reconnect
+                                    let ticket = resume::park(&mut ir.state, remote_addr.ip());
+                                    let state = if ticket.is_some() {
+                                        TransferState::Reconnecting
+                                    } else {
+                                        TransferState::Disconnected
+                                    };
+                                    drop(esender.send(ChannelMessage {
+                                        id: ir.state.id.clone(),
+                                        msg: channel::Message::Client(Box::new(MessageClient {
+                                            kind: TransferKind::Inbound,
+                                            state: Some(state),
+                                            metadata: Default::default()
+                                        })),
+                                    }));
+
+                                    // Recorded once resumed, or given up on
+                                    if let Some(ticket) = ticket {
+                                        tokio::spawn(resume::expire_after(ticket, esender));
+                                        return;
+                                    }
+                                }
+
+                                // Connections that never got to the introduction aren't transfers
+                                if ir.state.transfer_metadata.is_some() {
+                                    certs::remember(&ir.state);
+                                    history::record(&HistoryEntry::from_state(TransferKind::Inbound, &ir.state, failure));
+                                }
                             });
                         },
                         Err(err) => {
@@ -142,68 +233,133 @@ impl TcpServer {
 
     /// To be called inside a separate task if we want to handle concurrency
     pub async fn connect(&self, ctk: CancellationToken, si: SendInfo) -> Result<(), anyhow::Error> {
-        debug!("{INNER_NAME}: Connecting to: {}", si.addr);
-        let socket = TcpStream::connect(si.addr.clone()).await?;
+        let mut addrs = si.addresses();
+        debug!("{INNER_NAME}: Connecting to: {addrs:?}");
+        let socket = match connect_any(&addrs, CONNECT_TIMEOUT).await {
+            Ok((socket, addr)) => {
+                // Try the one that worked first when reconnecting
+                addrs.retain(|a| *a != addr);
+                addrs.insert(0, addr);
+                socket
+            }
+            Err(e) => {
+                let mut state = InnerState::new(si.id, None);
+                state.remote_device_info = Some(RemoteDeviceInfo::new(si.name, crate::DeviceType::Unknown));
+                history::record(&HistoryEntry::from_state(TransferKind::Outbound, &state, Some(e.to_string())));
+                return Err(e.into());
+            }
+        };
+
+        let mut or = self.new_outbound(socket, &si);
+        let mut failure = match self.drive_outbound(&ctk, &mut or).await {
+            Ok(failure) => failure,
+            Err(e) => {
+                history::record(&HistoryEntry::from_state(TransferKind::Outbound, &or.state, Some(e.to_string())));
+                return Err(e);
+            }
+        };
+
+        // Resume the files the connection dropped in the middle of
+        let mut delays = RECONNECT_DELAYS.iter();
+        while failure.is_some() && resume::can_reconnect(&or.state) {
+            let Some(delay) = delays.next() else {
+                break;
+            };
+            self.inform_outbound(&or.state, TransferState::Reconnecting);
+            info!("{INNER_NAME}: reconnecting to {} in {delay:?}", addrs[0]);
+            tokio::select! {
+                _ = ctk.cancelled() => break,
+                () = tokio::time::sleep(*delay) => {}
+            }
+
+            let socket = match connect_any(&addrs, RECONNECT_TIMEOUT).await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    warn!("{INNER_NAME}: failed to reconnect: {e}");
+                    continue;
+                }
+            };
+            let transfer = or.state.take_resumable();
+            or = self.new_outbound(socket, &si);
+            or.state.resume(transfer);
+            failure = self.drive_outbound(&ctk, &mut or).await.unwrap_or_else(|e| Some(e.to_string()));
+        }
+
+        if failure.is_some() {
+            self.inform_outbound(&or.state, TransferState::Disconnected);
+        }
+        certs::remember(&or.state);
+        history::record(&HistoryEntry::from_state(TransferKind::Outbound, &or.state, failure));
+
+        Ok(())
+    }
 
+    fn new_outbound(&self, socket: TcpStream, si: &SendInfo) -> OutboundRequest {
         // Set TCP socket options for better performance
         if let Err(e) = socket.set_nodelay(true) {
             warn!("{INNER_NAME}: failed to set TCP_NODELAY: {e}");
         }
 
-        let mut or = OutboundRequest::new(
+        OutboundRequest::new(
             self.endpoint_id,
             socket,
-            si.id,
+            si.id.clone(),
             self.sender.clone(),
-            si.ob,
-            RemoteDeviceInfo {
-                device_type: crate::DeviceType::Unknown,
-                name: si.name,
-            },
-        );
-
-        // Send connection request
+            si.ob.clone(),
+            RemoteDeviceInfo::new(si.name.clone(), crate::DeviceType::Unknown),
+        )
+    }
+
+    /// Set the connection up and run it until it ends, returns why it failed if it did.
+    /// Errors are for a connection that couldn't even be set up.
+    async fn drive_outbound(
+        &self,
+        ctk: &CancellationToken,
+        or: &mut OutboundRequest,
+    ) -> Result<Option<String>, anyhow::Error> {
+        // Send connection request, then UKEY init
         or.send_connection_request().await?;
-        // Send UKEY init
         or.send_ukey2_client_init().await?;
 
         loop {
             tokio::select! {
                 _ = ctk.cancelled() => {
                     info!("{INNER_NAME}: tracker cancelled, breaking");
-                    break;
+                    return Ok(None);
                 },
                 r = or.handle() => {
                     if let Err(e) = r {
                         match e.downcast_ref() {
-                            Some(AppError::NotAnError) => break,
+                            Some(AppError::NotAnError) => return Ok(None),
                             None => {
                                 if or.state.state == TransferState::Initial {
-                                    break;
+                                    return Ok(None);
                                 }
 
                                 if or.state.state == TransferState::Finished || or.state.state == TransferState::Cancelled {
                                     // Connection closed after transfer completed - this is normal
                                     debug!("{INNER_NAME}: connection closed after transfer ({:?})", or.state.state);
-                                } else {
-                                    drop(self.sender.clone().send(ChannelMessage {
-                                        id: si.addr,
-                                        msg: channel::Message::Client(MessageClient {
-                                            kind: TransferKind::Outbound,
-                                            state: Some(TransferState::Disconnected),
-                                            metadata: Default::default()
-                                        }),
-                                    }));
-                                    error!("{INNER_NAME}: error while handling client: {e} ({:?})", or.state.state);
+                                    return Ok(None);
                                 }
-                                break;
+
+                                error!("{INNER_NAME}: error while handling client: {e} ({:?})", or.state.state);
+                                return Ok(Some(e.to_string()));
                             }
                         }
                     }
                 }
             }
         }
+    }
 
-        Ok(())
+    fn inform_outbound(&self, state: &InnerState, transfer_state: TransferState) {
+        drop(self.sender.send(ChannelMessage {
+            id: state.id.clone(),
+            msg: channel::Message::Client(Box::new(MessageClient {
+                kind: TransferKind::Outbound,
+                state: Some(transfer_state),
+                metadata: state.transfer_metadata.clone(),
+            })),
+        }));
     }
 }
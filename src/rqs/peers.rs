@@ -0,0 +1,277 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::hdl::EndpointInfo;
+use crate::{DeviceType, STATIC_PEERS};
+
+/// How long a peer stays listed without being resolved again,
+/// in case its goodbye packet got lost.
+pub const PEER_TTL: Duration = Duration::from_secs(120);
+
+/// How often discovery queries again (refreshing `last_seen`) and drops expired peers.
+pub const PEER_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A device found by discovery, keyed by its endpoint id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Peer {
+    pub endpoint: EndpointInfo,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+/// A peer configured by address, for networks where mDNS multicast doesn't get through.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaticPeer {
+    /// `host:port`, the host can be a name or an address (bracketed for IPv6)
+    pub addr: String,
+    pub name: Option<String>,
+}
+
+/// How long a static peer has to accept the probe connection
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+static PEERS: LazyLock<RwLock<HashMap<String, Peer>>> = LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Add the endpoint, or refresh it if it's already known.
+pub fn seen(endpoint: EndpointInfo) {
+    let Ok(mut peers) = PEERS.write() else {
+        return;
+    };
+
+    let now = SystemTime::now();
+    let first_seen = peers.get(&endpoint.id).map_or(now, |p| p.first_seen);
+    peers.insert(
+        endpoint.id.clone(),
+        Peer {
+            endpoint,
+            first_seen,
+            last_seen: now,
+        },
+    );
+}
+
+pub fn remove(id: &str) -> Option<Peer> {
+    PEERS.write().ok()?.remove(id)
+}
+
+/// Drop the peers not seen for `ttl`, returning them.
+/// Static peers aren't refreshed, they stay until they're unreachable or unconfigured.
+pub fn expire(ttl: Duration) -> Vec<Peer> {
+    let static_peers = get_static_peers();
+    let Ok(mut peers) = PEERS.write() else {
+        return Vec::new();
+    };
+
+    expire_from(&mut peers, &static_peers, ttl, SystemTime::now())
+}
+
+fn expire_from(
+    peers: &mut HashMap<String, Peer>,
+    static_peers: &[StaticPeer],
+    ttl: Duration,
+    now: SystemTime,
+) -> Vec<Peer> {
+    let expired: Vec<String> = peers
+        .iter()
+        .filter(|(id, _)| !static_peers.iter().any(|sp| sp.addr == **id))
+        .filter(|(_, p)| now.duration_since(p.last_seen).unwrap_or_default() > ttl)
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    expired.iter().filter_map(|id| peers.remove(id)).collect()
+}
+
+pub fn clear() {
+    if let Ok(mut peers) = PEERS.write() {
+        peers.clear();
+    }
+}
+
+/// The peers currently known, most recently seen first.
+pub fn snapshot() -> Vec<Peer> {
+    let mut peers: Vec<Peer> = PEERS
+        .read()
+        .map(|peers| peers.values().cloned().collect())
+        .unwrap_or_default();
+    peers.sort_by_key(|p| Reverse(p.last_seen));
+    peers
+}
+
+pub fn get_static_peers() -> Vec<StaticPeer> {
+    STATIC_PEERS.borrow().clone()
+}
+
+/// Probe the static peers when discovery starts and each time they're set, listing the
+/// reachable ones like discovered endpoints (their id is the configured address) and
+/// removing the others. Nothing is probed in between, sending to a peer that went away
+/// fails when connecting.
+pub async fn probe_static_peers(sender: broadcast::Sender<EndpointInfo>, ctk: CancellationToken) {
+    let mut config = STATIC_PEERS.subscribe();
+    let mut listed: Vec<String> = Vec::new();
+
+    loop {
+        let static_peers = config.borrow_and_update().clone();
+
+        // The ones no longer configured go away
+        for id in listed.drain(..) {
+            if !static_peers.iter().any(|p| p.addr == id) && remove(&id).is_some() {
+                drop(sender.send(EndpointInfo {
+                    id,
+                    ..Default::default()
+                }));
+            }
+        }
+        listed = static_peers.iter().map(|p| p.addr.clone()).collect();
+
+        let probes = static_peers.into_iter().map(|peer| async move {
+            let result = probe(&peer).await;
+            (peer, result)
+        });
+        for (peer, result) in join_all(probes).await {
+            match result {
+                Ok(endpoint) => {
+                    seen(endpoint.clone());
+                    drop(sender.send(endpoint));
+                }
+                Err(e) => {
+                    debug!("Static peer {} isn't reachable: {e}", peer.addr);
+                    if remove(&peer.addr).is_some() {
+                        drop(sender.send(EndpointInfo {
+                            id: peer.addr,
+                            ..Default::default()
+                        }));
+                    }
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = ctk.cancelled() => break,
+            changed = config.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Resolve the peer and check that it accepts connections on one of its addresses.
+async fn probe(peer: &StaticPeer) -> Result<EndpointInfo, anyhow::Error> {
+    let addrs: Vec<SocketAddr> = lookup_host(&peer.addr).await?.collect();
+
+    let mut reachable = None;
+    for addr in &addrs {
+        if let Ok(Ok(_)) = tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await {
+            reachable = Some(*addr);
+            break;
+        }
+    }
+    let addr = reachable.ok_or_else(|| anyhow!("no address accepted a connection"))?;
+
+    Ok(EndpointInfo {
+        id: peer.addr.clone(),
+        name: Some(peer.name.clone().unwrap_or_else(|| peer.addr.clone())),
+        ip: Some(addr.ip().to_string()),
+        port: Some(addr.port().to_string()),
+        // The one that answered first
+        addresses: std::iter::once(addr)
+            .chain(addrs.into_iter().filter(|a| *a != addr))
+            .map(|a| a.to_string())
+            .collect(),
+        rtype: Some(DeviceType::Unknown),
+        present: Some(true),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, last_seen: SystemTime) -> (String, Peer) {
+        let endpoint = EndpointInfo {
+            id: id.into(),
+            ..Default::default()
+        };
+        let peer = Peer {
+            endpoint,
+            first_seen: last_seen,
+            last_seen,
+        };
+        (id.into(), peer)
+    }
+
+    #[test]
+    fn test_seen() {
+        let endpoint = |name: &str| EndpointInfo {
+            id: "SEEN".into(),
+            name: Some(name.into()),
+            ..Default::default()
+        };
+
+        seen(endpoint("before"));
+        let first = snapshot().into_iter().find(|p| p.endpoint.id == "SEEN").unwrap();
+        seen(endpoint("after"));
+        let peer = snapshot().into_iter().find(|p| p.endpoint.id == "SEEN").unwrap();
+        assert_eq!(peer.endpoint.name.as_deref(), Some("after"));
+        assert_eq!(peer.first_seen, first.first_seen);
+        assert!(remove("SEEN").is_some());
+    }
+
+    #[test]
+    fn test_expire() {
+        let now = SystemTime::now();
+        let mut peers = HashMap::from([
+            peer("GONE", now - Duration::from_secs(61)),
+            peer("HERE", now - Duration::from_secs(59)),
+        ]);
+
+        let expired = expire_from(&mut peers, &[], Duration::from_secs(60), now);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].endpoint.id, "GONE");
+        assert!(peers.contains_key("HERE"));
+    }
+
+    #[test]
+    fn test_static_peers_dont_expire() {
+        let now = SystemTime::now();
+        let static_peer = StaticPeer {
+            addr: "192.0.2.1:4242".into(),
+            name: None,
+        };
+        let mut peers = HashMap::from([peer("192.0.2.1:4242", now - Duration::from_secs(600))]);
+
+        assert!(expire_from(&mut peers, &[static_peer], Duration::from_secs(60), now).is_empty());
+        assert!(peers.contains_key("192.0.2.1:4242"));
+    }
+
+    #[tokio::test]
+    async fn test_probe_static_peer() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let peer = StaticPeer {
+            addr: addr.clone(),
+            name: Some("office".into()),
+        };
+        let endpoint = probe(&peer).await.unwrap();
+        assert_eq!(endpoint.id, addr);
+        assert_eq!(endpoint.name.as_deref(), Some("office"));
+        assert_eq!(endpoint.socket_addr(), Some(addr));
+
+        drop(listener);
+        assert!(probe(&peer).await.is_err());
+    }
+}
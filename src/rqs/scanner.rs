@@ -0,0 +1,245 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+
+use crate::SCAN_CONFIG;
+
+/// Size of the chunks streamed to clamd with INSTREAM
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long a scan may take by default before the file is considered unscannable
+pub const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How received files are scanned before being moved into the download dir.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ScanHook {
+    /// Run `program args... <file>`.
+    /// Exit code 0 means clean, 1 means infected (same as `clamscan`), anything else is an error.
+    Command { program: PathBuf, args: Vec<String> },
+    /// Stream the file to a clamd-compatible daemon listening on a Unix socket.
+    Clamd { socket: PathBuf },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanConfig {
+    pub hook: ScanHook,
+    /// Where infected (or unscannable) files are moved to
+    pub quarantine_dir: PathBuf,
+    /// A scan taking longer fails, the file gets quarantined
+    #[serde(default = "default_scan_timeout")]
+    pub timeout: Duration,
+}
+
+impl ScanConfig {
+    pub fn new(hook: ScanHook) -> Self {
+        Self {
+            hook,
+            quarantine_dir: default_quarantine_dir(),
+            timeout: DEFAULT_SCAN_TIMEOUT,
+        }
+    }
+}
+
+fn default_scan_timeout() -> Duration {
+    DEFAULT_SCAN_TIMEOUT
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    /// Contains the signature name (or whatever the scanner reported)
+    Infected(String),
+}
+
+/// Returns the scan configuration, if scanning is enabled.
+pub fn get_scan_config() -> Option<ScanConfig> {
+    SCAN_CONFIG.read().ok().and_then(|g| g.clone())
+}
+
+pub fn default_quarantine_dir() -> PathBuf {
+    directories::ProjectDirs::from("", "", "kvakk")
+        .map(|d| d.data_dir().join("quarantine"))
+        .unwrap_or_else(|| std::env::temp_dir().join("kvakk-quarantine"))
+}
+
+/// Scan the file with the configured hook, giving up after `ScanConfig::timeout`.
+pub async fn scan_file(config: &ScanConfig, path: &Path) -> Result<ScanVerdict, anyhow::Error> {
+    let scan = async {
+        match &config.hook {
+            ScanHook::Command { program, args } => scan_with_command(program, args, path).await,
+            ScanHook::Clamd { socket } => scan_with_clamd(socket, path).await,
+        }
+    };
+
+    // The scanner is killed when its future is dropped
+    tokio::time::timeout(config.timeout, scan)
+        .await
+        .map_err(|_| anyhow!("no verdict after {:?}", config.timeout))?
+}
+
+async fn scan_with_command(
+    program: &Path,
+    args: &[String],
+    path: &Path,
+) -> Result<ScanVerdict, anyhow::Error> {
+    let output = Command::new(program)
+        .args(args)
+        .arg(path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    match output.status.code() {
+        Some(0) => Ok(ScanVerdict::Clean),
+        Some(1) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let reason = stdout
+                .lines()
+                .find(|l| !l.trim().is_empty())
+                .unwrap_or("infected")
+                .trim()
+                .to_string();
+            Ok(ScanVerdict::Infected(reason))
+        }
+        code => Err(anyhow!(
+            "{} exited with {code:?}: {}",
+            program.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+async fn scan_with_clamd(socket: &Path, path: &Path) -> Result<ScanVerdict, anyhow::Error> {
+    let mut stream = UnixStream::connect(socket).await?;
+    let mut file = tokio::fs::File::open(path).await?;
+
+    // The file is streamed rather than scanned by path, clamd usually
+    // doesn't have the permissions to read the user's download dir.
+    stream.write_all(b"zINSTREAM\0").await?;
+    let mut buffer = vec![0u8; CLAMD_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        // Safety: read is bounded by CLAMD_CHUNK_SIZE
+        let len = u32::try_from(read).unwrap_or(u32::MAX);
+        stream.write_all(&len.to_be_bytes()).await?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&buffer[..read]).await?;
+    }
+    stream.flush().await?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+
+    parse_clamd_reply(&String::from_utf8_lossy(&reply))
+}
+
+/// Parse a clamd reply such as `stream: OK` or `stream: Eicar-Signature FOUND`.
+fn parse_clamd_reply(reply: &str) -> Result<ScanVerdict, anyhow::Error> {
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let status = reply.split_once(": ").map_or(reply, |(_, s)| s);
+
+    if status == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = status.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected(signature.to_string()))
+    } else {
+        Err(anyhow!("clamd: unexpected reply: {reply}"))
+    }
+}
+
+/// Move a file into the quarantine dir, returning its new location.
+/// The file is renamed to `<timestamp>-<name>`, with a counter if that's taken,
+/// so nothing gets overwritten.
+pub fn quarantine_file(
+    path: &Path,
+    name: &str,
+    quarantine_dir: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    std::fs::create_dir_all(quarantine_dir)?;
+
+    let name = Path::new(name)
+        .file_name()
+        .map_or_else(|| "unnamed_file".to_string(), |n| n.to_string_lossy().to_string());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    // Claim the name first, in case another file gets quarantined at the same time
+    let mut dest = quarantine_dir.join(format!("{timestamp}-{name}"));
+    for counter in 1.. {
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&dest) {
+            Ok(_) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                dest = quarantine_dir.join(format!("{timestamp}-{counter}-{name}"));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // The quarantine dir may live on another filesystem
+    if std::fs::rename(path, &dest).is_err() {
+        std::fs::copy(path, &dest)?;
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(dest)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0").unwrap(), ScanVerdict::Clean);
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Test-Signature FOUND\0").unwrap(),
+            ScanVerdict::Infected("Eicar-Test-Signature".to_string())
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scan_timeout() {
+        let mut config = ScanConfig::new(ScanHook::Command {
+            program: "sh".into(),
+            args: vec!["-c".into(), "sleep 10".into()],
+        });
+        config.timeout = Duration::from_millis(100);
+
+        let started = std::time::Instant::now();
+        assert!(scan_file(&config, Path::new("/dev/null")).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_quarantine_same_name() {
+        let dir = std::env::temp_dir().join(format!("kvakk-quarantine-test-{}", std::process::id()));
+        let quarantine_dir = dir.join("quarantine");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut quarantined = Vec::new();
+        for content in ["first", "second"] {
+            let path = dir.join(format!("{content}.part"));
+            std::fs::write(&path, content).unwrap();
+            quarantined.push(quarantine_file(&path, "same.txt", &quarantine_dir).unwrap());
+            assert!(!path.exists());
+        }
+
+        assert_ne!(quarantined[0], quarantined[1]);
+        assert_eq!(std::fs::read_to_string(&quarantined[0]).unwrap(), "first");
+        assert_eq!(std::fs::read_to_string(&quarantined[1]).unwrap(), "second");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
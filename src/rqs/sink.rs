@@ -0,0 +1,90 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::PAYLOAD_SINK;
+
+/// A file announced by the sender and accepted by the user.
+#[derive(Debug, Clone)]
+pub struct IncomingFile {
+    /// Id of the transfer, as used in the `ChannelMessage`
+    pub transfer_id: String,
+    pub payload_id: i64,
+    /// File name as announced by the sender (not sanitized)
+    pub name: String,
+    /// Where the default filesystem sink would write the file
+    pub path: PathBuf,
+    pub total_size: i64,
+}
+
+/// Where the content of received files ends up.
+///
+/// The default is `FsSink`, writing into the download dir. Library users can
+/// provide their own with `RQS::set_payload_sink`.
+pub trait PayloadSink: Send + Sync + Debug {
+    /// Called for each file once the transfer has been accepted.
+    fn create(&self, file: &IncomingFile) -> Result<Box<dyn SinkFile>, anyhow::Error>;
+}
+
+/// A single file being received.
+/// Chunks arrive in order, but the offset is given for sinks that need it.
+pub trait SinkFile: Send + Sync + Debug {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), anyhow::Error>;
+
+    /// Every chunk has been received.
+    fn finish(self: Box<Self>) -> Result<(), anyhow::Error>;
+
+    /// The file won't be completed (cancelled, error, disconnection, ...).
+    fn abort(self: Box<Self>);
+}
+
+/// Returns the sink configured by the user, if any.
+pub fn get_custom_sink() -> Option<Arc<dyn PayloadSink>> {
+    PAYLOAD_SINK.read().ok().and_then(|g| g.as_ref().map(Arc::clone))
+}
+
+/// Writes files to `IncomingFile::path`.
+#[derive(Debug, Default)]
+pub struct FsSink;
+
+impl PayloadSink for FsSink {
+    fn create(&self, file: &IncomingFile) -> Result<Box<dyn SinkFile>, anyhow::Error> {
+        let handle = File::create(&file.path)?;
+        info!("Created file: {handle:?}");
+
+        Ok(Box::new(FsSinkFile {
+            path: file.path.clone(),
+            file: handle,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct FsSinkFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl SinkFile for FsSinkFile {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), anyhow::Error> {
+        self.file.write_all_at(data, offset)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    fn abort(self: Box<Self>) {
+        let Self { path, file } = *self;
+        drop(file);
+
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to cleanup partial file {path:?}: {e}");
+        } else {
+            info!("Cleaned up partial file: {path:?}");
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::TEXT_ACTIONS;
+use crate::hdl::InboundRequest;
+use crate::hdl::info::TransferPayload;
+use crate::sharing_nearby::wifi_credentials_metadata::SecurityType;
+use crate::utils::get_download_dir;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WifiExportFormat {
+    /// `<ssid>.nmconnection` keyfile, e.g. for `/etc/NetworkManager/system-connections`
+    NetworkManager,
+    /// `<ssid>.conf` containing a `network={...}` block
+    WpaSupplicant,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WifiExport {
+    pub format: WifiExportFormat,
+    pub dir: PathBuf,
+}
+
+/// What to do with received text, URLs and Wi-Fi credentials,
+/// on top of reporting them in the `TransferMetadata`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextActions {
+    /// Save received text to a timestamped `.txt` in the download dir
+    pub save_text: bool,
+    /// Open received URLs with `xdg-open`
+    pub open_urls: bool,
+    /// Schemes allowed to be opened (compared case-insensitively)
+    pub url_schemes: Vec<String>,
+    pub wifi_export: Option<WifiExport>,
+}
+
+impl Default for TextActions {
+    fn default() -> Self {
+        Self {
+            save_text: false,
+            open_urls: false,
+            url_schemes: vec!["http".into(), "https".into()],
+            wifi_export: None,
+        }
+    }
+}
+
+pub fn get_text_actions() -> TextActions {
+    TEXT_ACTIONS.read().map(|g| g.clone()).unwrap_or_default()
+}
+
+/// Run the configured actions for a received text payload.
+/// Failures are only logged, the transfer itself already succeeded.
+pub fn handle_text_payload(payload: &TransferPayload) {
+    let actions = get_text_actions();
+
+    let result = match payload {
+        TransferPayload::Text(text) if actions.save_text => save_text(text).map(|path| {
+            info!("Saved received text to {path:?}");
+        }),
+        TransferPayload::Url(url) if actions.open_urls => open_url(url, &actions.url_schemes),
+        TransferPayload::Wifi {
+            ssid,
+            password,
+            security_type,
+        } => match &actions.wifi_export {
+            Some(export) => export_wifi(export, ssid, password, *security_type).map(|path| {
+                info!("Saved Wi-Fi network {ssid} to {path:?}");
+            }),
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    };
+
+    if let Err(e) = result {
+        error!("Failed to handle received {:?}: {e}", payload.text_type());
+    }
+}
+
+fn save_text(text: &str) -> Result<PathBuf, anyhow::Error> {
+    let name = format!("Text {}.txt", format_timestamp(SystemTime::now()));
+    let path = InboundRequest::resolve_filename_conflict(&get_download_dir(), &name);
+
+    let mut file = File::create_new(&path)?;
+    file.write_all(text.as_bytes())?;
+
+    Ok(path)
+}
+
+fn open_url(url: &str, allowed_schemes: &[String]) -> Result<(), anyhow::Error> {
+    let scheme = url
+        .split_once(':')
+        .map(|(s, _)| s)
+        .ok_or_else(|| anyhow!("URL without scheme: {url}"))?;
+
+    if !allowed_schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)) {
+        return Err(anyhow!("scheme {scheme:?} is not allowed"));
+    }
+
+    info!("Opening received URL: {url}");
+    // The child is reaped by tokio once it exits
+    tokio::process::Command::new("xdg-open").arg(url).spawn()?;
+
+    Ok(())
+}
+
+fn export_wifi(
+    export: &WifiExport,
+    ssid: &str,
+    password: &str,
+    security_type: SecurityType,
+) -> Result<PathBuf, anyhow::Error> {
+    if ssid.is_empty() || ssid.contains(['\n', '\r']) || password.contains(['\n', '\r']) {
+        return Err(anyhow!("refusing to write SSID/password containing line breaks"));
+    }
+
+    let (content, extension) = match export.format {
+        WifiExportFormat::NetworkManager => {
+            (nm_keyfile(ssid, password, security_type), "nmconnection")
+        }
+        WifiExportFormat::WpaSupplicant => {
+            (wpa_supplicant_block(ssid, password, security_type)?, "conf")
+        }
+    };
+
+    std::fs::create_dir_all(&export.dir)?;
+    let name = format!("{ssid}.{extension}");
+    let path = InboundRequest::resolve_filename_conflict(&export.dir, &name);
+
+    write_private(&path, &content)?;
+
+    Ok(path)
+}
+
+/// Credentials are written readable by the owner only (NetworkManager ignores anything else).
+fn write_private(path: &Path, content: &str) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(content.as_bytes())?;
+
+    Ok(())
+}
+
+fn nm_keyfile(ssid: &str, password: &str, security_type: SecurityType) -> String {
+    let uuid = uuid::Builder::from_random_bytes(rand::rng().random()).into_uuid();
+    let id = keyfile_escape(ssid);
+    let password = keyfile_escape(password);
+    // Like NetworkManager, SSIDs that aren't plain printable ASCII are written as a byte list,
+    // as are those that would need escaping
+    let plain = ssid.bytes().all(|b| (b.is_ascii_graphic() || b == b' ') && b != b';');
+    let ssid = if plain && keyfile_escape(ssid) == ssid {
+        ssid.to_owned()
+    } else {
+        ssid.bytes().map(|b| format!("{b};")).collect()
+    };
+    let mut keyfile = format!(
+        "[connection]\nid={id}\nuuid={uuid}\ntype=wifi\n\n[wifi]\nmode=infrastructure\nssid={ssid}\n\n"
+    );
+
+    match security_type {
+        SecurityType::WpaPsk => {
+            keyfile.push_str(&format!("[wifi-security]\nkey-mgmt=wpa-psk\npsk={password}\n\n"));
+        }
+        SecurityType::Wep => {
+            keyfile.push_str(&format!(
+                "[wifi-security]\nkey-mgmt=none\nwep-key-type=1\nwep-key0={password}\n\n"
+            ));
+        }
+        SecurityType::Open | SecurityType::UnknownSecurityType => {}
+    }
+
+    keyfile.push_str("[ipv4]\nmethod=auto\n\n[ipv6]\nmethod=auto\n");
+    keyfile
+}
+
+/// Escape a GKeyFile value: backslashes, control characters, and the spaces at either end
+/// that would otherwise be trimmed when it's read back.
+fn keyfile_escape(value: &str) -> String {
+    let start = value.len() - value.trim_start_matches(' ').len();
+    let end = value.trim_end_matches(' ').len();
+
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.char_indices() {
+        match c {
+            ' ' if i < start || i >= end => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn wpa_supplicant_block(
+    ssid: &str,
+    password: &str,
+    security_type: SecurityType,
+) -> Result<String, anyhow::Error> {
+    // Quoted strings can't be escaped, fall back to the hex form for the SSID
+    let ssid = if ssid.contains('"') {
+        hex::encode(ssid)
+    } else {
+        format!("\"{ssid}\"")
+    };
+    if password.contains('"') {
+        return Err(anyhow!("password can't be represented in wpa_supplicant.conf"));
+    }
+
+    let security = match security_type {
+        SecurityType::WpaPsk => format!("\tpsk=\"{password}\"\n"),
+        SecurityType::Wep => format!("\tkey_mgmt=NONE\n\twep_key0=\"{password}\"\n"),
+        SecurityType::Open | SecurityType::UnknownSecurityType => "\tkey_mgmt=NONE\n".to_string(),
+    };
+
+    Ok(format!("network={{\n\tssid={ssid}\n{security}}}\n"))
+}
+
+/// Format as `YYYY-MM-DD HH.MM.SS` (UTC).
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}.{:02}.{:02}",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_251_199);
+        assert_eq!(format_timestamp(time), "2024-02-29 23.59.59");
+    }
+
+    #[test]
+    fn test_nm_keyfile() {
+        let keyfile = nm_keyfile("home", "hunter22", SecurityType::WpaPsk);
+        assert!(keyfile.contains("\nid=home\n"));
+        assert!(keyfile.contains("\nssid=home\n"));
+        assert!(keyfile.contains("\nkey-mgmt=wpa-psk\npsk=hunter22\n"));
+
+        // Escape sequences and the spaces at either end must survive
+        let keyfile = nm_keyfile("my home", " a\\sb\tc ", SecurityType::WpaPsk);
+        assert!(keyfile.contains("\nssid=my home\n"));
+        assert!(keyfile.contains("\npsk=\\sa\\\\sb\\tc\\s\n"));
+
+        // Not representable as a string
+        let keyfile = nm_keyfile("a;b", "hunter22", SecurityType::Open);
+        assert!(keyfile.contains("\nid=a;b\n"));
+        assert!(keyfile.contains("\nssid=97;59;98;\n"));
+        let keyfile = nm_keyfile("café", "hunter22", SecurityType::Open);
+        assert!(keyfile.contains("\nssid=99;97;102;195;169;\n"));
+        assert!(!keyfile.contains("psk="));
+    }
+
+    #[test]
+    fn test_wpa_supplicant_block() {
+        let block = wpa_supplicant_block("home", "hunter22", SecurityType::WpaPsk).unwrap();
+        assert_eq!(block, "network={\n\tssid=\"home\"\n\tpsk=\"hunter22\"\n}\n");
+    }
+}
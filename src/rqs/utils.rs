@@ -1,14 +1,14 @@
 use std::fs;
-use std::io::Write;
-use std::net::Ipv4Addr;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::sync::LazyLock;
+use std::sync::{LazyLock, OnceLock};
 
 use anyhow::anyhow;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 use directories::ProjectDirs;
-use get_if_addrs::get_if_addrs;
 use hkdf::Hkdf;
 use num_bigint::{BigUint, ToBigInt};
 use p256::elliptic_curve::rand_core::OsRng;
@@ -17,57 +17,72 @@ use rand::distr::Alphanumeric;
 use rand::{Rng, RngCore};
 use sha2::Sha256;
 use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use socket2::{Domain, Protocol, Socket};
+use tokio::net::{TcpListener, TcpStream};
 
 
 
-use crate::CUSTOM_DOWNLOAD;
+use crate::{CUSTOM_DOWNLOAD, DEVICE_TYPE};
 
 /// Persistent endpoint ID - loaded from data dir or generated once and saved
 static ENDPOINT_ID: LazyLock<[u8; 4]> = LazyLock::new(|| {
     // Try to load from data directory
     if let Some(proj_dirs) = ProjectDirs::from("", "", "kvakk") {
         let data_dir = proj_dirs.data_dir();
-        let endpoint_file = data_dir.join("endpoint_id");
-
-        // Try to read existing endpoint_id
-        if let Ok(data) = fs::read(&endpoint_file)
-            && data.len() >= 4
+        // Best effort - don't fail if we can't persist
+        drop(fs::create_dir_all(data_dir));
+
+        if let Ok(mut file) = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(data_dir.join("endpoint_id"))
         {
-            let mut id = [0u8; 4];
-            id.copy_from_slice(&data[..4]);
-            return id;
-        }
+            // The file stays locked while we run, another instance using the same
+            // data dir gets its own endpoint_id for the two to see each other
+            if let Err(e) = file.try_lock() {
+                info!("endpoint_id in use by another instance ({e}), using a new one");
+                return random_endpoint_id();
+            }
 
-        // Generate new endpoint_id and save it
-        let id: [u8; 4] = rand::rng()
-            .sample_iter(Alphanumeric)
-            .take(4)
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap_or([b'A', b'B', b'C', b'D']);
-
-        // Best effort save - don't fail if we can't persist
-        if fs::create_dir_all(data_dir).is_ok()
-            && let Ok(mut file) = fs::File::create(&endpoint_file)
-        {
-            drop(file.write_all(&id));
+            // Try to read existing endpoint_id
+            let mut data = Vec::new();
+            let id = if file.read_to_end(&mut data).is_ok() && data.len() >= 4 {
+                let mut id = [0u8; 4];
+                id.copy_from_slice(&data[..4]);
+                id
+            } else {
+                // Generate new endpoint_id and save it
+                let id = random_endpoint_id();
+                drop(file.set_len(0).and_then(|()| file.write_all_at(&id, 0)));
+                id
+            };
+
+            drop(ENDPOINT_ID_LOCK.set(file));
+            return id;
         }
-
-        return id;
     }
 
     // Fallback: generate random (won't be persistent)
+    random_endpoint_id()
+});
+
+/// Holds the lock on the endpoint_id file for the lifetime of the process
+static ENDPOINT_ID_LOCK: OnceLock<fs::File> = OnceLock::new();
+
+fn random_endpoint_id() -> [u8; 4] {
     rand::rng()
         .sample_iter(Alphanumeric)
         .take(4)
         .collect::<Vec<u8>>()
         .try_into()
         .unwrap_or([b'A', b'B', b'C', b'D'])
-});
+}
 
 /// Returns the persistent 4-byte endpoint ID used for mDNS service naming.
 /// This ID is generated once and saved to the app's data directory.
+/// Other instances running at the same time get a random one.
 pub fn get_endpoint_id() -> [u8; 4] {
     *ENDPOINT_ID
 }
@@ -99,29 +114,78 @@ impl DeviceType {
 pub struct RemoteDeviceInfo {
     pub name: String,
     pub device_type: DeviceType,
+    /// Version bits of the endpoint info
+    #[serde(default)]
+    pub version: u8,
+    /// The peer isn't visible to everyone (Quick Share's contacts or hidden modes)
+    #[serde(default)]
+    pub hidden: bool,
 }
 
 impl RemoteDeviceInfo {
-    pub fn serialize(&self) -> Vec<u8> {
-        // 1 byte: Version(3 bits)|Visibility(1 bit)|Device Type(3 bits)|Reserved(1 bit)
-        let mut endpoint_info: Vec<u8> = vec![((self.device_type.clone() as u8) << 1) & 0b111];
+    pub fn new(name: String, device_type: DeviceType) -> Self {
+        Self {
+            name,
+            device_type,
+            version: 0,
+            hidden: false,
+        }
+    }
 
-        // 16 bytes: unknown random bytes
-        endpoint_info.extend((0..16).map(|_| rand::rng().random_range(0..=255)));
+    pub fn serialize(&self, visible: bool) -> Vec<u8> {
+        endpoint_info(self.device_type.clone() as u8, &self.name, visible)
+    }
 
-        // Device name in UTF-8 prefixed with 1-byte length
-        let mut name_chars = self.name.as_bytes().to_vec();
-        if name_chars.len() > 255 {
-            name_chars.truncate(255);
+    /// Decode the endpoint info of a `ConnectionRequest` or an mDNS "n" record.
+    pub fn deserialize(endpoint_info: &[u8]) -> Result<Self, anyhow::Error> {
+        if endpoint_info.len() < 18 {
+            return Err(anyhow!("Endpoint info too short"));
         }
-        // Safety: truncated to max 255 above
-        endpoint_info.push(u8::try_from(name_chars.len()).unwrap_or(255));
-        endpoint_info.extend(name_chars);
 
-        endpoint_info
+        let device_name_length = endpoint_info[17] as usize;
+        // Validate length including device name
+        if endpoint_info.len() < device_name_length + 18 {
+            return Err(anyhow!("Endpoint info too short to contain the device name"));
+        }
+
+        // Extract and validate device name based on length
+        let device_name = std::str::from_utf8(&endpoint_info[18..(18 + device_name_length)])
+            .map_err(|_| anyhow!("Device name is not valid UTF-8"))?;
+
+        // 1 byte: Version(3 bits)|Visibility(1 bit)|Device Type(3 bits)|Reserved(1 bit)
+        let flags = endpoint_info[0];
+
+        Ok(Self {
+            name: device_name.to_string(),
+            device_type: DeviceType::from_raw_value((flags >> 1) & 0b111),
+            version: flags >> 5,
+            hidden: (flags >> 4) & 1 == 1,
+        })
     }
 }
 
+/// Stand-in for the salt and encrypted metadata key of the endpoint info.
+/// Random, but the same for the whole session so peers keep seeing the same identity.
+static ENDPOINT_INFO_KEY: LazyLock<[u8; 16]> = LazyLock::new(|| rand::rng().random());
+
+fn endpoint_info(device_type: u8, device_name: &str, visible: bool) -> Vec<u8> {
+    // 1 byte: Version(3 bits)|Visibility(1 bit)|Device Type(3 bits)|Reserved(1 bit)
+    // Device types: unknown=0, phone=1, tablet=2, laptop=3
+    // The visibility bit is set when hidden
+    let mut record = vec![(u8::from(!visible) << 4) | ((device_type & 0b111) << 1)];
+
+    // 16 bytes: unknown, stable for the session
+    record.extend_from_slice(&*ENDPOINT_INFO_KEY);
+
+    // Device name in UTF-8 prefixed with 1-byte length
+    let device_name = &device_name.as_bytes()[..device_name.len().min(255)];
+    // Safety: clamped to max 255 above
+    record.push(u8::try_from(device_name.len()).unwrap_or(255));
+    record.extend_from_slice(device_name);
+
+    record
+}
+
 pub fn gen_mdns_name(endpoint_id: [u8; 4]) -> String {
     let mut name_b = Vec::new();
 
@@ -139,40 +203,17 @@ pub fn gen_mdns_name(endpoint_id: [u8; 4]) -> String {
     URL_SAFE_NO_PAD.encode(&name_b)
 }
 
-pub fn gen_mdns_endpoint_info(device_type: u8, device_name: &str) -> String {
-    let mut record = Vec::new();
-
-    // 1 byte: Version(3 bits)|Visibility(1 bit)|Device Type(3 bits)|Reserved(1 bits)
-    // Device types: unknown=0, phone=1, tablet=2, laptop=3
-    record.push(device_type << 1);
-
-    let unknown_bytes = rand::rng().random::<[u8; 16]>();
-    record.extend_from_slice(&unknown_bytes);
-
-    let device_name = &device_name.as_bytes()[..device_name.len().min(255)];
-    // Safety: clamped to max 255 above
-    record.push(u8::try_from(device_name.len()).unwrap_or(255));
-    record.extend_from_slice(device_name);
-
-    URL_SAFE_NO_PAD.encode(&record)
+pub fn gen_mdns_endpoint_info(device_type: u8, device_name: &str, visible: bool) -> String {
+    URL_SAFE_NO_PAD.encode(endpoint_info(device_type, device_name, visible))
 }
 
-pub fn parse_mdns_endpoint_info(encoded_str: &str) -> Result<(DeviceType, String), anyhow::Error> {
-    let decoded_bytes = URL_SAFE_NO_PAD.decode(encoded_str)?;
-    if decoded_bytes.len() < 19 {
-        return Err(anyhow!("Invalid data length"));
-    }
-
-    let device_type = (decoded_bytes[0] >> 1) & 0x7;
-    let name_length = decoded_bytes[17] as usize;
-    if 18 + name_length > decoded_bytes.len() {
-        return Err(anyhow!("Invalid name length"));
-    }
-
-    let device_name_bytes = &decoded_bytes[18..18 + name_length];
-    let device_name = String::from_utf8(device_name_bytes.to_vec())?;
+pub fn parse_mdns_endpoint_info(encoded_str: &str) -> Result<RemoteDeviceInfo, anyhow::Error> {
+    RemoteDeviceInfo::deserialize(&URL_SAFE_NO_PAD.decode(encoded_str)?)
+}
 
-    Ok((DeviceType::from_raw_value(device_type), device_name))
+/// The device type we present ourselves as.
+pub fn get_device_type() -> DeviceType {
+    DEVICE_TYPE.read().map(|g| g.clone()).unwrap_or(DeviceType::Laptop)
 }
 
 pub async fn stream_read_exact(
@@ -185,6 +226,31 @@ pub async fn stream_read_exact(
     }
 }
 
+/// Reads the 4-byte length prefix of frames.
+///
+/// Unlike `stream_read_exact`, it can lose a `select!` against a timer: the bytes
+/// already read are kept for the next call instead of being dropped.
+#[derive(Debug, Default)]
+pub struct LengthReader {
+    buf: [u8; 4],
+    filled: usize,
+}
+
+impl LengthReader {
+    pub async fn read(&mut self, socket: &mut TcpStream) -> Result<[u8; 4], anyhow::Error> {
+        while self.filled < self.buf.len() {
+            let n = socket.read(&mut self.buf[self.filled..]).await?;
+            if n == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            self.filled += n;
+        }
+
+        self.filled = 0;
+        Ok(self.buf)
+    }
+}
+
 pub fn gen_ecdsa_keypair() -> (SecretKey, PublicKey) {
     let secret_key = SecretKey::random(&mut OsRng);
     let public_key = secret_key.public_key();
@@ -254,21 +320,37 @@ pub fn get_download_dir() -> PathBuf {
     Path::new("/").to_path_buf()
 }
 
-pub fn is_not_self_ip(ip_address: &Ipv4Addr) -> bool {
-    if let Ok(if_addrs) = get_if_addrs() {
-        for if_addr in if_addrs {
-            if if_addr.ip() == *ip_address {
-                return false;
-            }
+/// Listen on every IPv6 and IPv4 address, or on IPv4 only where IPv6 is disabled.
+pub fn bind_dual_stack(port: u16) -> Result<TcpListener, anyhow::Error> {
+    let bind_v6 = || -> std::io::Result<std::net::TcpListener> {
+        let socket = Socket::new(Domain::IPV6, socket2::Type::STREAM, Some(Protocol::TCP))?;
+        // IPv4 peers show up as IPv4-mapped addresses
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket.into())
+    };
+
+    let listener = match bind_v6() {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Can't listen on IPv6 ({e}), falling back to IPv4 only");
+            let listener = std::net::TcpListener::bind(("0.0.0.0", port))?;
+            listener.set_nonblocking(true)?;
+            listener
         }
-    }
+    };
 
-    true
+    Ok(TcpListener::from_std(listener)?)
 }
 
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod tests {
+    use std::net::IpAddr;
+
     use super::*;
 
     #[test]
@@ -279,10 +361,44 @@ mod tests {
         dbg!(&device_type);
         dbg!(device_type.clone() as u8);
 
-        let info = gen_mdns_endpoint_info(device_type.clone() as u8, device_name);
+        let info = gen_mdns_endpoint_info(device_type.clone() as u8, device_name, true);
         let parse_info = parse_mdns_endpoint_info(&info).unwrap();
 
-        assert_eq!(parse_info.1, device_name);
-        assert_eq!(parse_info.0, device_type);
+        assert_eq!(parse_info.name, device_name);
+        assert_eq!(parse_info.device_type, device_type);
+        assert!(!parse_info.hidden);
+        // Stable for the session
+        assert_eq!(info, gen_mdns_endpoint_info(device_type as u8, device_name, true));
+    }
+
+    #[test]
+    fn test_endpoint_info_visibility_bit() {
+        let hidden = URL_SAFE_NO_PAD.decode(gen_mdns_endpoint_info(3, "a", false)).unwrap();
+        assert_eq!(hidden[0], 0b0001_0110);
+        let visible = URL_SAFE_NO_PAD.decode(gen_mdns_endpoint_info(3, "a", true)).unwrap();
+        assert_eq!(visible[0], 0b0000_0110);
+
+        let info = RemoteDeviceInfo::new("a".into(), DeviceType::Laptop);
+        assert_eq!(info.serialize(false)[0], hidden[0]);
+
+        let mut raw = vec![0b0101_0100];
+        raw.extend([0; 16]);
+        raw.extend([1, b'b']);
+        let parsed = RemoteDeviceInfo::deserialize(&raw).unwrap();
+        assert_eq!(parsed.version, 2);
+        assert!(parsed.hidden);
+        assert_eq!(parsed.device_type, DeviceType::Tablet);
+        assert_eq!(parsed.name, "b");
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_accepts_ipv4() {
+        let listener = bind_dual_stack(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (accepted, connected) = tokio::join!(listener.accept(), TcpStream::connect(("127.0.0.1", port)));
+        connected.unwrap();
+        let (_, remote_addr) = accepted.unwrap();
+        assert_eq!(remote_addr.ip().to_canonical(), IpAddr::from([127, 0, 0, 1]));
     }
 }